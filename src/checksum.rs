@@ -0,0 +1,90 @@
+use std::io;
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{digest::DynDigest, Sha224, Sha256, Sha384, Sha512};
+
+use crate::storage::StorageFile;
+
+const BUF_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Algorithm {
+    Md5,
+    Sha1,
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512
+}
+
+impl Algorithm {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "md5" => Some(Algorithm::Md5),
+            "sha1" => Some(Algorithm::Sha1),
+            "sha224" => Some(Algorithm::Sha224),
+            "sha256" => Some(Algorithm::Sha256),
+            "sha384" => Some(Algorithm::Sha384),
+            "sha512" => Some(Algorithm::Sha512),
+            _ => None
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha224 => "sha224",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha384 => "sha384",
+            Algorithm::Sha512 => "sha512"
+        }
+    }
+
+    pub(crate) fn hasher(self) -> Box<dyn DynDigest + Send> {
+        match self {
+            Algorithm::Md5 => Box::new(Md5::default()),
+            Algorithm::Sha1 => Box::new(Sha1::default()),
+            Algorithm::Sha224 => Box::new(Sha224::default()),
+            Algorithm::Sha256 => Box::new(Sha256::default()),
+            Algorithm::Sha384 => Box::new(Sha384::default()),
+            Algorithm::Sha512 => Box::new(Sha512::default())
+        }
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hashes `length` bytes of `file` starting at `start`, a length of 0 means up to the end of the file.
+/// With a non zero `block_size` every block is hashed separately and the hashes are concatenated
+pub(crate) async fn hash_file(file: &dyn StorageFile, algorithm: Algorithm, start: u64, length: u64, block_size: u32) -> io::Result<Vec<u8>> {
+    let end = if length == 0 { u64::MAX } else { start.saturating_add(length) };
+    let block_size = if block_size == 0 { u64::MAX } else { block_size as u64 };
+    let mut hashes = Vec::new();
+    let mut hasher = algorithm.hasher();
+    let mut in_block = 0;
+    let mut offset = start;
+
+    while offset < end {
+        let want = (end - offset).min(block_size - in_block).min(BUF_SIZE as u64) as usize;
+        let data = file.read_at(offset, want).await?;
+        if data.is_empty() {
+            break
+        }
+        hasher.update(&data);
+        offset += data.len() as u64;
+        in_block += data.len() as u64;
+        if in_block == block_size {
+            hashes.extend_from_slice(&hasher.finalize_reset());
+            in_block = 0;
+        }
+    }
+    // a trailing partial block, or the whole range when not hashing in blocks
+    if in_block != 0 || hashes.is_empty() {
+        hashes.extend_from_slice(&hasher.finalize_reset());
+    }
+    Ok(hashes)
+}
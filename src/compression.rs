@@ -0,0 +1,727 @@
+use std::{collections::{BTreeMap, HashMap}, io::{self, ErrorKind}, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex as StdMutex, Weak}};
+
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+use crate::{config::CompressionConfig, glob::Glob, jail, storage::{BoxFuture, DirEntry, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile}};
+
+// plaintext per zstd frame, frames are compressed on their own so reads only decompress the frames they need
+const FRAME_SIZE: usize = 1024 * 1024;
+// the seek table is a skippable frame at the end of the file, in the format of zstd's seekable contrib library
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+const FOOTER_SIZE: u64 = 9;
+const ENTRY_SIZE: u64 = 8;
+// files compressed by the server start with this skippable frame, listed in the seek table as a frame without any
+// data, so files that only happen to be in the seekable format are left alone
+const MARKER: &[u8] = b"\x50\x2a\x4d\x18\x09\x00\x00\x00flux-sftp";
+const MARKER_SIZE: u64 = MARKER.len() as u64;
+// a seek table listing only the marker
+const MIN_TABLE_SIZE: u64 = 8 + ENTRY_SIZE + FOOTER_SIZE;
+// writes that arrive ahead of the ones before them are held back if they end within this amount of what was written
+const MAX_OUT_OF_ORDER: usize = 64 * 1024 * 1024;
+
+fn corrupted() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "compressed file is corrupted")
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+async fn compress(data: Vec<u8>, level: i32) -> io::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || zstd::bulk::compress(&data, level)).await.map_err(io::Error::other)?
+}
+
+async fn decompress(data: Vec<u8>, size: u32) -> io::Result<Vec<u8>> {
+    let plain = tokio::task::spawn_blocking(move || zstd::bulk::decompress(&data, size as usize)).await.map_err(io::Error::other)?.map_err(|_| corrupted())?;
+    if plain.len() != size as usize {
+        return Err(corrupted())
+    }
+    Ok(plain)
+}
+
+/// Settings of the compression layer, shared by all sessions
+pub(crate) struct Compression {
+    enabled: bool,
+    level: i32,
+    patterns: Vec<Glob>,
+    // open files of every user, by the directory of their files
+    files: StdMutex<HashMap<String, Arc<OpenFiles>>>
+}
+
+impl Compression {
+    pub(crate) fn new(config: &CompressionConfig) -> Result<Self, String> {
+        if !(1..=22).contains(&config.level) {
+            return Err(format!("invalid compression level {}, it has to be between 1 and 22", config.level))
+        }
+        let patterns = config.patterns.iter().map(|pattern| Glob::new(pattern)).collect::<Result<_, _>>()?;
+        Ok(Compression { enabled: config.enabled, level: config.level, patterns, files: StdMutex::new(HashMap::new()) })
+    }
+
+    /// Whether new files are compressed for users without their own setting
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The open files of the user with files in `dir`, so every session of the user shares them
+    pub(crate) fn open_files(&self, dir: &str) -> Arc<OpenFiles> {
+        self.files.lock().unwrap().entry(dir.to_string()).or_insert_with(|| Arc::new(OpenFiles::new())).clone()
+    }
+}
+
+/// The compressed files open in a tree, by canonical path. the frames of a file and the one being built are shared by
+/// all of its handles, so they don't write over each other's seek tables or read a stale one
+pub(crate) struct OpenFiles {
+    files: StdMutex<HashMap<String, Weak<Shared>>>
+}
+
+impl OpenFiles {
+    pub(crate) fn new() -> Self {
+        OpenFiles { files: StdMutex::new(HashMap::new()) }
+    }
+
+    // dropped with the last handle of their file
+    fn get(&self, path: &str) -> Arc<Shared> {
+        let mut files = self.files.lock().unwrap();
+        if let Some(shared) = files.get(path).and_then(Weak::upgrade) {
+            return shared
+        }
+        files.retain(|_, shared| shared.strong_count() > 0);
+        let shared = Arc::new(Shared { state: Mutex::new(None), writers: AtomicUsize::new(0) });
+        files.insert(path.to_string(), Arc::downgrade(&shared));
+        shared
+    }
+}
+
+struct Shared {
+    // None until the file is known to be compressed
+    state: Mutex<Option<State>>,
+    // handles open for writing and not closed yet, the last one fills the gaps left by writes that never arrived
+    writers: AtomicUsize
+}
+
+#[derive(Clone, Copy)]
+struct Frame {
+    // where the compressed frame starts in the file
+    offset: u64,
+    plain_offset: u64,
+    compressed: u32,
+    size: u32
+}
+
+// the frames of a compressed file after the marker, None for files without the marker and a valid seek table which
+// are read as they are
+async fn read_table(file: &dyn StorageFile, disk_size: u64) -> io::Result<Option<Vec<Frame>>> {
+    if disk_size < MARKER_SIZE + MIN_TABLE_SIZE {
+        return Ok(None)
+    }
+    let footer = file.read_at(disk_size - FOOTER_SIZE, FOOTER_SIZE as usize).await?;
+    if footer.len() != FOOTER_SIZE as usize || u32_at(&footer, 5) != SEEKABLE_MAGIC {
+        return Ok(None)
+    }
+    // entries carry a checksum when the descriptor's top bit is set
+    let entry_size = if footer[4] & 0x80 != 0 { ENTRY_SIZE + 4 } else { ENTRY_SIZE };
+    let count = u32_at(&footer, 0) as u64;
+    let table_size = 8 + count * entry_size + FOOTER_SIZE;
+    if count == 0 || table_size > disk_size {
+        return Ok(None)
+    }
+    let table = file.read_at(disk_size - table_size, table_size as usize).await?;
+    if table.len() != table_size as usize || u32_at(&table, 0) != SKIPPABLE_MAGIC || u32_at(&table, 4) as u64 != table_size - 8 {
+        return Ok(None)
+    }
+    let mut frames = Vec::with_capacity(count as usize);
+    let (mut offset, mut plain_offset) = (0, 0);
+    // frames are decompressed into buffers of their size, so no table gets to ask for more than a frame holds
+    let max_compressed = zstd::zstd_safe::compress_bound(FRAME_SIZE);
+    for i in 0..count {
+        let entry = 8 + (i * entry_size) as usize;
+        let (compressed, size) = (u32_at(&table, entry), u32_at(&table, entry + 4));
+        if size as usize > FRAME_SIZE || compressed as usize > max_compressed {
+            return Ok(None)
+        }
+        frames.push(Frame { offset, plain_offset, compressed, size });
+        offset += compressed as u64;
+        plain_offset += size as u64;
+    }
+    if offset != disk_size - table_size {
+        return Ok(None)
+    }
+    let marker = frames.remove(0);
+    if marker.compressed as u64 != MARKER_SIZE || marker.size != 0 || file.read_at(0, MARKER.len()).await? != MARKER {
+        return Ok(None)
+    }
+    Ok(Some(frames))
+}
+
+// the marker comes first in the table, followed by `frames`
+fn seek_table(frames: &[Frame]) -> Vec<u8> {
+    let count = frames.len() + 1;
+    let mut table = Vec::with_capacity(8 + count * ENTRY_SIZE as usize + FOOTER_SIZE as usize);
+    table.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    table.extend_from_slice(&((count as u64 * ENTRY_SIZE + FOOTER_SIZE) as u32).to_le_bytes());
+    table.extend_from_slice(&(MARKER_SIZE as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+    for frame in frames {
+        table.extend_from_slice(&frame.compressed.to_le_bytes());
+        table.extend_from_slice(&frame.size.to_le_bytes());
+    }
+    table.extend_from_slice(&(count as u32).to_le_bytes());
+    table.push(0);
+    table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+    table
+}
+
+// size of the plaintext of the file at `path`, None if it isn't compressed
+async fn compressed_size(inner: &dyn StorageBackend, path: &str, disk_size: u64) -> Option<u64> {
+    if disk_size < MARKER_SIZE + MIN_TABLE_SIZE {
+        return None
+    }
+    let file = inner.open(path, OpenOptions { read: true, ..Default::default() }, 0).await.ok()?;
+    let frames = read_table(&*file, disk_size).await.ok().flatten();
+    let _ = file.close().await;
+    frames.map(|frames| frames.last().map_or(0, |frame| frame.plain_offset + frame.size as u64))
+}
+
+/// Stores new files zstd compressed in the seekable format, they can also be decompressed with a plain `zstd -d`.
+/// Files the server didn't compress are passed through untouched, so existing files keep working
+pub(crate) struct CompressedBackend {
+    inner: Arc<dyn StorageBackend>,
+    settings: Arc<Compression>,
+    // whether new files matching the patterns are compressed
+    enabled: bool,
+    files: Arc<OpenFiles>
+}
+
+impl CompressedBackend {
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>, settings: Arc<Compression>, enabled: bool, files: Arc<OpenFiles>) -> Self {
+        CompressedBackend { inner, settings, enabled, files }
+    }
+
+    // a file that doesn't exist yet is known by its canonical parent
+    async fn key(&self, path: &str) -> String {
+        if let Ok(resolved) = self.inner.canonicalize(path).await {
+            return jail::normalize(&resolved)
+        }
+        let path = jail::normalize(path);
+        let (dir, name) = jail::split(&path);
+        match self.inner.canonicalize(dir).await {
+            Ok(dir) => jail::normalize(&format!("{}/{}", dir, name)),
+            Err(_) => path
+        }
+    }
+
+    fn should_compress(&self, path: &str) -> bool {
+        let path = jail::normalize(path);
+        self.enabled && (self.settings.patterns.is_empty() || self.settings.patterns.iter().any(|pattern| pattern.matches(&path)))
+    }
+}
+
+impl StorageBackend for CompressedBackend {
+    fn owner(&self) -> io::Result<(u32, u32)> {
+        self.inner.owner()
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.canonicalize(path)
+    }
+
+    // files opened for writing are also opened for reading, the last frame is taken back out when more is appended.
+    // the table is only read by the first handle of a file, the others go on from what it has
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            let writing = options.write || options.append;
+            let inner_options = if writing { OpenOptions { read: true, append: false, ..options } } else { options };
+            let shared = self.files.get(&self.key(path).await);
+            let mut slot = shared.state.lock().await;
+            let inner = self.inner.open(path, inner_options, mode).await?;
+            if slot.is_none() || options.truncate {
+                let disk_size = inner.stat().await?.size;
+                let frames = match read_table(&*inner, disk_size).await? {
+                    Some(frames) => frames,
+                    // an empty file that is being written becomes a compressed one, so does one truncated while open
+                    // compressed through another handle
+                    None if disk_size == 0 && writing && (slot.is_some() || self.should_compress(path)) => {
+                        inner.write_at(0, MARKER.to_vec()).await.1?;
+                        Vec::new()
+                    }
+                    // appends to a plain file are left to the backend again
+                    None if options.append => {
+                        drop(inner);
+                        return self.inner.open(path, OpenOptions { create: false, exclusive: false, truncate: false, ..options }, mode).await
+                    }
+                    None => return Ok(inner)
+                };
+                let end = frames.last().map_or(MARKER_SIZE, |frame| frame.offset + frame.compressed as u64);
+                *slot = Some(State { frames, end, pending: Vec::new(), ahead: BTreeMap::new(), ahead_len: 0, dirty: disk_size == 0, disk_size: disk_size.max(MARKER_SIZE) });
+            }
+            if writing {
+                slot.as_mut().unwrap().reopen_tail(&*inner).await?;
+                shared.writers.fetch_add(1, Ordering::Relaxed);
+            }
+            drop(slot);
+            let file = CompressedFile { inner, level: self.settings.level, writable: writing, append: options.append, shared, closed: AtomicBool::new(false) };
+            Ok(Box::new(file) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+        Box::pin(async move {
+            let mut st = self.inner.stat(path, follow).await?;
+            if st.is_file() {
+                st.size = compressed_size(&*self.inner, path, st.size).await.unwrap_or(st.size);
+            }
+            Ok(st)
+        })
+    }
+
+    // sizes are changed through an open file since frames are taken apart for it
+    fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, follow: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let Some(size) = attrs.size else {
+                return self.inner.set_attributes(path, attrs, follow).await
+            };
+            if !follow && !self.inner.stat(path, false).await?.is_file() {
+                return self.inner.set_attributes(path, attrs, follow).await
+            }
+            let file = self.open(path, OpenOptions { write: true, ..Default::default() }, 0).await?;
+            file.set_attributes(SetAttributes { size: Some(size), ..Default::default() }).await?;
+            file.close().await?;
+            self.inner.set_attributes(path, SetAttributes { size: None, ..attrs }, follow).await
+        })
+    }
+
+    fn list<'a>(&'a self, path: &'a str, sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+        Box::pin(async move {
+            let stream = self.inner.list(path, sort, limit).await?;
+            Ok(Box::new(CompressedDir { stream, inner: self.inner.clone(), dir: jail::normalize(path) }) as Box<dyn DirStream>)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.remove_file(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.create_dir(path, mode)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.remove_dir(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.rename(from, to, replace)
+    }
+
+    fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+        self.inner.statvfs(path)
+    }
+
+    fn read_link<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.read_link(path)
+    }
+
+    fn symlink<'a>(&'a self, target: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.symlink(target, path)
+    }
+
+    fn hard_link<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.hard_link(from, to)
+    }
+
+    // the copy stays compressed, whatever the patterns say about its new name
+    fn copy_file<'a>(&'a self, from: &'a str, to: &'a str, overwrite: bool) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.copy_file(from, to, overwrite)
+    }
+}
+
+struct State {
+    frames: Vec<Frame>,
+    // where the next frame is written
+    end: u64,
+    // plaintext after the last frame, not stored in a frame of its own until the file is synced or closed
+    pending: Vec<u8>,
+    // writes that arrived before the data in front of them, by offset
+    ahead: BTreeMap<u64, Vec<u8>>,
+    ahead_len: usize,
+    // frames changed since the seek table was written
+    dirty: bool,
+    // the underlying file may still hold an old seek table or frames past `end`
+    disk_size: u64
+}
+
+impl State {
+    fn committed(&self) -> u64 {
+        self.frames.last().map_or(0, |frame| frame.plain_offset + frame.size as u64)
+    }
+
+    fn size(&self) -> u64 {
+        self.committed() + self.pending.len() as u64
+    }
+
+    // the last frame goes back into `pending` if it isn't full, so appending keeps frames full sized
+    async fn reopen_tail(&mut self, inner: &dyn StorageFile) -> io::Result<()> {
+        match self.frames.last() {
+            Some(frame) if (frame.size as usize) < FRAME_SIZE => self.pop_frame(inner).await,
+            _ => Ok(())
+        }
+    }
+
+    async fn pop_frame(&mut self, inner: &dyn StorageFile) -> io::Result<()> {
+        let Some(frame) = self.frames.pop() else {
+            return Ok(())
+        };
+        let data = inner.read_at(frame.offset, frame.compressed as usize).await?;
+        let mut plain = decompress(data, frame.size).await?;
+        plain.append(&mut self.pending);
+        self.pending = plain;
+        self.end = frame.offset;
+        Ok(())
+    }
+
+    // takes data starting at or before the end of `pending`, data that overlaps it replaces it
+    fn accept(&mut self, offset: u64, data: &[u8]) {
+        let start = (offset - self.committed()) as usize;
+        let overlap = (self.pending.len() - start).min(data.len());
+        self.pending[start..start + overlap].copy_from_slice(&data[..overlap]);
+        self.pending.extend_from_slice(&data[overlap..]);
+        self.dirty = true;
+    }
+
+    fn catch_up(&mut self) {
+        loop {
+            let size = self.size();
+            let Some(entry) = self.ahead.first_entry() else {
+                break
+            };
+            if *entry.key() > size {
+                break
+            }
+            let (offset, data) = entry.remove_entry();
+            self.ahead_len -= data.len();
+            self.accept(offset, &data);
+        }
+    }
+
+    async fn store_frame(&mut self, inner: &dyn StorageFile, level: i32) -> io::Result<()> {
+        let rest = self.pending.split_off(FRAME_SIZE);
+        let plain = std::mem::replace(&mut self.pending, rest);
+        let plain_offset = self.committed();
+        let compressed = compress(plain, level).await?;
+        let len = compressed.len();
+        inner.write_at(self.end, compressed).await.1?;
+        self.frames.push(Frame { offset: self.end, plain_offset, compressed: len as u32, size: FRAME_SIZE as u32 });
+        self.end += len as u64;
+        self.disk_size = self.disk_size.max(self.end);
+        Ok(())
+    }
+
+    async fn write(&mut self, inner: &dyn StorageFile, level: i32, offset: u64, data: Vec<u8>) -> io::Result<()> {
+        if offset < self.committed() {
+            return Err(io::Error::new(ErrorKind::Unsupported, "compressed files can only be written at the end"))
+        }
+        if offset > self.size() {
+            // the gap counts as well, it is filled with zeros if it's never written
+            if offset.saturating_add(data.len() as u64) - self.size() > MAX_OUT_OF_ORDER as u64 || self.ahead_len + data.len() > MAX_OUT_OF_ORDER {
+                return Err(io::Error::new(ErrorKind::Unsupported, "writes too far out of order"))
+            }
+            self.ahead_len += data.len();
+            if let Some(replaced) = self.ahead.insert(offset, data) {
+                self.ahead_len -= replaced.len();
+            }
+            return Ok(())
+        }
+        self.accept(offset, &data);
+        self.catch_up();
+        while self.pending.len() >= FRAME_SIZE {
+            self.store_frame(inner, level).await?;
+        }
+        Ok(())
+    }
+
+    // stores what is pending as a last frame followed by the seek table. the frame stays pending, further writes replace it
+    async fn flush(&mut self, inner: &dyn StorageFile, level: i32) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(())
+        }
+        let mut frames = self.frames.clone();
+        let mut data = Vec::new();
+        if !self.pending.is_empty() {
+            data = compress(self.pending.clone(), level).await?;
+            frames.push(Frame { offset: self.end, plain_offset: self.committed(), compressed: data.len() as u32, size: self.pending.len() as u32 });
+        }
+        data.extend_from_slice(&seek_table(&frames));
+        let end = self.end + data.len() as u64;
+        inner.write_at(self.end, data).await.1?;
+        if self.disk_size > end {
+            inner.set_attributes(SetAttributes { size: Some(end), ..Default::default() }).await?;
+        }
+        self.disk_size = end;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // everything past `size` is dropped, whole frames by forgetting them and the one `size` ends in by taking it apart
+    async fn truncate(&mut self, inner: &dyn StorageFile, size: u64) -> io::Result<()> {
+        self.ahead.retain(|offset, _| *offset < size);
+        for (offset, data) in self.ahead.iter_mut() {
+            data.truncate((size - offset).min(data.len() as u64) as usize);
+        }
+        self.ahead_len = self.ahead.values().map(Vec::len).sum();
+        while self.frames.last().is_some_and(|frame| frame.plain_offset >= size) {
+            let frame = self.frames.pop().unwrap();
+            self.end = frame.offset;
+            self.pending.clear();
+        }
+        if size < self.committed() {
+            self.pop_frame(inner).await?;
+        }
+        self.pending.truncate((size - self.committed()) as usize);
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+/// A compressed file. Reads can start anywhere, writes only at the end of what was written before
+struct CompressedFile {
+    inner: Box<dyn StorageFile>,
+    level: i32,
+    writable: bool,
+    append: bool,
+    shared: Arc<Shared>,
+    closed: AtomicBool
+}
+
+impl CompressedFile {
+    async fn state(&self) -> MappedMutexGuard<'_, State> {
+        MutexGuard::map(self.shared.state.lock().await, |state| state.as_mut().unwrap())
+    }
+
+    // whether this was the last writer still open
+    fn close_writer(&self) -> bool {
+        self.writable && !self.closed.swap(true, Ordering::Relaxed) && self.shared.writers.fetch_sub(1, Ordering::Relaxed) == 1
+    }
+}
+
+impl Drop for CompressedFile {
+    fn drop(&mut self) {
+        self.close_writer();
+    }
+}
+
+impl StorageFile for CompressedFile {
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let (frames, mut tail) = {
+                let state = self.state().await;
+                let end = offset.saturating_add(len as u64).min(state.size());
+                if offset >= end {
+                    return Ok(Vec::new())
+                }
+                let frames: Vec<Frame> = state.frames.iter().filter(|frame| frame.plain_offset < end && frame.plain_offset + frame.size as u64 > offset).copied().collect();
+                let committed = state.committed();
+                let tail = if end > committed {
+                    let start = offset.max(committed) - committed;
+                    state.pending[start as usize..(end - committed) as usize].to_vec()
+                }
+                else {
+                    Vec::new()
+                };
+                (frames, tail)
+            };
+            let mut data = Vec::with_capacity(len);
+            if let (Some(first), Some(last)) = (frames.first(), frames.last()) {
+                let compressed = self.inner.read_at(first.offset, (last.offset + last.compressed as u64 - first.offset) as usize).await?;
+                for frame in &frames {
+                    let start = (frame.offset - first.offset) as usize;
+                    let Some(chunk) = compressed.get(start..start + frame.compressed as usize) else {
+                        return Err(corrupted())
+                    };
+                    let plain = decompress(chunk.to_vec(), frame.size).await?;
+                    let from = offset.saturating_sub(frame.plain_offset) as usize;
+                    let to = (offset.saturating_add(len as u64) - frame.plain_offset).min(frame.size as u64) as usize;
+                    data.extend_from_slice(&plain[from..to]);
+                }
+            }
+            data.append(&mut tail);
+            Ok(data)
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)> {
+        Box::pin(async move {
+            if !self.writable {
+                return (data, Err(io::Error::from_raw_os_error(libc::EBADF)))
+            }
+            let mut state = self.state().await;
+            // clients still send the offsets of appended data, writes that overtook others wait for them like any other
+            let offset = if self.append { offset.max(state.size()) } else { offset };
+            match state.write(&*self.inner, self.level, offset, data).await {
+                Ok(()) => (Vec::new(), Ok(())),
+                Err(e) => (Vec::new(), Err(e))
+            }
+        })
+    }
+
+    fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>> {
+        Box::pin(async move {
+            let mut st = self.inner.stat().await?;
+            let state = self.state().await;
+            st.size = state.ahead.last_key_value().map_or(state.size(), |(offset, data)| state.size().max(offset + data.len() as u64));
+            Ok(st)
+        })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if let Some(new_size) = attrs.size {
+                if !self.writable {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL))
+                }
+                let mut state = self.state().await;
+                let size = state.size();
+                if new_size < size {
+                    state.truncate(&*self.inner, new_size).await?;
+                }
+                // growing the file appends zeros, one frame at a time
+                let mut size = state.size();
+                while size < new_size {
+                    let zeros = vec![0; (new_size - size).min(FRAME_SIZE as u64) as usize];
+                    state.write(&*self.inner, self.level, size, zeros).await?;
+                    size = state.size();
+                }
+            }
+            self.inner.set_attributes(SetAttributes { size: None, ..attrs }).await
+        })
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if self.writable {
+                self.state().await.flush(&*self.inner, self.level).await?;
+            }
+            self.inner.sync().await
+        })
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        self.inner.statvfs()
+    }
+
+    // gaps left by writes that never arrived read as zeros, like in a sparse file, once no other handle can fill them
+    fn close(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let result = if self.writable {
+                let mut state = self.state().await;
+                let last = self.close_writer();
+                async {
+                    while let Some((&offset, _)) = state.ahead.first_key_value().filter(|_| last) {
+                        let size = state.size();
+                        let zeros = vec![0; (offset - size).min(FRAME_SIZE as u64) as usize];
+                        state.write(&*self.inner, self.level, size, zeros).await?;
+                    }
+                    state.flush(&*self.inner, self.level).await
+                }.await
+            }
+            else {
+                Ok(())
+            };
+            result.and(self.inner.close().await)
+        })
+    }
+}
+
+// a directory listing with the sizes of compressed files replaced by their plaintext sizes
+struct CompressedDir {
+    stream: Box<dyn DirStream>,
+    inner: Arc<dyn StorageBackend>,
+    dir: String
+}
+
+impl DirStream for CompressedDir {
+    fn next_entries(&mut self, count: usize) -> BoxFuture<'_, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            let mut entries = self.stream.next_entries(count).await?;
+            for entry in entries.iter_mut().filter(|entry| entry.stat.is_file()) {
+                let path = if self.dir.is_empty() { entry.name.clone() } else { format!("{}/{}", self.dir, entry.name) };
+                entry.stat.size = compressed_size(&*self.inner, &path, entry.stat.size).await.unwrap_or(entry.stat.size);
+            }
+            Ok(entries)
+        })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        self.stream.set_attributes(attrs)
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        self.stream.statvfs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::MemoryConfig, memory::MemoryBackend};
+
+    fn backend() -> (Arc<MemoryBackend>, CompressedBackend) {
+        let inner = Arc::new(MemoryBackend::new(&MemoryConfig::default()));
+        let settings = Compression::new(&CompressionConfig { enabled: true, ..Default::default() }).unwrap();
+        let backend = CompressedBackend::new(inner.clone(), Arc::new(settings), true, Arc::new(OpenFiles::new()));
+        (inner, backend)
+    }
+
+    #[tokio::test]
+    async fn forged_frame_sizes() {
+        let (inner, backend) = backend();
+        let file = backend.open("a", OpenOptions { write: true, create: true, ..Default::default() }, 0o644).await.unwrap();
+        file.write_at(0, vec![7; 1000]).await.1.unwrap();
+        file.close().await.unwrap();
+        assert_eq!(backend.stat("a", true).await.unwrap().size, 1000);
+        let disk_size = inner.stat("a", true).await.unwrap().size;
+        // the size of the frame after the marker, and then its compressed size, are made larger than a frame can be
+        let entry = disk_size - FOOTER_SIZE - ENTRY_SIZE;
+        let raw = inner.open("a", OpenOptions { read: true, write: true, ..Default::default() }, 0).await.unwrap();
+        let forged = [(entry + 4, u32::MAX), (entry + 4, FRAME_SIZE as u32 + 1), (entry, (zstd::zstd_safe::compress_bound(FRAME_SIZE) + 1) as u32)];
+        for (offset, value) in forged {
+            let original = raw.read_at(offset, 4).await.unwrap();
+            raw.write_at(offset, value.to_le_bytes().to_vec()).await.1.unwrap();
+            assert!(read_table(&*raw, disk_size).await.unwrap().is_none());
+            // the file is read as it is stored
+            assert_eq!(backend.stat("a", true).await.unwrap().size, disk_size);
+            raw.write_at(offset, original).await.1.unwrap();
+        }
+        assert_eq!(read_table(&*raw, disk_size).await.unwrap().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handles_share_frames() {
+        let (_, backend) = backend();
+        let write = OpenOptions { write: true, create: true, ..Default::default() };
+        let a = backend.open("a", write, 0o644).await.unwrap();
+        a.write_at(0, vec![1; 10]).await.1.unwrap();
+        let b = backend.open("a", write, 0o644).await.unwrap();
+        b.write_at(10, vec![2; 10]).await.1.unwrap();
+        // a reader sees what the writers have so far, and a write ahead of the others waits for them
+        let reader = backend.open("a", OpenOptions { read: true, ..Default::default() }, 0).await.unwrap();
+        assert_eq!(reader.read_at(0, 100).await.unwrap(), [vec![1; 10], vec![2; 10]].concat());
+        b.write_at(30, vec![4; 10]).await.1.unwrap();
+        b.close().await.unwrap();
+        a.write_at(20, vec![3; 10]).await.1.unwrap();
+        a.close().await.unwrap();
+        reader.close().await.unwrap();
+        let file = backend.open("a", OpenOptions { read: true, ..Default::default() }, 0).await.unwrap();
+        assert_eq!(file.read_at(0, 100).await.unwrap(), [vec![1; 10], vec![2; 10], vec![3; 10], vec![4; 10]].concat());
+        // truncating starts over for every handle
+        let a = backend.open("a", write, 0o644).await.unwrap();
+        a.write_at(40, vec![5; 10]).await.1.unwrap();
+        let b = backend.open("a", OpenOptions { truncate: true, ..write }, 0o644).await.unwrap();
+        assert_eq!(file.read_at(0, 100).await.unwrap(), Vec::<u8>::new());
+        a.write_at(0, vec![6; 10]).await.1.unwrap();
+        a.close().await.unwrap();
+        b.close().await.unwrap();
+        assert_eq!(file.read_at(0, 100).await.unwrap(), vec![6; 10]);
+        assert_eq!(backend.stat("a", true).await.unwrap().size, 10);
+    }
+}
@@ -1,9 +1,26 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::FixedOffset;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct Config {
     pub(crate) general: GeneralConfig,
-    pub(crate) database: DBConfig
+    pub(crate) database: DBConfig,
+    #[serde(default)]
+    pub(crate) users: UsersConfig,
+    #[serde(default)]
+    pub(crate) sftp: SftpConfig,
+    #[serde(default)]
+    pub(crate) exec: ExecConfig,
+    #[serde(default)]
+    pub(crate) storage: StorageConfig,
+    #[serde(default)]
+    pub(crate) folders: HashMap<String, FolderConfig>,
+    #[serde(default)]
+    pub(crate) groups: BTreeMap<String, GroupConfig>,
+    #[serde(default)]
+    pub(crate) permissions: Vec<PermissionConfig>
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -52,7 +69,445 @@ pub(crate) struct CommonConfig {
     pub(crate) table: String,
     pub(crate) username_field: String,
     pub(crate) public_key_field: Option<String>,
-    pub(crate) password_field: Option<String>
+    pub(crate) password_field: Option<String>,
+    pub(crate) dir_field: Option<String>,
+    pub(crate) umask_field: Option<String>,
+    pub(crate) backend_field: Option<String>,
+    pub(crate) compression_field: Option<String>,
+    pub(crate) folders_field: Option<String>,
+    pub(crate) groups_field: Option<String>,
+    pub(crate) max_size_field: Option<String>,
+    pub(crate) max_files_field: Option<String>,
+    pub(crate) mode_field: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct UsersConfig {
+    pub(crate) username_regex: String,
+    pub(crate) max_username_length: usize,
+    pub(crate) lowercase_usernames: bool,
+    pub(crate) unicode_normalization: Normalization
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub(crate) enum Normalization {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "nfc")]
+    Nfc,
+    #[serde(rename = "nfkc")]
+    Nfkc
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct SftpConfig {
+    pub(crate) realpath_resolve_symlinks: bool,
+    pub(crate) max_handles: usize,
+    pub(crate) max_concurrent_requests: usize,
+    pub(crate) read_ahead_size: usize,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) atomic_uploads: bool,
+    pub(crate) partial_uploads: PartialUploads,
+    pub(crate) chmod_mask: u32,
+    pub(crate) umask: u32,
+    pub(crate) file_mode: u32,
+    pub(crate) dir_mode: u32,
+    pub(crate) honor_client_permissions: bool,
+    pub(crate) chown: ChownPolicy,
+    pub(crate) uid_map: HashMap<String, u32>,
+    pub(crate) gid_map: HashMap<String, u32>,
+    pub(crate) symlinks: SymlinkPolicy,
+    pub(crate) owner: OwnerDisplay,
+    pub(crate) timezone: Timezone,
+    pub(crate) sort_directory_listings: bool,
+    pub(crate) max_directory_entries: usize,
+    pub(crate) drop_box_overwrite: bool
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum OwnerDisplay {
+    #[serde(rename = "real")]
+    Real,
+    #[serde(rename = "session")]
+    Session
+}
+
+/// Timezone used for the dates in directory listings, `local`, `utc` or a fixed offset like `+05:00`
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum Timezone {
+    Local,
+    Utc,
+    Fixed(FixedOffset)
+}
+
+impl TryFrom<String> for Timezone {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "local" => Ok(Timezone::Local),
+            "utc" => Ok(Timezone::Utc),
+            offset => offset.parse().map(Timezone::Fixed).map_err(|_| format!("invalid timezone: {}", offset))
+        }
+    }
+}
+
+impl From<Timezone> for String {
+    fn from(value: Timezone) -> Self {
+        match value {
+            Timezone::Local => String::from("local"),
+            Timezone::Utc => String::from("utc"),
+            Timezone::Fixed(offset) => offset.to_string()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum SymlinkPolicy {
+    #[serde(rename = "deny")]
+    Deny,
+    #[serde(rename = "within-jail")]
+    WithinJail,
+    #[serde(rename = "allow")]
+    Allow
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum PartialUploads {
+    #[serde(rename = "delete")]
+    Delete,
+    #[serde(rename = "keep")]
+    Keep
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum ChownPolicy {
+    #[serde(rename = "ignore")]
+    Ignore,
+    #[serde(rename = "reject")]
+    Reject,
+    #[serde(rename = "map")]
+    Map
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct ExecConfig {
+    pub(crate) hash_commands: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct StorageConfig {
+    pub(crate) backend: Backend,
+    pub(crate) s3: Option<S3Config>,
+    pub(crate) memory: MemoryConfig,
+    pub(crate) dedup: Option<DedupConfig>,
+    pub(crate) encryption: Option<EncryptionConfig>,
+    pub(crate) compression: Option<CompressionConfig>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct S3Config {
+    pub(crate) endpoint: String,
+    pub(crate) region: String,
+    pub(crate) bucket: String,
+    pub(crate) prefix: String,
+    pub(crate) access_key: String,
+    pub(crate) secret_key: String,
+    pub(crate) path_style: bool,
+    pub(crate) part_size: usize
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct CompressionConfig {
+    pub(crate) enabled: bool,
+    pub(crate) level: i32,
+    pub(crate) patterns: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct EncryptionConfig {
+    pub(crate) master_key_file: String,
+    pub(crate) key_dir: String
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct DedupConfig {
+    pub(crate) dir: String
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct MemoryConfig {
+    pub(crate) max_size: u64,
+    pub(crate) max_files: u64
+}
+
+/// A directory mounted into the trees of the users it is given to, `dir` can use `{username}` and `{dir}`
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct FolderConfig {
+    pub(crate) path: String,
+    pub(crate) dir: String,
+    pub(crate) read_only: bool,
+    pub(crate) max_size: u64,
+    pub(crate) max_files: u64,
+    pub(crate) users: Vec<String>
+}
+
+/// Settings shared by the users of a group, the ones that are set replace the global ones for them
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct GroupConfig {
+    pub(crate) users: Vec<String>,
+    pub(crate) folders: Vec<String>,
+    pub(crate) backend: Option<Backend>,
+    pub(crate) compression: Option<bool>,
+    pub(crate) umask: Option<u32>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) max_files: Option<u64>,
+    pub(crate) mode: Option<AccountMode>
+}
+
+/// A rule allowing or denying operations on the paths matching one of `paths`, for the users listed in `users`
+/// and the members of `groups`, or everyone if both are empty
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct PermissionConfig {
+    pub(crate) users: Vec<String>,
+    pub(crate) groups: Vec<String>,
+    pub(crate) paths: Vec<String>,
+    pub(crate) allow: Vec<Permission>,
+    pub(crate) deny: Vec<Permission>
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum Permission {
+    All,
+    List,
+    Download,
+    Upload,
+    Overwrite,
+    Delete,
+    Rename,
+    Mkdir,
+    Symlink,
+    Chmod,
+    Chtimes
+}
+
+impl Permission {
+    /// Whether a rule naming this permission also covers `permission`
+    pub(crate) fn covers(self, permission: Permission) -> bool {
+        self == Permission::All || self == permission
+    }
+}
+
+impl TryFrom<String> for Permission {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "*" => Ok(Permission::All),
+            "list" => Ok(Permission::List),
+            "download" => Ok(Permission::Download),
+            "upload" => Ok(Permission::Upload),
+            "overwrite" => Ok(Permission::Overwrite),
+            "delete" => Ok(Permission::Delete),
+            "rename" => Ok(Permission::Rename),
+            "mkdir" => Ok(Permission::Mkdir),
+            "symlink" => Ok(Permission::Symlink),
+            "chmod" => Ok(Permission::Chmod),
+            "chtimes" => Ok(Permission::Chtimes),
+            name => Err(format!("unknown permission: {}", name))
+        }
+    }
+}
+
+impl From<Permission> for String {
+    fn from(value: Permission) -> Self {
+        match value {
+            Permission::All => String::from("*"),
+            Permission::List => String::from("list"),
+            Permission::Download => String::from("download"),
+            Permission::Upload => String::from("upload"),
+            Permission::Overwrite => String::from("overwrite"),
+            Permission::Delete => String::from("delete"),
+            Permission::Rename => String::from("rename"),
+            Permission::Mkdir => String::from("mkdir"),
+            Permission::Symlink => String::from("symlink"),
+            Permission::Chmod => String::from("chmod"),
+            Permission::Chtimes => String::from("chtimes")
+        }
+    }
+}
+
+/// What a user can do with their files, by name so it can also come from a database column
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum AccountMode {
+    Normal,
+    ReadOnly,
+    DropBox
+}
+
+impl TryFrom<String> for AccountMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "normal" => Ok(AccountMode::Normal),
+            "read-only" => Ok(AccountMode::ReadOnly),
+            "drop-box" => Ok(AccountMode::DropBox),
+            name => Err(format!("unknown account mode: {}", name))
+        }
+    }
+}
+
+impl From<AccountMode> for String {
+    fn from(value: AccountMode) -> Self {
+        match value {
+            AccountMode::Normal => String::from("normal"),
+            AccountMode::ReadOnly => String::from("read-only"),
+            AccountMode::DropBox => String::from("drop-box")
+        }
+    }
+}
+
+/// Where a user's files are kept, by name so it can also come from a database column
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum Backend {
+    Local,
+    S3,
+    Memory,
+    Dedup
+}
+
+impl TryFrom<String> for Backend {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "local" => Ok(Backend::Local),
+            "s3" => Ok(Backend::S3),
+            "memory" => Ok(Backend::Memory),
+            "dedup" => Ok(Backend::Dedup),
+            name => Err(format!("unknown storage backend: {}", name))
+        }
+    }
+}
+
+impl From<Backend> for String {
+    fn from(value: Backend) -> Self {
+        match value {
+            Backend::Local => String::from("local"),
+            Backend::S3 => String::from("s3"),
+            Backend::Memory => String::from("memory"),
+            Backend::Dedup => String::from("dedup")
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: Backend::Local,
+            s3: None,
+            memory: MemoryConfig::default(),
+            dedup: None,
+            encryption: None,
+            compression: None
+        }
+    }
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            endpoint: String::new(),
+            region: String::from("us-east-1"),
+            bucket: String::new(),
+            prefix: String::new(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            path_style: false,
+            part_size: 8 * 1024 * 1024
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            level: 3,
+            patterns: Vec::new()
+        }
+    }
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            max_size: 64 * 1024 * 1024,
+            max_files: 10000
+        }
+    }
+}
+
+impl Default for ExecConfig {
+    fn default() -> Self {
+        ExecConfig {
+            hash_commands: ["md5sum", "sha1sum", "sha256sum", "sha512sum"].map(String::from).to_vec()
+        }
+    }
+}
+
+impl Default for SftpConfig {
+    fn default() -> Self {
+        SftpConfig {
+            realpath_resolve_symlinks: true,
+            max_handles: 256,
+            max_concurrent_requests: 32,
+            read_ahead_size: 1024 * 1024,
+            write_buffer_size: 1024 * 1024,
+            atomic_uploads: false,
+            partial_uploads: PartialUploads::Delete,
+            chmod_mask: 0o777,
+            umask: 0o022,
+            file_mode: 0o666,
+            dir_mode: 0o777,
+            honor_client_permissions: true,
+            chown: ChownPolicy::Ignore,
+            uid_map: HashMap::new(),
+            gid_map: HashMap::new(),
+            symlinks: SymlinkPolicy::WithinJail,
+            owner: OwnerDisplay::Real,
+            timezone: Timezone::Local,
+            sort_directory_listings: false,
+            max_directory_entries: 0,
+            drop_box_overwrite: false
+        }
+    }
+}
+
+impl Default for UsersConfig {
+    fn default() -> Self {
+        UsersConfig {
+            username_regex: String::from("^[A-Za-z0-9_][A-Za-z0-9_.-]*$"),
+            max_username_length: 32,
+            lowercase_usernames: false,
+            unicode_normalization: Normalization::None
+        }
+    }
 }
 
 
@@ -73,9 +528,25 @@ impl Default for Config {
                     table: String::from("users"),
                     username_field: String::from("username"),
                     public_key_field: Some(String::from("public_key")),
-                    password_field: None
-                } 
-            }
+                    password_field: None,
+                    dir_field: None,
+                    umask_field: None,
+                    backend_field: None,
+                    compression_field: None,
+                    folders_field: None,
+                    groups_field: None,
+                    max_size_field: None,
+                    max_files_field: None,
+                    mode_field: None
+                }
+            },
+            users: UsersConfig::default(),
+            sftp: SftpConfig::default(),
+            exec: ExecConfig::default(),
+            storage: StorageConfig::default(),
+            folders: HashMap::new(),
+            groups: BTreeMap::new(),
+            permissions: Vec::new()
         }
     }
 }
@@ -0,0 +1,812 @@
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{self, ErrorKind, Read, Write}, os::{fd::{AsFd, AsRawFd}, unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt}}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex as StdMutex}, time::Duration};
+
+use libc::c_int;
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{checksum::to_hex, config::DedupConfig, jail::{self, blocking, Jail, LocalFile}, storage::{BoxFuture, DirEntry, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile}};
+
+// a pointer file holds the hex encoded SHA-256 of the file's contents, empty files have no blob
+const HASH_LEN: usize = 64;
+const HASH_BUF_SIZE: usize = 1024 * 1024;
+// uploads older than this in the staging directory were left behind by a crash, `gc` removes them unless a handle
+// still has them open and locked
+const STALE_UPLOAD: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn is_hash(name: &str) -> bool {
+    name.len() == HASH_LEN && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn create_dir(path: &Path) -> io::Result<()> {
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        res => res
+    }
+}
+
+fn flock(file: &File, operation: c_int) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+// set after the previous contents are copied in, copy_file_range refuses to write to O_APPEND files
+fn set_append(file: &File) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_APPEND) } < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+fn hash_contents(file: &File) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; HASH_BUF_SIZE];
+    let mut offset = 0;
+    loop {
+        match file.read_at(&mut buf, offset) {
+            Ok(0) => return Ok(to_hex(&hasher.finalize())),
+            Ok(n) => {
+                hasher.update(&buf[..n]);
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+// the hash a pointer file holds, None for an empty file
+async fn read_pointer(pointer: &dyn StorageFile) -> io::Result<Option<String>> {
+    let data = pointer.read_at(0, HASH_LEN + 1).await?;
+    if data.is_empty() {
+        return Ok(None)
+    }
+    match String::from_utf8(data) {
+        Ok(hash) if is_hash(&hash) => Ok(Some(hash)),
+        _ => Err(io::Error::new(ErrorKind::InvalidData, "not a deduplicated file"))
+    }
+}
+
+async fn write_pointer(pointer: &dyn StorageFile, hash: Option<&str>) -> io::Result<()> {
+    if let Some(hash) = hash {
+        pointer.write_at(0, hash.as_bytes().to_vec()).await.1?;
+    }
+    let size = if hash.is_some() { HASH_LEN as u64 } else { 0 };
+    pointer.set_attributes(SetAttributes { size: Some(size), ..Default::default() }).await
+}
+
+// writes the pointer of a file that was given a reference to `hash`, the reference is dropped again if the pointer
+// ends up not holding it. a count left too high is corrected by `gc`
+async fn point_to(store: &Arc<BlobStore>, pointer: &dyn StorageFile, hash: Option<&str>) -> io::Result<()> {
+    let written = write_pointer(pointer, hash).await;
+    match (&written, hash) {
+        (Err(_), Some(hash)) if read_pointer(pointer).await.ok().flatten().as_deref() != Some(hash) => {
+            let _ = store.release(hash).await;
+        }
+        _ => {}
+    }
+    written
+}
+
+// size of a file's contents, 0 if its pointer can't be read
+async fn content_size(namespace: &Jail, store: &BlobStore, path: &str) -> u64 {
+    let Ok(pointer) = namespace.open(path, OpenOptions { read: true, ..Default::default() }, 0).await else {
+        return 0
+    };
+    let Ok(Some(hash)) = read_pointer(&*pointer).await else {
+        return 0
+    };
+    let blob = store.blob_path(&hash);
+    blocking(move || fs::metadata(blob)).await.map_or(0, |metadata| metadata.len())
+}
+
+/// What a `gc` run did
+#[derive(Default)]
+pub(crate) struct Collected {
+    pub(crate) blobs: u64,
+    pub(crate) bytes: u64,
+    pub(crate) counts: u64,
+    pub(crate) uploads: u64,
+    pub(crate) missing: u64
+}
+
+/// The contents of all dedup users' files, every distinct content is stored once as `blobs/ab/abcd...` named by
+/// its SHA-256, next to a `.refs` file counting the files pointing to it. users' directories are kept under
+/// `users/` and only hold pointer files
+pub(crate) struct BlobStore {
+    dir: PathBuf,
+    // held while reference counts change, the file is also flock'ed so `gc` never runs at the same time
+    lock: Mutex<File>
+}
+
+struct StoreLock<'a> {
+    file: MutexGuard<'a, File>
+}
+
+impl Drop for StoreLock<'_> {
+    fn drop(&mut self) {
+        let _ = flock(&self.file, libc::LOCK_UN);
+    }
+}
+
+impl BlobStore {
+    pub(crate) fn open(config: &DedupConfig) -> Result<Self, String> {
+        let dir = PathBuf::from(&config.dir);
+        for sub in ["blobs", "users", "tmp"] {
+            let path = dir.join(sub);
+            create_dir(&path).map_err(|e| format!("error creating dedup directory {}: {}", path.display(), e))?;
+        }
+        let lock_path = dir.join("lock");
+        let lock = fs::OpenOptions::new().create(true).append(true).mode(0o600).open(&lock_path)
+            .map_err(|e| format!("error opening dedup lock file {}: {}", lock_path.display(), e))?;
+        Ok(BlobStore { dir, lock: Mutex::new(lock) })
+    }
+
+    /// The directory a user's pointer files are kept in, created the first time they log in
+    pub(crate) fn namespace(&self, dir: &str) -> io::Result<Jail> {
+        let path = self.dir.join("users").join(dir);
+        create_dir(&path)?;
+        Jail::new(&path.to_string_lossy())
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("blobs").join(&hash[..2]).join(hash)
+    }
+
+    fn refs_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("blobs").join(&hash[..2]).join(format!("{}.refs", hash))
+    }
+
+    async fn lock(&self) -> io::Result<StoreLock<'_>> {
+        let file = self.lock.lock().await;
+        let fd = file.try_clone()?;
+        blocking(move || flock(&fd, libc::LOCK_EX)).await?;
+        Ok(StoreLock { file })
+    }
+
+    // a new file in the staging directory, on the same filesystem as the blobs so it can be renamed into place
+    fn staging(&self) -> io::Result<(PathBuf, File)> {
+        let path = self.dir.join("tmp").join(to_hex(&rand::random::<[u8; 16]>()));
+        let file = fs::OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(&path)?;
+        // held as long as the file is open, so `gc` leaves the upload alone however old it is
+        flock(&file, libc::LOCK_EX | libc::LOCK_NB)?;
+        Ok((path, file))
+    }
+
+    // None if the count file is missing or damaged, such blobs are left alone until `gc` counts them again
+    fn read_refs(&self, hash: &str) -> Option<u64> {
+        fs::read_to_string(self.refs_path(hash)).ok()?.trim().parse().ok()
+    }
+
+    fn write_refs(&self, hash: &str, refs: u64) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(self.refs_path(hash))?;
+        file.write_all(refs.to_string().as_bytes())
+    }
+
+    // a blob nothing points to anymore is removed right away
+    fn update_refs(&self, hash: &str, add: bool) -> io::Result<()> {
+        let Some(refs) = self.read_refs(hash) else {
+            return Ok(())
+        };
+        let refs = if add { refs + 1 } else { refs.saturating_sub(1) };
+        if refs > 0 {
+            return self.write_refs(hash, refs)
+        }
+        remove_if_exists(&self.blob_path(hash))?;
+        remove_if_exists(&self.refs_path(hash))
+    }
+
+    async fn add_ref(self: &Arc<Self>, hash: &str) -> io::Result<()> {
+        let (store, hash) = (self.clone(), hash.to_string());
+        blocking(move || store.update_refs(&hash, true)).await
+    }
+
+    async fn release(self: &Arc<Self>, hash: &str) -> io::Result<()> {
+        let (store, hash) = (self.clone(), hash.to_string());
+        blocking(move || store.update_refs(&hash, false)).await
+    }
+
+    // moves a finished upload into the store, or drops it if the same contents are stored already
+    fn insert(&self, staging: &Path, hash: &str) -> io::Result<()> {
+        let blob = self.blob_path(hash);
+        if blob.exists() {
+            fs::remove_file(staging)?;
+            return self.update_refs(hash, true)
+        }
+        create_dir(&self.dir.join("blobs").join(&hash[..2]))?;
+        fs::rename(staging, &blob)?;
+        self.write_refs(hash, 1)
+    }
+
+    /// Counts the files pointing to every blob, corrects the counts that are off and removes blobs nothing points to,
+    /// e.g. after a user's directory was deleted or the server was stopped halfway through updating a file
+    pub(crate) async fn collect_garbage(self: Arc<Self>) -> io::Result<Collected> {
+        let _lock = self.lock().await?;
+        let store = self.clone();
+        blocking(move || store.sweep()).await
+    }
+
+    fn sweep(&self) -> io::Result<Collected> {
+        let mut collected = Collected::default();
+        let mut refs = HashMap::new();
+        count_refs(&self.dir.join("users"), &mut refs, &mut HashSet::new())?;
+
+        for prefix in fs::read_dir(self.dir.join("blobs"))? {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue
+            }
+            for entry in fs::read_dir(prefix.path())? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if let Some(hash) = name.strip_suffix(".refs") {
+                    if !is_hash(hash) || !self.blob_path(hash).exists() {
+                        remove_if_exists(&entry.path())?;
+                    }
+                    continue
+                }
+                if !is_hash(&name) {
+                    continue
+                }
+                match refs.remove(&name) {
+                    None => {
+                        collected.bytes += entry.metadata()?.len();
+                        collected.blobs += 1;
+                        remove_if_exists(&entry.path())?;
+                        remove_if_exists(&self.refs_path(&name))?;
+                    }
+                    Some(count) if self.read_refs(&name) != Some(count) => {
+                        self.write_refs(&name, count)?;
+                        collected.counts += 1;
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        // files whose blob is gone, their contents are lost
+        collected.missing = refs.len() as u64;
+
+        for entry in fs::read_dir(self.dir.join("tmp"))? {
+            let entry = entry?;
+            if !entry.metadata()?.modified()?.elapsed().is_ok_and(|age| age > STALE_UPLOAD) {
+                continue
+            }
+            let Ok(file) = File::open(entry.path()) else {
+                continue
+            };
+            if flock(&file, libc::LOCK_EX | libc::LOCK_NB).is_err() {
+                continue
+            }
+            remove_if_exists(&entry.path())?;
+            collected.uploads += 1;
+        }
+        Ok(collected)
+    }
+}
+
+// hard links share a pointer, they are counted once
+fn count_refs(dir: &Path, refs: &mut HashMap<String, u64>, seen: &mut HashSet<(u64, u64)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            count_refs(&entry.path(), refs, seen)?;
+            continue
+        }
+        if !metadata.is_file() || (metadata.nlink() > 1 && !seen.insert((metadata.dev(), metadata.ino()))) {
+            continue
+        }
+        let mut data = Vec::new();
+        File::open(entry.path())?.take(HASH_LEN as u64 + 1).read_to_end(&mut data)?;
+        if let Some(hash) = String::from_utf8(data).ok().filter(|hash| is_hash(hash)) {
+            *refs.entry(hash).or_default() += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Stores every distinct file content once in a blob store shared by all users. The user's directory tree is kept
+/// as it is, with pointer files holding the hash of their contents, so everything but the contents works like the
+/// local backend. changes to a file are made to a private copy which replaces the file's contents when it is closed
+pub(crate) struct DedupBackend {
+    inner: Jail,
+    store: Arc<BlobStore>
+}
+
+impl DedupBackend {
+    pub(crate) fn new(inner: Jail, store: Arc<BlobStore>) -> Self {
+        DedupBackend { inner, store }
+    }
+
+    // the blob a file stops pointing to when it is removed or replaced, none if other hard links still point to it
+    async fn unlinked_blob(&self, path: &str) -> Option<String> {
+        let st = self.inner.stat(path, false).await.ok()?;
+        if !st.is_file() || st.nlink > 1 {
+            return None
+        }
+        let pointer = self.inner.open(path, OpenOptions { read: true, ..Default::default() }, 0).await.ok()?;
+        read_pointer(&*pointer).await.ok()?
+    }
+
+    // renaming or copying a file onto itself leaves it as it is
+    async fn same_file(&self, a: &str, b: &str) -> bool {
+        matches!(self.inner.stat(a, false).await, Ok(st) if st.is_file())
+            && matches!((self.inner.canonicalize(a).await, self.inner.canonicalize(b).await), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+impl StorageBackend for DedupBackend {
+    fn owner(&self) -> io::Result<(u32, u32)> {
+        self.inner.owner()
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.canonicalize(path)
+    }
+
+    // the pointer is opened with the client's options, so permissions and exclusive creation work as on disk.
+    // it is only truncated once the new contents are stored
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            let writing = options.write || options.append;
+            let pointer = self.inner.open(path, OpenOptions { read: true, write: writing, append: false, truncate: false, ..options }, mode).await?;
+            let hash = read_pointer(&*pointer).await?;
+            let blob = match &hash {
+                Some(hash) => {
+                    let blob = self.store.blob_path(hash);
+                    match blocking(move || File::open(blob)).await {
+                        Ok(blob) => Some(blob),
+                        Err(e) if e.kind() == ErrorKind::NotFound => return Err(io::Error::new(ErrorKind::InvalidData, "the file's contents are missing from the blob store")),
+                        Err(e) => return Err(e)
+                    }
+                }
+                None => None
+            };
+            if !writing {
+                let file = DedupFile { store: self.store.clone(), pointer, data: blob.map(LocalFile::from), staging: StdMutex::new(None), changed: AtomicBool::new(false) };
+                return Ok(Box::new(file) as Box<dyn StorageFile>)
+            }
+
+            let store = self.store.clone();
+            let (staging_path, staging) = blocking(move || store.staging()).await?;
+            let copy = match &blob {
+                Some(blob) if !options.truncate => jail::copy_data(blob.as_fd(), 0, 0, staging.as_fd(), 0).await,
+                _ => Ok(())
+            };
+            let copy = copy.and_then(|()| if options.append { set_append(&staging) } else { Ok(()) });
+            let file = DedupFile {
+                store: self.store.clone(),
+                pointer,
+                data: Some(LocalFile::from(staging)),
+                staging: StdMutex::new(Some(staging_path)),
+                changed: AtomicBool::new(options.truncate && hash.is_some())
+            };
+            copy?;
+            Ok(Box::new(file) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+        Box::pin(async move {
+            let mut st = self.inner.stat(path, follow).await?;
+            if st.is_file() {
+                st.size = content_size(&self.inner, &self.store, path).await;
+            }
+            Ok(st)
+        })
+    }
+
+    // sizes are changed through an open file since the contents are replaced as a whole
+    fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, follow: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let Some(size) = attrs.size else {
+                return self.inner.set_attributes(path, attrs, follow).await
+            };
+            if !follow && !self.inner.stat(path, false).await?.is_file() {
+                return self.inner.set_attributes(path, attrs, follow).await
+            }
+            let file = self.open(path, OpenOptions { write: true, ..Default::default() }, 0).await?;
+            file.set_attributes(SetAttributes { size: Some(size), ..Default::default() }).await?;
+            file.close().await?;
+            self.inner.set_attributes(path, SetAttributes { size: None, ..attrs }, follow).await
+        })
+    }
+
+    fn list<'a>(&'a self, path: &'a str, sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+        Box::pin(async move {
+            let stream = self.inner.list(path, sort, limit).await?;
+            Ok(Box::new(DedupDir { stream, inner: self.inner.clone(), store: self.store.clone(), dir: jail::normalize(path) }) as Box<dyn DirStream>)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let _lock = self.store.lock().await?;
+            let blob = self.unlinked_blob(path).await;
+            self.inner.remove_file(path).await?;
+            match blob {
+                Some(hash) => self.store.release(&hash).await,
+                None => Ok(())
+            }
+        })
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.create_dir(path, mode)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.remove_dir(path)
+    }
+
+    // a file that is replaced no longer points to its blob
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            if !replace {
+                return self.inner.rename(from, to, replace).await
+            }
+            let _lock = self.store.lock().await?;
+            let replaced = if self.same_file(from, to).await { None } else { self.unlinked_blob(to).await };
+            self.inner.rename(from, to, replace).await?;
+            match replaced {
+                Some(hash) => self.store.release(&hash).await,
+                None => Ok(())
+            }
+        })
+    }
+
+    fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+        self.inner.statvfs(path)
+    }
+
+    fn read_link<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.read_link(path)
+    }
+
+    fn symlink<'a>(&'a self, target: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.symlink(target, path)
+    }
+
+    fn hard_link<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.hard_link(from, to)
+    }
+
+    // the copy points to the same blob, no data is copied
+    fn copy_file<'a>(&'a self, from: &'a str, to: &'a str, overwrite: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let _lock = self.store.lock().await?;
+            let source = self.inner.open(from, OpenOptions { read: true, ..Default::default() }, 0).await?;
+            let st = source.stat().await?;
+            if !st.is_file() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "not a regular file"))
+            }
+            if self.same_file(from, to).await {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "source and destination are the same file"))
+            }
+            let hash = read_pointer(&*source).await?;
+            let options = OpenOptions { read: true, write: true, create: true, exclusive: !overwrite, ..Default::default() };
+            let dest = self.inner.open(to, options, st.mode & 0o777).await?;
+            let old = read_pointer(&*dest).await.unwrap_or(None);
+            if let Some(hash) = &hash {
+                self.store.add_ref(hash).await?;
+            }
+            point_to(&self.store, &*dest, hash.as_deref()).await?;
+            match old {
+                Some(old) => self.store.release(&old).await,
+                None => Ok(())
+            }
+        })
+    }
+}
+
+/// A file of the dedup backend. Files opened for writing work on a private copy in the staging directory
+struct DedupFile {
+    store: Arc<BlobStore>,
+    // the client visible file, its attributes are the file's attributes
+    pointer: Box<dyn StorageFile>,
+    // the blob being read or the private copy, None for an empty file opened for reading
+    data: Option<LocalFile>,
+    // path of the private copy until it is stored or thrown away
+    staging: StdMutex<Option<PathBuf>>,
+    changed: AtomicBool
+}
+
+impl DedupFile {
+    fn writable(&self) -> bool {
+        self.staging.lock().unwrap().is_some()
+    }
+
+    // hashes the private copy and points the file to the blob with its contents, under the store's lock since
+    // the file's previous blob is released
+    async fn commit(&self, staging: &Path, data: &LocalFile) -> io::Result<()> {
+        let file = data.as_local().ok_or_else(|| io::Error::from(ErrorKind::Unsupported))?.try_clone()?;
+        let hash = match data.stat().await?.size {
+            0 => None,
+            _ => Some(blocking(move || hash_contents(&file)).await?)
+        };
+        let _lock = self.store.lock().await?;
+        // removed while it was open, like on disk the contents go with it
+        if self.pointer.stat().await?.nlink == 0 {
+            return Ok(())
+        }
+        let old = read_pointer(&*self.pointer).await.unwrap_or(None);
+        if old == hash {
+            return Ok(())
+        }
+        if let Some(hash) = hash.clone() {
+            let (store, staging) = (self.store.clone(), staging.to_path_buf());
+            blocking(move || store.insert(&staging, &hash)).await?;
+        }
+        point_to(&self.store, &*self.pointer, hash.as_deref()).await?;
+        match old {
+            Some(old) => self.store.release(&old).await,
+            None => Ok(())
+        }
+    }
+}
+
+impl StorageFile for DedupFile {
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        match &self.data {
+            Some(data) => data.read_at(offset, len),
+            None => Box::pin(async { Ok(Vec::new()) })
+        }
+    }
+
+    fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)> {
+        match &self.data {
+            Some(file) if self.writable() => {
+                self.changed.store(true, Ordering::Relaxed);
+                file.write_at(offset, data)
+            }
+            _ => Box::pin(async { (data, Err(io::Error::from_raw_os_error(libc::EBADF))) })
+        }
+    }
+
+    fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>> {
+        Box::pin(async move {
+            let mut st = self.pointer.stat().await?;
+            st.size = match &self.data {
+                Some(data) => data.stat().await?.size,
+                None => 0
+            };
+            Ok(st)
+        })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if let Some(size) = attrs.size {
+                let Some(data) = self.data.as_ref().filter(|_| self.writable()) else {
+                    return Err(io::Error::from_raw_os_error(libc::EBADF))
+                };
+                data.set_attributes(SetAttributes { size: Some(size), ..Default::default() }).await?;
+                self.changed.store(true, Ordering::Relaxed);
+            }
+            self.pointer.set_attributes(SetAttributes { size: None, ..attrs }).await
+        })
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if let Some(data) = &self.data {
+                data.sync().await?;
+            }
+            self.pointer.sync().await
+        })
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        self.pointer.statvfs()
+    }
+
+    // unchanged copies are thrown away, as is one whose contents are stored already
+    fn close(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let (Some(staging), Some(data)) = (self.staging.lock().unwrap().take(), &self.data) else {
+                return Ok(())
+            };
+            let result = if self.changed.load(Ordering::Relaxed) { self.commit(&staging, data).await } else { Ok(()) };
+            blocking(move || remove_if_exists(&staging)).await?;
+            result
+        })
+    }
+
+    fn will_need(&self, offset: u64, len: u64) {
+        if let Some(data) = &self.data {
+            data.will_need(offset, len);
+        }
+    }
+}
+
+// a file that is never closed loses its changes
+impl Drop for DedupFile {
+    fn drop(&mut self) {
+        if let Some(staging) = self.staging.lock().unwrap().take() {
+            let _ = fs::remove_file(staging);
+        }
+    }
+}
+
+// a directory listing with the sizes of the pointers replaced by the sizes of the contents
+struct DedupDir {
+    stream: Box<dyn DirStream>,
+    inner: Jail,
+    store: Arc<BlobStore>,
+    dir: String
+}
+
+impl DirStream for DedupDir {
+    fn next_entries(&mut self, count: usize) -> BoxFuture<'_, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            let mut entries = self.stream.next_entries(count).await?;
+            for entry in entries.iter_mut().filter(|entry| entry.stat.is_file()) {
+                let path = if self.dir.is_empty() { entry.name.clone() } else { format!("{}/{}", self.dir, entry.name) };
+                entry.stat.size = content_size(&self.inner, &self.store, &path).await;
+            }
+            Ok(entries)
+        })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        self.stream.set_attributes(attrs)
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        self.stream.statvfs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    // a blob store with a single user, removed when dropped
+    struct Setup {
+        dir: PathBuf,
+        store: Arc<BlobStore>,
+        backend: DedupBackend
+    }
+
+    impl Setup {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("flux-sftp-dedup-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            let store = Arc::new(BlobStore::open(&DedupConfig { dir: dir.to_string_lossy().into_owned() }).unwrap());
+            let backend = DedupBackend::new(store.namespace("user").unwrap(), store.clone());
+            Setup { dir, store, backend }
+        }
+
+        // reference counts of the blobs in the store, by hash
+        fn blobs(&self) -> HashMap<String, Option<u64>> {
+            let mut blobs = HashMap::new();
+            for prefix in fs::read_dir(self.dir.join("blobs")).unwrap() {
+                for entry in fs::read_dir(prefix.unwrap().path()).unwrap() {
+                    let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+                    if is_hash(&name) {
+                        blobs.insert(name.clone(), self.store.read_refs(&name));
+                    }
+                }
+            }
+            blobs
+        }
+
+        fn uploads(&self) -> Vec<PathBuf> {
+            fs::read_dir(self.dir.join("tmp")).unwrap().map(|entry| entry.unwrap().path()).collect()
+        }
+
+        async fn upload(&self, path: &str, data: &[u8]) {
+            let file = self.backend.open(path, OpenOptions { write: true, create: true, truncate: true, ..Default::default() }, 0o644).await.unwrap();
+            file.write_at(0, data.to_vec()).await.1.unwrap();
+            file.close().await.unwrap();
+        }
+
+        async fn read(&self, path: &str) -> Vec<u8> {
+            let file = self.backend.open(path, OpenOptions { read: true, ..Default::default() }, 0).await.unwrap();
+            file.read_at(0, 1024).await.unwrap()
+        }
+    }
+
+    impl Drop for Setup {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn hash(data: &[u8]) -> String {
+        to_hex(&Sha256::digest(data))
+    }
+
+    fn make_stale(path: &Path) {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - 2 * STALE_UPLOAD).unwrap();
+    }
+
+    #[tokio::test]
+    async fn identical_contents_are_stored_once() {
+        let setup = Setup::new("identical");
+        setup.upload("a", b"hello").await;
+        setup.upload("b", b"hello").await;
+        setup.upload("c", b"other").await;
+        setup.upload("empty", b"").await;
+        assert_eq!(setup.blobs(), HashMap::from([(hash(b"hello"), Some(2)), (hash(b"other"), Some(1))]));
+        assert_eq!(setup.read("b").await, b"hello");
+        assert_eq!(setup.read("empty").await, b"");
+        assert_eq!(setup.backend.stat("a", true).await.unwrap().size, 5);
+        assert!(setup.uploads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn remove() {
+        let setup = Setup::new("remove");
+        setup.upload("a", b"hello").await;
+        setup.upload("b", b"hello").await;
+        setup.backend.remove_file("a").await.unwrap();
+        assert_eq!(setup.blobs(), HashMap::from([(hash(b"hello"), Some(1))]));
+        setup.backend.remove_file("b").await.unwrap();
+        assert!(setup.blobs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn overwrite() {
+        let setup = Setup::new("overwrite");
+        setup.upload("a", b"one").await;
+        setup.upload("a", b"two").await;
+        assert_eq!(setup.blobs(), HashMap::from([(hash(b"two"), Some(1))]));
+        // changes are made to a copy, the blob is only replaced on close
+        let file = setup.backend.open("a", OpenOptions { write: true, ..Default::default() }, 0).await.unwrap();
+        file.write_at(3, b" three".to_vec()).await.1.unwrap();
+        assert_eq!(setup.read("a").await, b"two");
+        file.close().await.unwrap();
+        assert_eq!(setup.read("a").await, b"two three");
+        assert_eq!(setup.blobs(), HashMap::from([(hash(b"two three"), Some(1))]));
+        // copies point to the blob, the one they replace is released
+        setup.upload("b", b"one").await;
+        setup.backend.copy_file("a", "b", true).await.unwrap();
+        assert_eq!(setup.blobs(), HashMap::from([(hash(b"two three"), Some(2))]));
+        setup.backend.set_attributes("a", SetAttributes { size: Some(0), ..Default::default() }, true).await.unwrap();
+        setup.backend.remove_file("b").await.unwrap();
+        assert!(setup.blobs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn collect_garbage() {
+        let setup = Setup::new("gc");
+        setup.upload("a", b"hello").await;
+        setup.upload("b", b"other").await;
+        // a count that is off, a blob whose file went away behind the server's back and an upload left by a crash
+        setup.store.write_refs(&hash(b"hello"), 5).unwrap();
+        fs::remove_file(setup.dir.join("users/user/b")).unwrap();
+        fs::write(setup.dir.join("tmp/left"), b"left").unwrap();
+        make_stale(&setup.dir.join("tmp/left"));
+        // an upload that is old but still open
+        let file = setup.backend.open("c", OpenOptions { write: true, create: true, ..Default::default() }, 0o644).await.unwrap();
+        file.write_at(0, b"open".to_vec()).await.1.unwrap();
+        let open = setup.uploads().into_iter().find(|path| !path.ends_with("left")).unwrap();
+        make_stale(&open);
+
+        let collected = setup.store.clone().collect_garbage().await.unwrap();
+        assert_eq!((collected.blobs, collected.bytes, collected.counts, collected.uploads, collected.missing), (1, 5, 1, 1, 0));
+        assert_eq!(setup.blobs(), HashMap::from([(hash(b"hello"), Some(1))]));
+        assert_eq!(setup.uploads(), vec![open]);
+        file.close().await.unwrap();
+        assert_eq!(setup.read("c").await, b"open");
+        assert!(setup.uploads().is_empty());
+
+        fs::remove_file(setup.dir.join("users/user/a")).unwrap();
+        fs::remove_file(setup.dir.join("users/user/c")).unwrap();
+        setup.upload("d", b"missing").await;
+        fs::remove_file(setup.store.blob_path(&hash(b"missing"))).unwrap();
+        let collected = setup.store.clone().collect_garbage().await.unwrap();
+        assert_eq!((collected.blobs, collected.missing), (2, 1));
+        assert!(setup.blobs().is_empty());
+    }
+}
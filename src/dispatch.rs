@@ -0,0 +1,384 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use russh_sftp::{protocol::{Packet, StatusCode}, server::Handler as SftpHandler};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, sync::{mpsc, watch, Semaphore}};
+
+use crate::sftp::{self, SftpSession, MAX_PACKET_LEN};
+
+macro_rules! into_wrap {
+    ($id:expr, $handler:expr, $var:ident; $($arg:ident),*) => {
+        match $handler.$var($($var.$arg),*).await {
+            Err(err) => Packet::error($id, err),
+            Ok(packet) => packet.into(),
+        }
+    };
+}
+
+async fn process_request(packet: Packet, session: &mut SftpSession) -> Packet {
+    let id = packet.get_request_id();
+
+    match packet {
+        Packet::Init(init) => into_wrap!(id, session, init; version, extensions),
+        Packet::Open(open) => into_wrap!(id, session, open; id, filename, pflags, attrs),
+        Packet::Close(close) => into_wrap!(id, session, close; id, handle),
+        Packet::Read(read) => into_wrap!(id, session, read; id, handle, offset, len),
+        Packet::Write(write) => into_wrap!(id, session, write; id, handle, offset, data),
+        Packet::Lstat(lstat) => into_wrap!(id, session, lstat; id, path),
+        Packet::Fstat(fstat) => into_wrap!(id, session, fstat; id, handle),
+        Packet::SetStat(setstat) => into_wrap!(id, session, setstat; id, path, attrs),
+        Packet::FSetStat(fsetstat) => into_wrap!(id, session, fsetstat; id, handle, attrs),
+        Packet::OpenDir(opendir) => into_wrap!(id, session, opendir; id, path),
+        Packet::ReadDir(readdir) => into_wrap!(id, session, readdir; id, handle),
+        Packet::Remove(remove) => into_wrap!(id, session, remove; id, filename),
+        Packet::MkDir(mkdir) => into_wrap!(id, session, mkdir; id, path, attrs),
+        Packet::RmDir(rmdir) => into_wrap!(id, session, rmdir; id, path),
+        Packet::RealPath(realpath) => into_wrap!(id, session, realpath; id, path),
+        Packet::Stat(stat) => into_wrap!(id, session, stat; id, path),
+        Packet::Rename(rename) => into_wrap!(id, session, rename; id, oldpath, newpath),
+        Packet::ReadLink(readlink) => into_wrap!(id, session, readlink; id, path),
+        Packet::Symlink(symlink) => into_wrap!(id, session, symlink; id, linkpath, targetpath),
+        Packet::Extended(extended) => into_wrap!(id, session, extended; id, request, data),
+        _ => Packet::error(0, StatusCode::BadMessage),
+    }
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Option<Bytes> {
+    let len = reader.read_u32().await.ok()?;
+    // same limit we announce through limits@openssh.com, anything larger is a broken or hostile client
+    if len as u64 > MAX_PACKET_LEN {
+        println!("sftp packet too large: {}", len);
+        return None
+    }
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf).await.ok()?;
+    Some(Bytes::from(buf))
+}
+
+// a read or write running on its own task
+struct InFlight {
+    handle: String,
+    start: u64,
+    end: u64,
+    write: bool,
+    // closed once the request has finished
+    done: watch::Receiver<()>
+}
+
+impl InFlight {
+    // overlapping ranges on the same handle have to complete in the order they were sent,
+    // unless both are reads
+    fn conflicts(&self, handle: &str, start: u64, end: u64, write: bool) -> bool {
+        self.handle == handle && (self.write || write) && self.start < end && start < self.end
+    }
+
+    fn finished(&self) -> bool {
+        self.done.has_changed().is_err()
+    }
+
+    async fn wait(&self) {
+        let _ = self.done.clone().changed().await;
+    }
+}
+
+/// Runs an sftp session on the stream. Reads and writes are processed concurrently, at most `max_concurrent` at a time,
+/// every other request runs once the reads and writes received before it have finished
+pub(crate) async fn run<S>(stream: S, mut session: SftpSession, max_concurrent: usize)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (replies, mut reply_rx) = mpsc::unbounded_channel::<Packet>();
+    let writer_task = tokio::spawn(async move {
+        while let Some(reply) = reply_rx.recv().await {
+            let bytes = match Bytes::try_from(reply) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    println!("error encoding sftp reply: {}", e);
+                    continue
+                }
+            };
+            if writer.write_all(&bytes).await.is_err() || writer.flush().await.is_err() {
+                break
+            }
+        }
+    });
+    let permits = Arc::new(Semaphore::new(max_concurrent.max(1)));
+    let mut in_flight: Vec<InFlight> = Vec::new();
+
+    while let Some(mut bytes) = read_packet(&mut reader).await {
+        let packet = match Packet::try_from(&mut bytes) {
+            Ok(packet) => packet,
+            Err(_) => {
+                let _ = replies.send(Packet::error(0, StatusCode::BadMessage));
+                continue
+            }
+        };
+        in_flight.retain(|op| !op.finished());
+
+        let (id, handle, start, end, write) = match &packet {
+            Packet::Read(read) => (read.id, read.handle.clone(), read.offset, read.offset.saturating_add(read.len as u64), false),
+            Packet::Write(write) => (write.id, write.handle.clone(), write.offset, write.offset.saturating_add(write.data.len() as u64), true),
+            _ => {
+                for op in in_flight.drain(..) {
+                    op.wait().await;
+                }
+                session.flush_writes().await;
+                let _ = replies.send(process_request(packet, &mut session).await);
+                continue
+            }
+        };
+
+        let Some(file) = session.file(&handle) else {
+            println!("invalid file handle: {}", handle);
+            let _ = replies.send(Packet::error(id, StatusCode::Failure));
+            continue
+        };
+        if !write && !session.readable(&handle) {
+            let _ = replies.send(Packet::error(id, StatusCode::PermissionDenied));
+            continue
+        }
+        for op in in_flight.iter().filter(|op| op.conflicts(&handle, start, end, write)) {
+            op.wait().await;
+        }
+        // waiting here keeps us from reading more requests than we are willing to run at once
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            break
+        };
+        let (done_tx, done) = watch::channel(());
+        in_flight.push(InFlight { handle, start, end, write, done });
+
+        let replies = replies.clone();
+        tokio::spawn(async move {
+            let reply = match packet {
+                Packet::Read(read) => sftp::read_file(&file, id, read.offset, read.len).await.map(Packet::from),
+                Packet::Write(write) => sftp::write_file(&file, id, write.offset, write.data).await.map(Packet::from),
+                _ => unreachable!()
+            };
+            let _ = replies.send(reply.unwrap_or_else(|e| Packet::error(id, e)));
+            drop((permit, done_tx));
+        });
+    }
+
+    for op in in_flight.drain(..) {
+        op.wait().await;
+    }
+    session.close_all().await;
+    drop(replies);
+    let _ = writer_task.await;
+}
+
+#[cfg(test)]
+mod bench {
+    use std::{io, time::{Duration, Instant}};
+
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::{config::SftpConfig, jail::Jail, permissions::PermissionRules, storage::{BoxFuture, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile}};
+
+    const FILE_SIZE: u64 = 64 * 1024 * 1024;
+    const CHUNK: u32 = 32 * 1024;
+    // requests OpenSSH keeps in flight by default
+    const WINDOW: u64 = 64;
+
+    // a local jail that takes `delay` for every read and write, like a backend on the other side of a network
+    struct Slow {
+        inner: Jail,
+        delay: Duration
+    }
+
+    struct SlowFile {
+        inner: Box<dyn StorageFile>,
+        delay: Duration
+    }
+
+    impl StorageBackend for Slow {
+        fn owner(&self) -> io::Result<(u32, u32)> {
+            self.inner.owner()
+        }
+
+        fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+            self.inner.canonicalize(path)
+        }
+
+        fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+            Box::pin(async move {
+                let inner = self.inner.open(path, options, mode).await?;
+                Ok(Box::new(SlowFile { inner, delay: self.delay }) as Box<dyn StorageFile>)
+            })
+        }
+
+        fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+            self.inner.stat(path, follow)
+        }
+
+        fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, follow: bool) -> BoxFuture<'a, io::Result<()>> {
+            self.inner.set_attributes(path, attrs, follow)
+        }
+
+        fn list<'a>(&'a self, path: &'a str, sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+            self.inner.list(path, sort, limit)
+        }
+
+        fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+            self.inner.remove_file(path)
+        }
+
+        fn create_dir<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, io::Result<()>> {
+            self.inner.create_dir(path, mode)
+        }
+
+        fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+            self.inner.remove_dir(path)
+        }
+
+        fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>> {
+            self.inner.rename(from, to, replace)
+        }
+
+        fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+            self.inner.statvfs(path)
+        }
+    }
+
+    impl StorageFile for SlowFile {
+        fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                self.inner.read_at(offset, len).await
+            })
+        }
+
+        fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)> {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                self.inner.write_at(offset, data).await
+            })
+        }
+
+        fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>> {
+            self.inner.stat()
+        }
+
+        fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+            self.inner.set_attributes(attrs)
+        }
+
+        fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+            self.inner.sync()
+        }
+
+        fn close(&self) -> BoxFuture<'_, io::Result<()>> {
+            self.inner.close()
+        }
+    }
+
+    async fn send(client: &mut DuplexStream, kind: u8, id: u32, body: &[u8]) {
+        let mut packet = Vec::with_capacity(9 + body.len());
+        packet.extend_from_slice(&(5 + body.len() as u32).to_be_bytes());
+        packet.push(kind);
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(body);
+        client.write_all(&packet).await.unwrap();
+    }
+
+    // the reply's type and what follows its id
+    async fn receive(client: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let len = client.read_u32().await.unwrap();
+        let kind = client.read_u8().await.unwrap();
+        let mut body = vec![0; len as usize - 1];
+        client.read_exact(&mut body).await.unwrap();
+        (kind, body.split_off(4))
+    }
+
+    fn string(data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u32).to_be_bytes(), data].concat()
+    }
+
+    fn request(handle: &[u8], offset: u64, rest: &[u8]) -> Vec<u8> {
+        [string(handle).as_slice(), &offset.to_be_bytes(), rest].concat()
+    }
+
+    // writes the file and reads it back, with WINDOW requests in flight like OpenSSH, and returns how long each took
+    async fn transfer(storage: Arc<dyn StorageBackend>, max_concurrent: usize) -> (Duration, Duration) {
+        let permissions = PermissionRules::new(&[]).unwrap().for_user("bench", &[]);
+        let session = SftpSession::new(storage, SftpConfig::default(), String::from("bench"), permissions).unwrap();
+        let (mut client, server) = tokio::io::duplex(16 * 1024 * 1024);
+        let server = tokio::spawn(run(server, session, max_concurrent));
+
+        // init has a version where other requests have their id
+        send(&mut client, 1, 3, &[]).await;
+        assert_eq!(receive(&mut client).await.0, 2);
+        let mut id = 0;
+        let open = [string(b"bench").as_slice(), &0x1b_u32.to_be_bytes(), &0_u32.to_be_bytes()].concat();
+        send(&mut client, 3, id, &open).await;
+        let (kind, body) = receive(&mut client).await;
+        assert_eq!(kind, 102);
+        let handle = body[4..].to_vec();
+
+        let chunk = vec![0x5a; CHUNK as usize];
+        let count = FILE_SIZE / CHUNK as u64;
+        let mut times = Vec::new();
+        for write in [true, false] {
+            let start = Instant::now();
+            let (mut sent, mut received) = (0, 0);
+            while received < count {
+                while sent < count && sent - received < WINDOW {
+                    id += 1;
+                    if write {
+                        send(&mut client, 6, id, &request(&handle, sent * CHUNK as u64, &string(&chunk))).await;
+                    }
+                    else {
+                        send(&mut client, 5, id, &request(&handle, sent * CHUNK as u64, &CHUNK.to_be_bytes())).await;
+                    }
+                    sent += 1;
+                }
+                let (kind, body) = receive(&mut client).await;
+                assert!(if write { kind == 101 && body[..4] == [0; 4] } else { kind == 103 && body.len() == 4 + CHUNK as usize });
+                received += 1;
+            }
+            if write {
+                // writes are buffered, a stat waits for them to reach the file
+                id += 1;
+                send(&mut client, 8, id, &string(&handle)).await;
+                assert_eq!(receive(&mut client).await.0, 105);
+            }
+            times.push(start.elapsed());
+        }
+        drop(client);
+        server.await.unwrap();
+        (times[0], times[1])
+    }
+
+    fn report(name: &str, size: u64, (write, read): (Duration, Duration)) {
+        let mib = size as f64 / (1024.0 * 1024.0);
+        println!("{:<28} write {:>8.1} MiB/s   read {:>8.1} MiB/s", name, mib / write.as_secs_f64(), mib / read.as_secs_f64());
+    }
+
+    // cargo test --release bench -- --ignored --nocapture
+    //
+    // on a single core VM, 64 MiB in 32 KiB requests:
+    //   local, 1 at a time           write    334.2 MiB/s   read    112.6 MiB/s
+    //   local, 32 at a time          write    365.6 MiB/s   read    114.8 MiB/s
+    //   1ms latency, 1 at a time     write    175.3 MiB/s   read     14.1 MiB/s
+    //   1ms latency, 32 at a time    write    167.8 MiB/s   read    112.2 MiB/s
+    // with one core, local storage gains little. writes are buffered and flushed in large pieces, so latency barely
+    // matters for them either way, reads with latency are where running requests at the same time pays off
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn throughput() {
+        let dir = std::env::temp_dir().join(format!("flux-sftp-bench-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let jail = Jail::new(dir.to_str().unwrap()).unwrap();
+        let default = SftpConfig::default().max_concurrent_requests;
+        for max_concurrent in [1, default] {
+            report(&format!("local, {} at a time", max_concurrent), FILE_SIZE, transfer(Arc::new(jail.clone()), max_concurrent).await);
+        }
+        for max_concurrent in [1, default] {
+            let slow = Slow { inner: jail.clone(), delay: Duration::from_millis(1) };
+            report(&format!("1ms latency, {} at a time", max_concurrent), FILE_SIZE, transfer(Arc::new(slow), max_concurrent).await);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,432 @@
+use std::{collections::HashMap, fs, io::{self, ErrorKind, Write}, os::unix::fs::OpenOptionsExt, path::PathBuf, sync::{Arc, Mutex as StdMutex, Weak}};
+
+use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng, Payload}, XChaCha20Poly1305, XNonce};
+use tokio::sync::RwLock;
+
+use crate::{config::EncryptionConfig, storage::{BoxFuture, DirEntry, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile}};
+
+// plaintext bytes per block, every block is encrypted on its own so files can be read and written at any offset
+const BLOCK_SIZE: u64 = 64 * 1024;
+const NONCE_SIZE: u64 = 24;
+const TAG_SIZE: u64 = 16;
+const DISK_BLOCK_SIZE: u64 = BLOCK_SIZE + NONCE_SIZE + TAG_SIZE;
+// magic, reserved bytes and the file's id, which is part of every block's associated data
+const HEADER_SIZE: u64 = 32;
+const MAGIC: &[u8; 4] = b"FXE1";
+const KEY_SIZE: usize = 32;
+// largest plaintext whose encrypted file still fits in a file offset
+const MAX_SIZE: u64 = (i64::MAX as u64 - HEADER_SIZE) / DISK_BLOCK_SIZE * BLOCK_SIZE;
+// blocks written at once when a write or truncate fills a gap with zeros
+const MAX_BATCH: u64 = 16;
+
+fn corrupted() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "decryption failed, the file is corrupted or was encrypted with a different key")
+}
+
+fn block_offset(block: u64) -> u64 {
+    HEADER_SIZE + block * DISK_BLOCK_SIZE
+}
+
+// size of a file's plaintext, files without a header are empty
+fn plain_size(disk_size: u64) -> u64 {
+    let Some(data) = disk_size.checked_sub(HEADER_SIZE) else {
+        return 0
+    };
+    data / DISK_BLOCK_SIZE * BLOCK_SIZE + (data % DISK_BLOCK_SIZE).saturating_sub(NONCE_SIZE + TAG_SIZE)
+}
+
+fn disk_size(plain_size: u64) -> u64 {
+    let rest = plain_size % BLOCK_SIZE;
+    block_offset(plain_size / BLOCK_SIZE) + if rest == 0 { 0 } else { rest + NONCE_SIZE + TAG_SIZE }
+}
+
+fn plain_stat(mut st: FileStat) -> FileStat {
+    if st.is_file() {
+        st.size = plain_size(st.size);
+    }
+    st
+}
+
+/// The master key and where the per user data keys it wraps are kept
+pub(crate) struct Keys {
+    master: XChaCha20Poly1305,
+    key_dir: PathBuf,
+    locks: StdMutex<HashMap<String, Arc<FileLocks>>>
+}
+
+impl Keys {
+    pub(crate) fn load(config: &EncryptionConfig) -> Result<Self, String> {
+        let master = fs::read(&config.master_key_file).map_err(|e| format!("error reading master key file {}: {}", config.master_key_file, e))?;
+        if master.len() != KEY_SIZE {
+            return Err(format!("master key file {} must contain exactly {} bytes", config.master_key_file, KEY_SIZE))
+        }
+        fs::create_dir_all(&config.key_dir).map_err(|e| format!("error creating key directory {}: {}", config.key_dir, e))?;
+        Ok(Keys { master: XChaCha20Poly1305::new_from_slice(&master).unwrap(), key_dir: PathBuf::from(&config.key_dir), locks: StdMutex::new(HashMap::new()) })
+    }
+
+    /// The data key of the user with directory `dir`, generated the first time the user logs in
+    pub(crate) fn user_key(&self, dir: &str) -> io::Result<XChaCha20Poly1305> {
+        let path = self.key_dir.join(format!("{}.key", dir));
+        loop {
+            match fs::read(&path) {
+                Ok(wrapped) => return self.unwrap_key(dir, &wrapped),
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                Err(_) => {}
+            }
+            let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let wrapped = self.master.encrypt(&nonce, Payload { msg: &key, aad: dir.as_bytes() }).map_err(|_| io::Error::other("error wrapping data key"))?;
+            // another session of the same user may be creating it at the same time, its key wins
+            match fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(&[nonce.as_slice(), &wrapped].concat())?;
+                    file.sync_all()?;
+                    return Ok(XChaCha20Poly1305::new(&key))
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// The locks of the files of the user with directory `dir`, so every session of the user shares them
+    pub(crate) fn file_locks(&self, dir: &str) -> Arc<FileLocks> {
+        self.locks.lock().unwrap().entry(dir.to_string()).or_insert_with(|| Arc::new(FileLocks::new())).clone()
+    }
+
+    fn unwrap_key(&self, dir: &str, wrapped: &[u8]) -> io::Result<XChaCha20Poly1305> {
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "data key can't be unwrapped, the master key is wrong or the key file is corrupted");
+        if wrapped.len() != NONCE_SIZE as usize + KEY_SIZE + TAG_SIZE as usize {
+            return Err(invalid())
+        }
+        let (nonce, wrapped) = wrapped.split_at(NONCE_SIZE as usize);
+        let key = self.master.decrypt(XNonce::from_slice(nonce), Payload { msg: wrapped, aad: dir.as_bytes() }).map_err(|_| invalid())?;
+        Ok(XChaCha20Poly1305::new_from_slice(&key).unwrap())
+    }
+}
+
+/// A lock for every open file, by path. blocks are read, changed and written back by writes, so every handle of
+/// a file has to wait for the others
+pub(crate) struct FileLocks {
+    locks: StdMutex<HashMap<String, Weak<RwLock<()>>>>
+}
+
+impl FileLocks {
+    fn new() -> Self {
+        FileLocks { locks: StdMutex::new(HashMap::new()) }
+    }
+
+    // locks are dropped with the last handle of their file
+    fn get(&self, path: &str) -> Arc<RwLock<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        if let Some(lock) = locks.get(path).and_then(Weak::upgrade) {
+            return lock
+        }
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        let lock = Arc::new(RwLock::new(()));
+        locks.insert(path.to_string(), Arc::downgrade(&lock));
+        lock
+    }
+}
+
+/// Encrypts the contents of files stored in another backend. Names, directories and attributes are left as they are
+pub(crate) struct EncryptedBackend {
+    inner: Arc<dyn StorageBackend>,
+    cipher: Arc<XChaCha20Poly1305>,
+    locks: Arc<FileLocks>
+}
+
+impl EncryptedBackend {
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>, key: XChaCha20Poly1305, locks: Arc<FileLocks>) -> Self {
+        EncryptedBackend { inner, cipher: Arc::new(key), locks }
+    }
+}
+
+impl StorageBackend for EncryptedBackend {
+    fn owner(&self) -> io::Result<(u32, u32)> {
+        self.inner.owner()
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.canonicalize(path)
+    }
+
+    // blocks are rewritten as a whole, so the file is always opened for reading as well. appends are done here
+    // since the end of the plaintext isn't the end of the file
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            let writing = options.write || options.append;
+            let inner = self.inner.open(path, OpenOptions { read: true, write: writing, append: false, truncate: false, ..options }, mode).await?;
+            // links to the file share the lock of the path they lead to
+            let resolved = self.inner.canonicalize(path).await.unwrap_or_else(|_| path.to_string());
+            let lock = self.locks.get(&resolved);
+            // the header is written under the lock, so a file opened by two handles at once gets only one
+            let guard = lock.write().await;
+            let mut disk_size = inner.stat().await?.size;
+            // truncating keeps the header, so other handles of the file go on with the id they have
+            if options.truncate && writing && disk_size > 0 {
+                let header = inner.read_at(0, HEADER_SIZE as usize).await?;
+                disk_size = if header.len() == HEADER_SIZE as usize && &header[..4] == MAGIC { HEADER_SIZE } else { 0 };
+                inner.set_attributes(SetAttributes { size: Some(disk_size), ..Default::default() }).await?;
+            }
+            let file_id = if disk_size == 0 {
+                let file_id: [u8; 16] = rand::random();
+                if writing {
+                    let header = [MAGIC.as_slice(), &[0; 12], &file_id].concat();
+                    inner.write_at(0, header).await.1?;
+                }
+                file_id
+            }
+            else {
+                let header = inner.read_at(0, HEADER_SIZE as usize).await?;
+                if header.len() != HEADER_SIZE as usize || &header[..4] != MAGIC {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "file is not encrypted"))
+                }
+                header[16..].try_into().unwrap()
+            };
+            drop(guard);
+            let file = EncryptedFile { inner, cipher: self.cipher.clone(), file_id, append: options.append, lock };
+            Ok(Box::new(file) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+        Box::pin(async move { self.inner.stat(path, follow).await.map(plain_stat) })
+    }
+
+    // sizes are changed through an open file since the last block has to be encrypted again
+    fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, follow: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let Some(size) = attrs.size else {
+                return self.inner.set_attributes(path, attrs, follow).await
+            };
+            if !follow && !self.inner.stat(path, false).await?.is_file() {
+                return self.inner.set_attributes(path, attrs, follow).await
+            }
+            let file = self.open(path, OpenOptions { write: true, ..Default::default() }, 0).await?;
+            file.set_attributes(SetAttributes { size: Some(size), ..Default::default() }).await?;
+            file.close().await?;
+            self.inner.set_attributes(path, SetAttributes { size: None, ..attrs }, follow).await
+        })
+    }
+
+    fn list<'a>(&'a self, path: &'a str, sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+        Box::pin(async move {
+            let inner = self.inner.list(path, sort, limit).await?;
+            Ok(Box::new(EncryptedDir { inner }) as Box<dyn DirStream>)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.remove_file(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.create_dir(path, mode)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.remove_dir(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.rename(from, to, replace)
+    }
+
+    fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+        self.inner.statvfs(path)
+    }
+
+    fn read_link<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.read_link(path)
+    }
+
+    fn symlink<'a>(&'a self, target: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.symlink(target, path)
+    }
+
+    fn hard_link<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.hard_link(from, to)
+    }
+
+    // a copy of the ciphertext decrypts just like the original, the file's id comes along with it
+    fn copy_file<'a>(&'a self, from: &'a str, to: &'a str, overwrite: bool) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.copy_file(from, to, overwrite)
+    }
+}
+
+/// An encrypted file, made of a header followed by blocks of a nonce, the encrypted data and its tag
+struct EncryptedFile {
+    inner: Box<dyn StorageFile>,
+    cipher: Arc<XChaCha20Poly1305>,
+    file_id: [u8; 16],
+    append: bool,
+    // shared by every handle of the file, reads must not see blocks half written
+    lock: Arc<RwLock<()>>
+}
+
+impl EncryptedFile {
+    // the block's position is part of its associated data, so blocks can't be swapped around
+    fn aad(&self, block: u64) -> Vec<u8> {
+        [self.file_id.as_slice(), &block.to_le_bytes()].concat()
+    }
+
+    fn encrypt(&self, block: u64, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = self.aad(block);
+        let encrypted = self.cipher.encrypt(&nonce, Payload { msg: data, aad: &aad }).map_err(|_| io::Error::other("encryption failed"))?;
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&encrypted);
+        Ok(())
+    }
+
+    // plaintext of `count` blocks starting at `first`, fewer at the end of the file
+    async fn read_blocks(&self, first: u64, count: u64) -> io::Result<Vec<Vec<u8>>> {
+        let disk = self.inner.read_at(block_offset(first), (count * DISK_BLOCK_SIZE) as usize).await?;
+        let mut blocks = Vec::with_capacity(disk.chunks(DISK_BLOCK_SIZE as usize).len());
+        for (i, chunk) in disk.chunks(DISK_BLOCK_SIZE as usize).enumerate() {
+            if chunk.len() <= (NONCE_SIZE + TAG_SIZE) as usize {
+                return Err(corrupted())
+            }
+            let (nonce, encrypted) = chunk.split_at(NONCE_SIZE as usize);
+            let aad = self.aad(first + i as u64);
+            blocks.push(self.cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: encrypted, aad: &aad }).map_err(|_| corrupted())?);
+        }
+        Ok(blocks)
+    }
+
+    async fn size(&self) -> io::Result<u64> {
+        Ok(plain_size(self.inner.stat().await?.size))
+    }
+
+    // writes `data` at `offset` into a file of `size` bytes, a gap up to `offset` is filled with zeros.
+    // with no data the file is only extended to `offset`
+    async fn write_locked(&self, size: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= MAX_SIZE => end,
+            _ => return Err(io::Error::from_raw_os_error(libc::EFBIG))
+        };
+        let new_size = size.max(end);
+        let last = (end - 1) / BLOCK_SIZE;
+        let mut first = offset.min(size) / BLOCK_SIZE;
+        while first <= last {
+            let count = (last - first + 1).min(MAX_BATCH);
+            let old = if first * BLOCK_SIZE < size { self.read_blocks(first, count).await? } else { Vec::new() };
+            let mut out = Vec::with_capacity((count * DISK_BLOCK_SIZE) as usize);
+            for (i, block) in (first..first + count).enumerate() {
+                let start = block * BLOCK_SIZE;
+                let mut plain = old.get(i).cloned().unwrap_or_default();
+                plain.resize((new_size - start).min(BLOCK_SIZE) as usize, 0);
+                let (from, to) = (offset.max(start), end.min(start + plain.len() as u64));
+                if from < to {
+                    plain[(from - start) as usize..(to - start) as usize].copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+                }
+                self.encrypt(block, &plain, &mut out)?;
+            }
+            self.inner.write_at(block_offset(first), out).await.1?;
+            first += count;
+        }
+        Ok(())
+    }
+}
+
+impl StorageFile for EncryptedFile {
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            // nothing can be stored past MAX_SIZE, reads there are at the end of the file
+            let end = offset.saturating_add(len as u64).min(MAX_SIZE);
+            if offset >= end {
+                return Ok(Vec::new())
+            }
+            let _guard = self.lock.read().await;
+            let (first, last) = (offset / BLOCK_SIZE, (end - 1) / BLOCK_SIZE);
+            let plain = self.read_blocks(first, last - first + 1).await?.concat();
+            let start = ((offset - first * BLOCK_SIZE) as usize).min(plain.len());
+            Ok(plain[start..(start + len).min(plain.len())].to_vec())
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)> {
+        Box::pin(async move {
+            if data.is_empty() {
+                return (data, Ok(()))
+            }
+            let _guard = self.lock.write().await;
+            let result = match self.size().await {
+                Ok(size) => self.write_locked(size, if self.append { size } else { offset }, &data).await,
+                Err(e) => Err(e)
+            };
+            (data, result)
+        })
+    }
+
+    fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>> {
+        Box::pin(async move { self.inner.stat().await.map(plain_stat) })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if let Some(new_size) = attrs.size {
+                let _guard = self.lock.write().await;
+                let size = self.size().await?;
+                if new_size > size {
+                    self.write_locked(size, new_size, &[]).await?;
+                }
+                else if new_size < size {
+                    // the block the file now ends in is cut off and encrypted again
+                    let (block, rest) = (new_size / BLOCK_SIZE, (new_size % BLOCK_SIZE) as usize);
+                    let plain = if rest == 0 { Vec::new() } else { self.read_blocks(block, 1).await?.concat() };
+                    self.inner.set_attributes(SetAttributes { size: Some(block_offset(block)), ..Default::default() }).await?;
+                    if rest != 0 {
+                        let mut out = Vec::with_capacity(DISK_BLOCK_SIZE as usize);
+                        self.encrypt(block, &plain[..rest.min(plain.len())], &mut out)?;
+                        self.inner.write_at(block_offset(block), out).await.1?;
+                    }
+                }
+            }
+            self.inner.set_attributes(SetAttributes { size: None, ..attrs }).await
+        })
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.sync()
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        self.inner.statvfs()
+    }
+
+    fn close(&self) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.close()
+    }
+
+    fn will_need(&self, offset: u64, len: u64) {
+        let end = offset.saturating_add(len).min(MAX_SIZE);
+        if offset >= end {
+            return
+        }
+        let (start, end) = (block_offset(offset / BLOCK_SIZE), disk_size(end));
+        self.inner.will_need(start, end - start);
+    }
+}
+
+struct EncryptedDir {
+    inner: Box<dyn DirStream>
+}
+
+impl DirStream for EncryptedDir {
+    fn next_entries(&mut self, count: usize) -> BoxFuture<'_, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            let entries = self.inner.next_entries(count).await?;
+            Ok(entries.into_iter().map(|entry| DirEntry { name: entry.name, stat: plain_stat(entry.stat) }).collect())
+        })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.set_attributes(attrs)
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        self.inner.statvfs()
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use russh::{server::Msg, Channel, ChannelMsg};
+
+use crate::{checksum::{self, Algorithm}, config::Permission, permissions::Permissions, storage::{OpenOptions, StorageBackend}};
+
+/// A hash command like `sha256sum <path>` which is computed in process instead of running a shell
+pub(crate) struct HashCommand {
+    name: String,
+    algorithm: Algorithm,
+    // "-" or no paths at all hashes the channel's input
+    paths: Vec<String>
+}
+
+// splits a command line the way a POSIX shell would for plain words and quoting,
+// anything that would make a shell do more than run a single command is refused
+fn split_words(command: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c)
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            c => { word.push('\\'); word.push(c); }
+                        }
+                        '$' | '`' => return None,
+                        c => word.push(c)
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).push(chars.next()?),
+            ';' | '&' | '|' | '<' | '>' | '(' | ')' | '$' | '`' | '\n' | '*' | '?' | '[' => return None,
+            '~' | '#' if word.is_none() => return None,
+            c => word.get_or_insert_with(String::new).push(c)
+        }
+    }
+    if let Some(word) = word {
+        words.push(word);
+    }
+    Some(words)
+}
+
+impl HashCommand {
+    /// Parses an exec request, None if it is not an allowed hash command
+    pub(crate) fn parse(command: &str, allowed: &[String]) -> Option<Self> {
+        let mut words = split_words(command)?.into_iter();
+        let name = words.next()?;
+        if !allowed.contains(&name) {
+            return None
+        }
+        let algorithm = Algorithm::from_name(name.strip_suffix("sum")?)?;
+
+        let mut paths = Vec::new();
+        let mut options_done = false;
+        for word in words {
+            if !options_done && word == "--" {
+                options_done = true;
+            }
+            else if !options_done && word.starts_with('-') && word != "-" {
+                return None
+            }
+            else {
+                paths.push(word);
+            }
+        }
+        Some(HashCommand { name, algorithm, paths })
+    }
+
+    /// Writes coreutils style output to the channel and closes it
+    pub(crate) async fn run(self, mut channel: Channel<Msg>, storage: Arc<dyn StorageBackend>, permissions: Permissions) {
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        let paths = if self.paths.is_empty() { vec![String::from("-")] } else { self.paths };
+
+        for path in paths {
+            let hash = if path == "-" {
+                let mut hasher = self.algorithm.hasher();
+                while let Some(msg) = channel.wait().await {
+                    match msg {
+                        ChannelMsg::Data { data } => hasher.update(&data),
+                        ChannelMsg::Eof => break,
+                        _ => {}
+                    }
+                }
+                Ok(hasher.finalize().to_vec())
+            }
+            else {
+                match permissions.check(&*storage, Permission::Download, &path).await {
+                    Ok(()) => match storage.open(&path, OpenOptions { read: true, ..Default::default() }, 0).await {
+                        Ok(file) => checksum::hash_file(&*file, self.algorithm, 0, 0, 0).await,
+                        Err(e) => Err(e)
+                    },
+                    Err(e) => Err(e)
+                }
+            };
+            match hash {
+                Ok(hash) => stdout.push_str(&format!("{}  {}\n", checksum::to_hex(&hash), path)),
+                Err(e) => stderr.push_str(&format!("{}: {}: {}\n", self.name, path, e))
+            }
+        }
+
+        let _ = channel.data(stdout.as_bytes()).await;
+        if !stderr.is_empty() {
+            let _ = channel.extended_data(1, stderr.as_bytes()).await;
+        }
+        let _ = channel.eof().await;
+        let _ = channel.exit_status(if stderr.is_empty() { 0 } else { 1 }).await;
+        let _ = channel.close().await;
+    }
+}
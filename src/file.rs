@@ -0,0 +1,138 @@
+use std::{io, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex as StdMutex}};
+
+use tokio::sync::Mutex;
+
+use crate::storage::StorageFile;
+
+// idle buffers a pool keeps around, more than that are freed
+const MAX_IDLE_BUFFERS: usize = 4;
+
+/// Write buffers shared by the files of a session so uploads don't allocate a new one for every file
+pub(crate) struct BufferPool {
+    size: usize,
+    buffers: StdMutex<Vec<Vec<u8>>>
+}
+
+impl BufferPool {
+    pub(crate) fn new(size: usize) -> Self {
+        BufferPool { size, buffers: StdMutex::new(Vec::new()) }
+    }
+
+    fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_else(|| Vec::with_capacity(self.size))
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_IDLE_BUFFERS {
+            buffers.push(buf);
+        }
+    }
+}
+
+// contiguous data accepted from the client but not written to the file yet
+struct WriteBehind {
+    buf: Vec<u8>,
+    offset: u64
+}
+
+/// A file opened by a client. Contiguous writes are collected and written in larger blocks,
+/// reads that continue where the previous one ended make the backend read ahead
+pub(crate) struct OpenFile {
+    file: Box<dyn StorageFile>,
+    pool: Arc<BufferPool>,
+    read_ahead: u64,
+    pending: Mutex<WriteBehind>,
+    next_read: AtomicU64,
+    // end of the range the backend was last asked to read ahead
+    read_ahead_end: AtomicU64
+}
+
+impl OpenFile {
+    /// `read_ahead` is in bytes and a pool with a buffer size of 0 disables write coalescing
+    pub(crate) fn new(file: Box<dyn StorageFile>, pool: Arc<BufferPool>, read_ahead: usize) -> Self {
+        OpenFile {
+            file,
+            pool,
+            read_ahead: read_ahead as u64,
+            pending: Mutex::new(WriteBehind { buf: Vec::new(), offset: 0 }),
+            next_read: AtomicU64::new(0),
+            read_ahead_end: AtomicU64::new(0)
+        }
+    }
+
+    /// The backend's file, anything done through it directly doesn't see data that wasn't flushed yet
+    pub(crate) fn storage(&self) -> &dyn StorageFile {
+        &*self.file
+    }
+
+    // writes out pending data, on failure it is kept so the error is reported again on close
+    async fn flush_pending(&self, pending: &mut WriteBehind) -> io::Result<()> {
+        if pending.buf.is_empty() {
+            return Ok(())
+        }
+        let buf = std::mem::take(&mut pending.buf);
+        let (buf, result) = self.file.write_at(pending.offset, buf).await;
+        match result {
+            Ok(()) => {
+                self.pool.put(buf);
+                Ok(())
+            }
+            Err(e) => {
+                pending.buf = buf;
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes any data still held back, must succeed before the file's contents are used in any other way
+    pub(crate) async fn flush(&self) -> io::Result<()> {
+        let mut pending = self.pending.lock().await;
+        self.flush_pending(&mut pending).await
+    }
+
+    /// Reads up to `len` bytes at `offset`, less only at the end of the file
+    pub(crate) async fn read(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.flush().await?;
+        let end = offset.saturating_add(len as u64);
+        let sequential = self.next_read.swap(end, Ordering::Relaxed) == offset;
+        // offsets come from the client, near the end of the range the read ahead just stops there
+        if sequential && self.read_ahead > 0 && end.saturating_add(self.read_ahead / 2) > self.read_ahead_end.load(Ordering::Relaxed) {
+            let start = end.max(self.read_ahead_end.load(Ordering::Relaxed));
+            let ahead_end = end.saturating_add(self.read_ahead);
+            self.read_ahead_end.store(ahead_end, Ordering::Relaxed);
+            self.file.will_need(start, ahead_end.saturating_sub(start));
+        }
+        self.file.read_at(offset, len).await
+    }
+
+    /// Writes `data` at `offset`. Writes continuing the previous one are only collected in memory,
+    /// an error writing them out is returned by whichever call flushes them
+    pub(crate) async fn write(&self, offset: u64, data: Vec<u8>) -> io::Result<()> {
+        let mut pending = self.pending.lock().await;
+        let contiguous = !pending.buf.is_empty() && pending.offset.checked_add(pending.buf.len() as u64) == Some(offset);
+        if !contiguous || pending.buf.len() + data.len() > self.pool.size {
+            self.flush_pending(&mut pending).await?;
+        }
+
+        if data.len() >= self.pool.size {
+            return self.file.write_at(offset, data).await.1
+        }
+        if pending.buf.is_empty() {
+            pending.buf = self.pool.take();
+            pending.offset = offset;
+        }
+        pending.buf.extend_from_slice(&data);
+        if pending.buf.len() == self.pool.size {
+            self.flush_pending(&mut pending).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes any data still held back and closes the file in the backend
+    pub(crate) async fn close(&self) -> io::Result<()> {
+        let result = self.flush().await;
+        result.and(self.file.close().await)
+    }
+}
@@ -0,0 +1,314 @@
+use std::{collections::{HashMap, HashSet}, io::{self, ErrorKind}, sync::Arc};
+
+use crate::{config::FolderConfig, jail::{self, Jail}, quota::{QuotaBackend, Usages}, readonly::ReadOnlyBackend, storage::{self, BoxFuture, DirEntry, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile}};
+
+fn mount_point() -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, "a virtual folder can't be changed")
+}
+
+fn cross_folder() -> io::Error {
+    io::Error::from_raw_os_error(libc::EXDEV)
+}
+
+/// The folders from the config file
+pub(crate) struct Folders {
+    folders: HashMap<String, FolderConfig>,
+    usages: Arc<Usages>
+}
+
+impl Folders {
+    pub(crate) fn new(folders: &HashMap<String, FolderConfig>, usages: Arc<Usages>) -> Result<Self, String> {
+        for (name, folder) in folders {
+            if jail::normalize(&folder.path).is_empty() {
+                return Err(format!("folder {} needs a path other than /", name))
+            }
+            if folder.dir.is_empty() {
+                return Err(format!("folder {} needs a dir", name))
+            }
+        }
+        Ok(Folders { folders: folders.clone(), usages })
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.folders.contains_key(name)
+    }
+
+    /// Names of the folders the config file gives a user
+    pub(crate) fn names_for(&self, user: &str) -> Vec<String> {
+        self.folders.iter().filter(|(_, folder)| folder.users.iter().any(|u| u == user)).map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Opens the named folders for a user, `dir` is the name of the user's directory
+    pub(crate) fn mount(&self, names: &[String], user: &str, dir: &str) -> Result<Vec<Mount>, String> {
+        let mut mounts: Vec<Mount> = Vec::new();
+        for name in names {
+            let Some(folder) = self.folders.get(name) else {
+                return Err(format!("unknown folder {}", name))
+            };
+            let path = jail::normalize(&folder.path);
+            if mounts.iter().any(|mount| mount.path == path) {
+                return Err(format!("more than one folder at /{}", path))
+            }
+            let folder_dir = folder.dir.replace("{username}", user).replace("{dir}", dir);
+            let jail = Jail::new(&folder_dir).map_err(|e| format!("error opening directory {} of folder {}: {}", folder_dir, name, e))?;
+            let mut backend: Arc<dyn StorageBackend> = Arc::new(jail);
+            if folder.max_size != 0 || folder.max_files != 0 {
+                backend = Arc::new(QuotaBackend::new(backend, self.usages.get(&folder_dir), folder.max_size, folder.max_files));
+            }
+            if folder.read_only {
+                backend = Arc::new(ReadOnlyBackend::new(backend));
+            }
+            mounts.push(Mount { path, backend });
+        }
+        Ok(mounts)
+    }
+}
+
+/// A folder as it appears in a user's tree, `path` is relative to the user's root
+pub(crate) struct Mount {
+    path: String,
+    backend: Arc<dyn StorageBackend>
+}
+
+/// A user's own storage with folders mounted into it. Paths are sent to the folder they lead into, so moving
+/// anything between folders has to be done by copying it
+pub(crate) struct FolderBackend {
+    // longest paths first, so nested folders are found before the folders they are in. the user's own storage
+    // is last, with an empty path
+    mounts: Vec<Mount>
+}
+
+impl FolderBackend {
+    pub(crate) fn new(home: Arc<dyn StorageBackend>, mut mounts: Vec<Mount>) -> Self {
+        mounts.sort_by_key(|mount| std::cmp::Reverse(mount.path.len()));
+        mounts.push(Mount { path: String::new(), backend: home });
+        FolderBackend { mounts }
+    }
+
+    fn home(&self) -> &Mount {
+        self.mounts.last().unwrap()
+    }
+
+    // the folder a path leads into and the path inside of it, the folder itself is the empty path
+    fn route(&self, path: &str) -> (&Mount, String) {
+        let path = jail::normalize(path);
+        for mount in &self.mounts {
+            if mount.path.is_empty() {
+                break
+            }
+            if path == mount.path {
+                return (mount, String::new())
+            }
+            if let Some(rest) = path.strip_prefix(&mount.path).and_then(|rest| rest.strip_prefix('/')) {
+                return (mount, rest.to_string())
+            }
+        }
+        (self.home(), path)
+    }
+
+    // like route, for operations that add, remove or change the entry itself, which folders can't be
+    fn route_entry(&self, path: &str) -> io::Result<(&Mount, String)> {
+        let (mount, rest) = self.route(path);
+        if rest.is_empty() && !mount.path.is_empty() {
+            return Err(mount_point())
+        }
+        Ok((mount, rest))
+    }
+}
+
+impl StorageBackend for FolderBackend {
+    fn owner(&self) -> io::Result<(u32, u32)> {
+        self.home().backend.owner()
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route(path);
+            let resolved = mount.backend.canonicalize(&rest).await?;
+            if mount.path.is_empty() {
+                return Ok(resolved)
+            }
+            Ok(format!("/{}{}", mount.path, resolved.trim_end_matches('/')))
+        })
+    }
+
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route(path);
+            mount.backend.open(&rest, options, mode).await
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route(path);
+            mount.backend.stat(&rest, follow).await
+        })
+    }
+
+    fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, follow: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route_entry(path)?;
+            mount.backend.set_attributes(&rest, attrs, follow).await
+        })
+    }
+
+    // folders mounted in the directory are listed in place of entries with the same name, in order when the
+    // listing is sorted and after the directory's own entries otherwise
+    fn list<'a>(&'a self, path: &'a str, sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route(path);
+            let stream = mount.backend.list(&rest, sort, limit).await?;
+            let dir = jail::normalize(path);
+            let mut folders = Vec::new();
+            for mount in self.mounts.iter().filter(|mount| !mount.path.is_empty()) {
+                let (parent, name) = jail::split(&mount.path);
+                if parent != dir {
+                    continue
+                }
+                match mount.backend.stat("", true).await {
+                    Ok(stat) => folders.push(DirEntry { name: name.to_string(), stat }),
+                    Err(e) => println!("error reading folder /{}: {}", mount.path, e)
+                }
+            }
+            if folders.is_empty() {
+                return Ok(stream)
+            }
+            if sort {
+                folders.sort_by(|a, b| a.name.cmp(&b.name));
+            }
+            let names = folders.iter().map(|entry| entry.name.clone()).collect();
+            Ok(Box::new(FolderDir { stream, names, folders, sort }) as Box<dyn DirStream>)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route_entry(path)?;
+            mount.backend.remove_file(&rest).await
+        })
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route_entry(path)?;
+            mount.backend.create_dir(&rest, mode).await
+        })
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route_entry(path)?;
+            mount.backend.remove_dir(&rest).await
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let ((from_mount, from), (to_mount, to)) = (self.route_entry(from)?, self.route_entry(to)?);
+            if !std::ptr::eq(from_mount, to_mount) {
+                return Err(cross_folder())
+            }
+            from_mount.backend.rename(&from, &to, replace).await
+        })
+    }
+
+    fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route(path);
+            mount.backend.statvfs(&rest).await
+        })
+    }
+
+    fn read_link<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route(path);
+            mount.backend.read_link(&rest).await
+        })
+    }
+
+    // the target is resolved inside the folder the link is in
+    fn symlink<'a>(&'a self, target: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (mount, rest) = self.route_entry(path)?;
+            mount.backend.symlink(target, &rest).await
+        })
+    }
+
+    fn hard_link<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let ((from_mount, from), (to_mount, to)) = (self.route_entry(from)?, self.route_entry(to)?);
+            if !std::ptr::eq(from_mount, to_mount) {
+                return Err(cross_folder())
+            }
+            from_mount.backend.hard_link(&from, &to).await
+        })
+    }
+
+    // copies between folders read the file and write the copy
+    fn copy_file<'a>(&'a self, from: &'a str, to: &'a str, overwrite: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let ((from_mount, from), (to_mount, to)) = (self.route(from), self.route_entry(to)?);
+            if std::ptr::eq(from_mount, to_mount) {
+                return from_mount.backend.copy_file(&from, &to, overwrite).await
+            }
+            let source = from_mount.backend.open(&from, OpenOptions { read: true, ..Default::default() }, 0).await?;
+            let st = source.stat().await?;
+            if !st.is_file() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "not a regular file"))
+            }
+            let options = OpenOptions { write: true, create: true, truncate: true, exclusive: !overwrite, ..Default::default() };
+            let dest = to_mount.backend.open(&to, options, st.mode & 0o777).await?;
+            let result = storage::copy_data(&*source, 0, 0, &*dest, 0).await;
+            result.and(dest.close().await)
+        })
+    }
+}
+
+// a directory listing with the folders mounted in it added
+struct FolderDir {
+    stream: Box<dyn DirStream>,
+    // entries of the directory itself hidden by a folder
+    names: HashSet<String>,
+    // the folders not listed yet
+    folders: Vec<DirEntry>,
+    sort: bool
+}
+
+impl DirStream for FolderDir {
+    fn next_entries(&mut self, count: usize) -> BoxFuture<'_, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            loop {
+                let entries = self.stream.next_entries(count).await?;
+                if entries.is_empty() {
+                    return Ok(self.folders.drain(..count.min(self.folders.len())).collect())
+                }
+                let entries: Vec<DirEntry> = entries.into_iter().filter(|entry| !self.names.contains(&entry.name)).collect();
+                if entries.is_empty() {
+                    continue
+                }
+                if !self.sort {
+                    return Ok(entries)
+                }
+                // entries come in order, so every folder sorting before an entry goes right in front of it
+                let mut merged = Vec::with_capacity(entries.len());
+                for entry in entries {
+                    if entry.name != "." && entry.name != ".." {
+                        let before = self.folders.iter().take_while(|folder| folder.name < entry.name).count();
+                        merged.extend(self.folders.drain(..before));
+                    }
+                    merged.push(entry);
+                }
+                return Ok(merged)
+            }
+        })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        self.stream.set_attributes(attrs)
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        self.stream.statvfs()
+    }
+}
@@ -0,0 +1,49 @@
+use regex::Regex;
+
+/// A shell style pattern matched against paths relative to a user's root. `*` matches within a single name,
+/// `**` across directories and `?` any one character. patterns without a `/` are matched against the final
+/// name only, so `*.csv` matches csv files in every directory
+#[derive(Clone)]
+pub(crate) struct Glob {
+    regex: Regex,
+    name_only: bool
+}
+
+impl Glob {
+    pub(crate) fn new(pattern: &str) -> Result<Self, String> {
+        let trimmed = pattern.trim_start_matches('/');
+        let mut regex = String::from("^");
+        let mut chars = trimmed.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    // `**/` also matches no directory at all
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        regex.push_str("(?:.*/)?");
+                    }
+                    else {
+                        regex.push_str(".*");
+                    }
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string()))
+            }
+        }
+        regex.push('$');
+        match Regex::new(&regex) {
+            Ok(regex) => Ok(Glob { regex, name_only: !pattern.contains('/') }),
+            Err(e) => Err(format!("invalid pattern {}: {}", pattern, e))
+        }
+    }
+
+    /// Whether a normalized path, without a leading `/`, matches
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        if self.name_only {
+            return self.regex.is_match(path.rsplit('/').next().unwrap_or(path))
+        }
+        self.regex.is_match(path)
+    }
+}
@@ -0,0 +1,919 @@
+use std::{collections::VecDeque, ffi::{CStr, CString, OsString}, fs::File, io::{self, ErrorKind}, mem, os::{fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd}, unix::{ffi::{OsStrExt, OsStringExt}, fs::FileExt}}, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+
+use libc::{c_int, mode_t};
+
+use crate::storage::{BoxFuture, DirEntry, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile};
+
+// set once the kernel tells us openat2 is not available, after that we always walk paths manually
+static OPENAT2_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// same limit the kernel uses before giving up with ELOOP
+const MAX_SYMLINKS: usize = 40;
+
+// largest amount handed to a single copy_file_range call
+const COPY_CHUNK: u64 = 16 * 1024 * 1024;
+// buffer used when the kernel can't copy between two files itself
+const COPY_BUF_SIZE: usize = 64 * 1024;
+
+/// Lexically normalizes a client supplied path into a path relative to the jail root,
+/// `.` segments are dropped and `..` never goes above `/`. An empty string is the jail root itself
+pub(crate) fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => { parts.pop(); }
+            part => parts.push(part)
+        }
+    }
+    parts.join("/")
+}
+
+/// Splits a normalized path into its parent directory and final component
+pub(crate) fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// The normalized path a symlink inside `dir` pointing at `target` refers to, None if it leads outside the jail
+pub(crate) fn link_target(dir: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') {
+        return None
+    }
+    let mut parts: Vec<&str> = dir.split('/').filter(|p| !p.is_empty()).collect();
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => { parts.pop()?; }
+            part => parts.push(part)
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Relative path leading from the directory `from` to `to`, both normalized
+pub(crate) fn relative_path(from: &str, to: &str) -> String {
+    let from: Vec<&str> = from.split('/').filter(|p| !p.is_empty()).collect();
+    let to: Vec<&str> = to.split('/').filter(|p| !p.is_empty()).collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let parts: Vec<&str> = std::iter::repeat_n("..", from.len() - common).chain(to[common..].iter().copied()).collect();
+    if parts.is_empty() { String::from(".") } else { parts.join("/") }
+}
+
+fn escape_error() -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, "path escapes jail")
+}
+
+fn cstring(s: impl Into<Vec<u8>>) -> io::Result<CString> {
+    CString::new(s).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "path contains NUL byte"))
+}
+
+fn check(ret: c_int) -> io::Result<c_int> {
+    if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(ret) }
+}
+
+fn openat(dirfd: RawFd, name: &CStr, flags: c_int, mode: mode_t) -> io::Result<OwnedFd> {
+    let fd = check(unsafe { libc::openat(dirfd, name.as_ptr(), flags | libc::O_CLOEXEC, mode as libc::c_uint) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn openat2(dirfd: RawFd, path: &CStr, flags: c_int, mode: mode_t) -> io::Result<OwnedFd> {
+    let mut how: libc::open_how = unsafe { mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    if flags & (libc::O_CREAT | libc::O_TMPFILE) != 0 {
+        how.mode = mode as u64;
+    }
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+    let fd = unsafe { libc::syscall(libc::SYS_openat2, dirfd, path.as_ptr(), &how as *const libc::open_how, mem::size_of::<libc::open_how>()) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+fn fstat(fd: &OwnedFd) -> io::Result<libc::stat> {
+    let mut st: libc::stat = unsafe { mem::zeroed() };
+    check(unsafe { libc::fstat(fd.as_raw_fd(), &mut st) })?;
+    Ok(st)
+}
+
+fn readlinkat(dirfd: RawFd, name: &CStr) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let len = unsafe { libc::readlinkat(dirfd, name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if len < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    buf.truncate(len as usize);
+    Ok(buf)
+}
+
+fn stat_of(fd: OwnedFd) -> io::Result<FileStat> {
+    File::from(fd).metadata().map(|metadata| FileStat::from(&metadata))
+}
+
+// path through which an already resolved descriptor can be used with calls that only take paths,
+// following it always lands on the descriptor's inode so it cannot be redirected outside the jail
+fn proc_path(fd: RawFd) -> CString {
+    CString::new(format!("/proc/self/fd/{}", fd)).unwrap()
+}
+
+// gives something the server just created exactly the mode it was asked for, which the process umask may have
+// taken bits from. works on O_PATH descriptors as well
+fn set_mode(fd: &OwnedFd, mode: mode_t) -> io::Result<()> {
+    check(unsafe { libc::chmod(proc_path(fd.as_raw_fd()).as_ptr(), mode & 0o7777) }).map(|_| ())
+}
+
+fn timespec(time: Option<i64>) -> libc::timespec {
+    match time {
+        Some(secs) => libc::timespec { tv_sec: secs as libc::time_t, tv_nsec: 0 },
+        None => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT }
+    }
+}
+
+// works on O_PATH descriptors as well as regular ones. the owner is changed before the mode because
+// chown clears setuid bits, and times go last because truncating updates mtime
+fn apply_attributes(fd: RawFd, attrs: SetAttributes) -> io::Result<()> {
+    let path = proc_path(fd);
+    if let Some(size) = attrs.size {
+        check(unsafe { libc::truncate(path.as_ptr(), size as libc::off_t) })?;
+    }
+    if attrs.uid.is_some() || attrs.gid.is_some() {
+        let uid = attrs.uid.unwrap_or(u32::MAX);
+        let gid = attrs.gid.unwrap_or(u32::MAX);
+        check(unsafe { libc::fchownat(fd, c"".as_ptr(), uid, gid, libc::AT_EMPTY_PATH) })?;
+    }
+    if let Some(mode) = attrs.mode {
+        check(unsafe { libc::chmod(path.as_ptr(), mode as mode_t) })?;
+    }
+    if attrs.atime.is_some() || attrs.mtime.is_some() {
+        let times = [timespec(attrs.atime), timespec(attrs.mtime)];
+        check(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) })?;
+    }
+    Ok(())
+}
+
+// copies with copy_file_range so the filesystem can share extents or at least copy inside the kernel,
+// falls back to reading and writing where that is not supported. a length of 0 copies up to the end of `from`
+fn copy_range(from: &File, mut from_offset: u64, length: u64, to: &File, mut to_offset: u64) -> io::Result<()> {
+    let mut remaining = if length == 0 { u64::MAX } else { length };
+    let mut kernel_copy = true;
+    let mut buf = Vec::new();
+
+    while remaining > 0 {
+        let chunk = remaining.min(COPY_CHUNK);
+        let copied = if kernel_copy {
+            let (mut off_in, mut off_out) = (from_offset as i64, to_offset as i64);
+            let ret = unsafe { libc::copy_file_range(from.as_raw_fd(), &mut off_in, to.as_raw_fd(), &mut off_out, chunk as usize, 0) };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL) => {
+                        kernel_copy = false;
+                        continue
+                    }
+                    _ => return Err(e)
+                }
+            }
+            ret as u64
+        }
+        else {
+            buf.resize(COPY_BUF_SIZE, 0);
+            let want = chunk.min(COPY_BUF_SIZE as u64) as usize;
+            let read = from.read_at(&mut buf[..want], from_offset)?;
+            to.write_all_at(&buf[..read], to_offset)?;
+            read as u64
+        };
+        if copied == 0 {
+            break
+        }
+        from_offset += copied;
+        to_offset += copied;
+        remaining -= copied;
+    }
+    Ok(())
+}
+
+/// Copies `length` bytes (0 means up to the end of the file) between two open files
+pub(crate) async fn copy_data(from: BorrowedFd<'_>, from_offset: u64, length: u64, to: BorrowedFd<'_>, to_offset: u64) -> io::Result<()> {
+    let from = File::from(from.try_clone_to_owned()?);
+    let to = File::from(to.try_clone_to_owned()?);
+    blocking(move || copy_range(&from, from_offset, length, &to, to_offset)).await
+}
+
+pub(crate) async fn blocking<T: Send + 'static>(f: impl FnOnce() -> io::Result<T> + Send + 'static) -> io::Result<T> {
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+/// A user's jail directory. Every path is resolved relative to a file descriptor of the jail root
+/// so `..` segments and symlinks can never reach anything outside of it
+#[derive(Clone)]
+pub(crate) struct Jail {
+    root: Arc<OwnedFd>
+}
+
+impl Jail {
+    pub(crate) fn new(dir: &str) -> io::Result<Self> {
+        let dir = cstring(dir)?;
+        let root = openat(libc::AT_FDCWD, &dir, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+        Ok(Jail { root: Arc::new(root) })
+    }
+
+    // resolves a normalized path beneath the root and opens it with the given flags,
+    // the final component is only followed if it is a symlink when `follow` is set
+    fn resolve(&self, path: &str, flags: c_int, mode: mode_t, follow: bool) -> io::Result<OwnedFd> {
+        let flags = if follow { flags } else { flags | libc::O_NOFOLLOW };
+        if !OPENAT2_UNSUPPORTED.load(Ordering::Relaxed) {
+            let c_path = cstring(if path.is_empty() { "." } else { path })?;
+            match openat2(self.root.as_raw_fd(), &c_path, flags, mode) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => OPENAT2_UNSUPPORTED.store(true, Ordering::Relaxed),
+                Err(e) if e.raw_os_error() == Some(libc::EXDEV) => return Err(escape_error()),
+                res => return res
+            }
+        }
+        self.walk(path, flags, mode, follow).map(|(fd, _)| fd)
+    }
+
+    // fallback for kernels without openat2, walks the path one component at a time refusing to
+    // step above the root and expanding symlinks ourselves with the same rules as RESOLVE_BENEATH.
+    // also returns the names of the components the path resolved to
+    fn walk(&self, path: &str, flags: c_int, mode: mode_t, follow: bool) -> io::Result<(OwnedFd, Vec<Vec<u8>>)> {
+        let mut dirs: Vec<(OwnedFd, Vec<u8>)> = Vec::new();
+        let mut pending: VecDeque<Vec<u8>> = path.split('/').filter(|p| !p.is_empty()).map(|p| p.as_bytes().to_vec()).collect();
+        let mut links = 0;
+
+        loop {
+            let cur = dirs.last().map_or(self.root.as_raw_fd(), |(d, _)| d.as_raw_fd());
+            let Some(name) = pending.pop_front() else {
+                let fd = openat(cur, c".", flags & !libc::O_NOFOLLOW, mode)?;
+                return Ok((fd, dirs.into_iter().map(|(_, name)| name).collect()))
+            };
+            match name.as_slice() {
+                b"." => continue,
+                b".." => {
+                    if dirs.pop().is_none() {
+                        return Err(escape_error())
+                    }
+                    continue
+                }
+                _ => {}
+            }
+
+            let last = pending.is_empty();
+            let c_name = cstring(name.clone())?;
+            let probe = match openat(cur, &c_name, libc::O_PATH | libc::O_NOFOLLOW, 0) {
+                Ok(fd) => fd,
+                Err(e) if e.kind() == ErrorKind::NotFound && last => {
+                    let fd = openat(cur, &c_name, flags | libc::O_NOFOLLOW, mode)?;
+                    return Ok((fd, dirs.into_iter().map(|(_, name)| name).chain([name]).collect()))
+                }
+                Err(e) => return Err(e)
+            };
+            let st = fstat(&probe)?;
+
+            if st.st_mode & libc::S_IFMT == libc::S_IFLNK && (!last || follow) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP))
+                }
+                let target = readlinkat(cur, &c_name)?;
+                if target.starts_with(b"/") {
+                    return Err(escape_error())
+                }
+                for part in target.split(|b| *b == b'/').rev().filter(|p| !p.is_empty()) {
+                    pending.push_front(part.to_vec());
+                }
+            }
+            else if last {
+                // O_NOFOLLOW so a component swapped for a symlink after the probe is refused
+                let fd = openat(cur, &c_name, flags | libc::O_NOFOLLOW, mode)?;
+                return Ok((fd, dirs.into_iter().map(|(_, name)| name).chain([name]).collect()))
+            }
+            else if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                dirs.push((probe, name));
+            }
+            else {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR))
+            }
+        }
+    }
+
+    // like resolve, following the final component, but a file created by O_CREAT gets exactly `mode`. it is
+    // created with O_EXCL first, so a file that is already there keeps its mode
+    fn create(&self, path: &str, flags: c_int, mode: mode_t) -> io::Result<OwnedFd> {
+        if flags & libc::O_CREAT == 0 {
+            return self.resolve(path, flags, mode, true)
+        }
+        match self.resolve(path, flags | libc::O_EXCL, mode, true) {
+            Ok(fd) => {
+                set_mode(&fd, mode)?;
+                Ok(fd)
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && flags & libc::O_EXCL == 0 => self.resolve(path, flags, mode, true),
+            Err(e) => Err(e)
+        }
+    }
+
+    // resolves the directory containing the final component, the final component itself is not followed
+    fn parent(&self, path: &str) -> io::Result<(OwnedFd, CString)> {
+        let (dir, name) = split(path);
+        if name.is_empty() {
+            return Err(escape_error())
+        }
+        let dirfd = self.resolve(dir, libc::O_PATH | libc::O_DIRECTORY, 0, true)?;
+        Ok((dirfd, cstring(name)?))
+    }
+}
+
+fn flags(options: OpenOptions) -> c_int {
+    let mut flags = match (options.read, options.write || options.append) {
+        (true, true) => libc::O_RDWR,
+        (false, true) => libc::O_WRONLY,
+        _ => libc::O_RDONLY
+    };
+    if options.append {
+        flags |= libc::O_APPEND;
+    }
+    if options.create {
+        flags |= libc::O_CREAT;
+    }
+    if options.truncate {
+        flags |= libc::O_TRUNC;
+    }
+    if options.exclusive {
+        flags |= libc::O_CREAT | libc::O_EXCL;
+    }
+    flags
+}
+
+impl StorageBackend for Jail {
+    fn owner(&self) -> io::Result<(u32, u32)> {
+        let st = fstat(&self.root)?;
+        Ok((st.st_uid, st.st_gid))
+    }
+
+    // like OpenSSH the final component is allowed to not exist
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(blocking(move || {
+            let names = match jail.walk(&path, libc::O_PATH, 0, true) {
+                Ok((_, names)) => names,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    let (dir, name) = split(&path);
+                    let (_, mut names) = jail.walk(dir, libc::O_PATH | libc::O_DIRECTORY, 0, true)?;
+                    names.push(name.as_bytes().to_vec());
+                    names
+                }
+                Err(e) => return Err(e)
+            };
+            let names: Vec<String> = names.iter().map(|name| String::from_utf8_lossy(name).into_owned()).collect();
+            Ok(format!("/{}", names.join("/")))
+        }))
+    }
+
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(async move {
+            let fd = blocking(move || jail.create(&path, flags(options), mode)).await?;
+            Ok(Box::new(LocalFile { file: Arc::new(File::from(fd)) }) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(blocking(move || stat_of(jail.resolve(&path, libc::O_PATH, 0, follow)?)))
+    }
+
+    fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, follow: bool) -> BoxFuture<'a, io::Result<()>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(blocking(move || {
+            let fd = jail.resolve(&path, libc::O_PATH, 0, follow)?;
+            if follow || fstat(&fd)?.st_mode & libc::S_IFMT != libc::S_IFLNK {
+                return apply_attributes(fd.as_raw_fd(), attrs)
+            }
+            // linux has no size or mode for symlinks, owner and times are set through the parent
+            // with AT_SYMLINK_NOFOLLOW so the link is never followed
+            if attrs.size.is_some() || attrs.mode.is_some() {
+                return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+            }
+            let (dirfd, name) = jail.parent(&path)?;
+            if attrs.uid.is_some() || attrs.gid.is_some() {
+                let uid = attrs.uid.unwrap_or(u32::MAX);
+                let gid = attrs.gid.unwrap_or(u32::MAX);
+                check(unsafe { libc::fchownat(dirfd.as_raw_fd(), name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) })?;
+            }
+            if attrs.atime.is_some() || attrs.mtime.is_some() {
+                let times = [timespec(attrs.atime), timespec(attrs.mtime)];
+                check(unsafe { libc::utimensat(dirfd.as_raw_fd(), name.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })?;
+            }
+            Ok(())
+        }))
+    }
+
+    fn list<'a>(&'a self, path: &'a str, sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(blocking(move || {
+            let fd = jail.resolve(&path, libc::O_RDONLY | libc::O_DIRECTORY, 0, true)?;
+            let mut names = Vec::new();
+            let stream = unsafe { libc::fdopendir(libc::dup(fd.as_raw_fd())) };
+            if stream.is_null() {
+                return Err(io::Error::last_os_error())
+            }
+            // a sorted listing is cut off after sorting, so it always starts with the first names
+            while sort || limit == 0 || names.len() < limit {
+                let entry = unsafe { libc::readdir64(stream) };
+                if entry.is_null() {
+                    break
+                }
+                let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) }.to_bytes();
+                if name != b"." && name != b".." {
+                    names.push(OsString::from_vec(name.to_vec()));
+                }
+            }
+            unsafe { libc::closedir(stream) };
+            if sort {
+                names.sort_unstable();
+                if limit != 0 {
+                    names.truncate(limit);
+                }
+            }
+
+            let mut names = VecDeque::from(names);
+            names.push_front(OsString::from(".."));
+            names.push_front(OsString::from("."));
+            let (st, root_st) = (fstat(&fd)?, fstat(&jail.root)?);
+            let is_root = st.st_dev == root_st.st_dev && st.st_ino == root_st.st_ino;
+            Ok(Box::new(Dir { fd: Arc::new(fd), root: jail.root.clone(), is_root, names }) as Box<dyn DirStream>)
+        }))
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(blocking(move || {
+            let (dirfd, name) = jail.parent(&path)?;
+            check(unsafe { libc::unlinkat(dirfd.as_raw_fd(), name.as_ptr(), 0) }).map(|_| ())
+        }))
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, io::Result<()>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(blocking(move || {
+            let (dirfd, name) = jail.parent(&path)?;
+            check(unsafe { libc::mkdirat(dirfd.as_raw_fd(), name.as_ptr(), mode) })?;
+            let fd = openat(dirfd.as_raw_fd(), &name, libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?;
+            set_mode(&fd, mode)
+        }))
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(blocking(move || {
+            let (dirfd, name) = jail.parent(&path)?;
+            check(unsafe { libc::unlinkat(dirfd.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR) }).map(|_| ())
+        }))
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>> {
+        let (jail, from, to) = (self.clone(), normalize(from), normalize(to));
+        Box::pin(blocking(move || {
+            let (from_dirfd, from_name) = jail.parent(&from)?;
+            let (to_dirfd, to_name) = jail.parent(&to)?;
+            if !replace {
+                let ret = unsafe { libc::renameat2(from_dirfd.as_raw_fd(), from_name.as_ptr(), to_dirfd.as_raw_fd(), to_name.as_ptr(), libc::RENAME_NOREPLACE) };
+                match check(ret) {
+                    // filesystems without RENAME_NOREPLACE, fall back to checking first
+                    Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                        let mut st: libc::stat = unsafe { mem::zeroed() };
+                        if unsafe { libc::fstatat(to_dirfd.as_raw_fd(), to_name.as_ptr(), &mut st, libc::AT_SYMLINK_NOFOLLOW) } == 0 {
+                            return Err(io::Error::from(ErrorKind::AlreadyExists))
+                        }
+                    }
+                    res => return res.map(|_| ())
+                }
+            }
+            check(unsafe { libc::renameat(from_dirfd.as_raw_fd(), from_name.as_ptr(), to_dirfd.as_raw_fd(), to_name.as_ptr()) }).map(|_| ())
+        }))
+    }
+
+    fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(blocking(move || {
+            let fd = jail.resolve(&path, libc::O_PATH, 0, true)?;
+            fstatvfs(fd.as_raw_fd())
+        }))
+    }
+
+    fn read_link<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        let (jail, path) = (self.clone(), normalize(path));
+        Box::pin(blocking(move || {
+            let (dirfd, name) = jail.parent(&path)?;
+            let target = readlinkat(dirfd.as_raw_fd(), &name)?;
+            Ok(String::from_utf8_lossy(&target).into_owned())
+        }))
+    }
+
+    fn symlink<'a>(&'a self, target: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        let (jail, path, target) = (self.clone(), normalize(path), cstring(target));
+        Box::pin(blocking(move || {
+            let target = target?;
+            let (dirfd, name) = jail.parent(&path)?;
+            check(unsafe { libc::symlinkat(target.as_ptr(), dirfd.as_raw_fd(), name.as_ptr()) }).map(|_| ())
+        }))
+    }
+
+    fn hard_link<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        let (jail, from, to) = (self.clone(), normalize(from), normalize(to));
+        Box::pin(blocking(move || {
+            let (from_dirfd, from_name) = jail.parent(&from)?;
+            let (to_dirfd, to_name) = jail.parent(&to)?;
+            check(unsafe { libc::linkat(from_dirfd.as_raw_fd(), from_name.as_ptr(), to_dirfd.as_raw_fd(), to_name.as_ptr(), 0) }).map(|_| ())
+        }))
+    }
+
+    // the copy shares its data with the original where the filesystem supports reflinks
+    fn copy_file<'a>(&'a self, from: &'a str, to: &'a str, overwrite: bool) -> BoxFuture<'a, io::Result<()>> {
+        let (jail, from, to) = (self.clone(), normalize(from), normalize(to));
+        Box::pin(blocking(move || {
+            let source = jail.resolve(&from, libc::O_RDONLY, 0, true)?;
+            let st = fstat(&source)?;
+            if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "not a regular file"))
+            }
+            let flags = libc::O_WRONLY | libc::O_CREAT | if overwrite { 0 } else { libc::O_EXCL };
+            let dest = jail.create(&to, flags, st.st_mode & 0o777)?;
+            let dest_st = fstat(&dest)?;
+            // truncating the destination would otherwise destroy the source
+            if dest_st.st_dev == st.st_dev && dest_st.st_ino == st.st_ino {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "source and destination are the same file"))
+            }
+            let (source, dest) = (File::from(source), File::from(dest));
+            dest.set_len(0)?;
+            if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == 0 {
+                return Ok(())
+            }
+            copy_range(&source, 0, 0, &dest, 0)
+        }))
+    }
+}
+
+// the flag values are the ones OpenSSH defines for statvfs@openssh.com
+#[allow(clippy::unnecessary_cast)]
+fn fstatvfs(fd: RawFd) -> io::Result<FsStats> {
+    let mut st: libc::statvfs = unsafe { mem::zeroed() };
+    check(unsafe { libc::fstatvfs(fd, &mut st) })?;
+    Ok(FsStats {
+        block_size: st.f_bsize as u64,
+        fragment_size: st.f_frsize as u64,
+        blocks: st.f_blocks as u64,
+        blocks_free: st.f_bfree as u64,
+        blocks_avail: st.f_bavail as u64,
+        files: st.f_files as u64,
+        files_free: st.f_ffree as u64,
+        files_avail: st.f_favail as u64,
+        fs_id: st.f_fsid as u64,
+        read_only: st.f_flag & libc::ST_RDONLY != 0,
+        no_suid: st.f_flag & libc::ST_NOSUID != 0,
+        name_max: st.f_namemax as u64
+    })
+}
+
+/// A file opened inside a jail
+pub(crate) struct LocalFile {
+    file: Arc<File>
+}
+
+impl From<File> for LocalFile {
+    fn from(file: File) -> Self {
+        LocalFile { file: Arc::new(file) }
+    }
+}
+
+impl StorageFile for LocalFile {
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        let file = self.file.clone();
+        Box::pin(blocking(move || {
+            let mut buf = vec![0u8; len];
+            let mut read = 0;
+            while read < len {
+                match file.read_at(&mut buf[read..], offset + read as u64) {
+                    Ok(0) => break,
+                    Ok(n) => read += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e)
+                }
+            }
+            buf.truncate(read);
+            Ok(buf)
+        }))
+    }
+
+    fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)> {
+        let file = self.file.clone();
+        Box::pin(async move {
+            let written = tokio::task::spawn_blocking(move || {
+                let result = file.write_all_at(&data, offset);
+                (data, result)
+            }).await;
+            written.unwrap_or_else(|e| (Vec::new(), Err(io::Error::other(e))))
+        })
+    }
+
+    fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>> {
+        let file = self.file.clone();
+        Box::pin(blocking(move || file.metadata().map(|metadata| FileStat::from(&metadata))))
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        let file = self.file.clone();
+        Box::pin(blocking(move || apply_attributes(file.as_raw_fd(), attrs)))
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        let file = self.file.clone();
+        Box::pin(blocking(move || file.sync_all()))
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        let file = self.file.clone();
+        Box::pin(blocking(move || fstatvfs(file.as_raw_fd())))
+    }
+
+    fn will_need(&self, offset: u64, len: u64) {
+        // only a hint, reads don't depend on it
+        unsafe { libc::posix_fadvise(self.file.as_raw_fd(), offset as i64, len as i64, libc::POSIX_FADV_WILLNEED) };
+    }
+
+    fn as_local(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
+
+/// Directory listing opened inside a jail, entries are stat'ed relative to the directory's descriptor
+pub(crate) struct Dir {
+    fd: Arc<OwnedFd>,
+    root: Arc<OwnedFd>,
+    // `..` of the jail root is the root itself, it must never be opened
+    is_root: bool,
+    names: VecDeque<OsString>
+}
+
+impl DirStream for Dir {
+    // entries deleted since the directory was read are skipped
+    fn next_entries(&mut self, count: usize) -> BoxFuture<'_, io::Result<Vec<DirEntry>>> {
+        let names: Vec<OsString> = self.names.drain(..count.min(self.names.len())).collect();
+        let (fd, root, is_root) = (self.fd.clone(), self.root.clone(), self.is_root);
+        Box::pin(blocking(move || {
+            let mut entries = Vec::with_capacity(names.len());
+            for name in names {
+                let entry_fd = match name.as_bytes() {
+                    b"." => fd.try_clone()?,
+                    b".." if is_root => root.try_clone()?,
+                    name => match openat(fd.as_raw_fd(), &cstring(name)?, libc::O_PATH | libc::O_NOFOLLOW, 0) {
+                        Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                        res => res?
+                    }
+                };
+                entries.push(DirEntry { name: name.to_string_lossy().into_owned(), stat: stat_of(entry_fd)? });
+            }
+            Ok(entries)
+        }))
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        let fd = self.fd.clone();
+        Box::pin(blocking(move || apply_attributes(fd.as_raw_fd(), attrs)))
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        let fd = self.fd.clone();
+        Box::pin(blocking(move || fstatvfs(fd.as_raw_fd())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink, path::PathBuf};
+
+    use super::*;
+
+    // a jail next to a directory with a file that must stay out of reach, both removed when dropped
+    struct Setup {
+        base: PathBuf,
+        jail: Jail
+    }
+
+    impl Setup {
+        fn new(name: &str) -> Self {
+            let base = std::env::temp_dir().join(format!("flux-sftp-jail-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&base);
+            fs::create_dir_all(base.join("jail/sub")).unwrap();
+            fs::create_dir_all(base.join("outside")).unwrap();
+            fs::write(base.join("outside/secret"), "secret").unwrap();
+            fs::write(base.join("jail/sub/file"), "file").unwrap();
+            let jail = Jail::new(base.join("jail").to_str().unwrap()).unwrap();
+            Setup { base, jail }
+        }
+
+        fn link(&self, name: &str, target: &str) {
+            symlink(target, self.base.join("jail").join(name)).unwrap();
+        }
+
+        fn outside(&self, name: &str) -> PathBuf {
+            self.base.join("outside").join(name)
+        }
+    }
+
+    impl Drop for Setup {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    fn escapes<T>(result: io::Result<T>) {
+        match result {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::PermissionDenied, "{}", e),
+            Ok(_) => panic!("path resolved outside the jail")
+        }
+    }
+
+    // both ways paths are resolved, with openat2 where the kernel has it and by walking them
+    fn resolve_escapes(jail: &Jail, path: &str) {
+        escapes(jail.resolve(path, libc::O_RDONLY, 0, true));
+        escapes(jail.walk(path, libc::O_RDONLY, 0, true));
+    }
+
+    fn read(jail: &Jail, path: &str) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        io::Read::read_to_end(&mut File::from(jail.resolve(path, libc::O_RDONLY, 0, true)?), &mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn normalize_stays_at_root() {
+        assert_eq!(normalize("../../outside/secret"), "outside/secret");
+        assert_eq!(normalize("/sub/../../.."), "");
+        assert_eq!(normalize("./sub/./file/"), "sub/file");
+        assert_eq!(link_target("sub", "../../outside"), None);
+        assert_eq!(link_target("sub", "/etc/passwd"), None);
+        assert_eq!(link_target("sub", "../sub/file").as_deref(), Some("sub/file"));
+    }
+
+    #[tokio::test]
+    async fn dot_dot_traversal() {
+        let setup = Setup::new("dotdot");
+        let jail = &setup.jail;
+        resolve_escapes(jail, "../outside/secret");
+        resolve_escapes(jail, "sub/../../outside/secret");
+        resolve_escapes(jail, "..");
+        // the backend normalizes client paths first, so they end up inside the jail
+        assert!(jail.stat("../outside/secret", true).await.is_err_and(|e| e.kind() == ErrorKind::NotFound));
+        assert_eq!(jail.canonicalize("../../sub/file").await.unwrap(), "/sub/file");
+        assert_eq!(read(jail, "sub/../sub/file").unwrap(), b"file");
+    }
+
+    #[tokio::test]
+    async fn absolute_symlink() {
+        let setup = Setup::new("absolute");
+        setup.link("abs", setup.outside("secret").to_str().unwrap());
+        setup.link("absdir", setup.base.join("outside").to_str().unwrap());
+        let jail = &setup.jail;
+        resolve_escapes(jail, "abs");
+        resolve_escapes(jail, "absdir/secret");
+        escapes(jail.open("abs", OpenOptions { read: true, ..Default::default() }, 0).await);
+        escapes(jail.open("abs", OpenOptions { write: true, truncate: true, ..Default::default() }, 0).await);
+        escapes(jail.stat("abs", true).await);
+        escapes(jail.canonicalize("abs").await);
+        // the link itself is still there to be looked at and removed
+        assert_eq!(jail.stat("abs", false).await.unwrap().mode & libc::S_IFMT, libc::S_IFLNK);
+        jail.remove_file("abs").await.unwrap();
+        assert_eq!(fs::read(setup.outside("secret")).unwrap(), b"secret");
+    }
+
+    #[tokio::test]
+    async fn relative_symlink() {
+        let setup = Setup::new("relative");
+        setup.link("rel", "../outside/secret");
+        setup.link("sub/deep", "../../outside/secret");
+        setup.link("inner", "sub/file");
+        let jail = &setup.jail;
+        resolve_escapes(jail, "rel");
+        resolve_escapes(jail, "sub/deep");
+        escapes(jail.open("rel", OpenOptions { read: true, ..Default::default() }, 0).await);
+        escapes(jail.open("sub/deep", OpenOptions { write: true, ..Default::default() }, 0).await);
+        escapes(jail.set_attributes("rel", SetAttributes { size: Some(0), ..Default::default() }, true).await);
+        escapes(jail.copy_file("rel", "copy", false).await);
+        // links that stay inside work as usual
+        assert_eq!(read(jail, "inner").unwrap(), b"file");
+        assert_eq!(jail.canonicalize("inner").await.unwrap(), "/sub/file");
+        assert_eq!(fs::read(setup.outside("secret")).unwrap(), b"secret");
+    }
+
+    #[tokio::test]
+    async fn symlinked_parent() {
+        let setup = Setup::new("parent");
+        setup.link("out", "../outside");
+        setup.link("sub/up", "..");
+        let jail = &setup.jail;
+        resolve_escapes(jail, "out/secret");
+        resolve_escapes(jail, "sub/up/out/secret");
+        resolve_escapes(jail, "sub/up/../outside/secret");
+        escapes(jail.open("out/secret", OpenOptions { read: true, ..Default::default() }, 0).await);
+        escapes(jail.open("out/new", OpenOptions { write: true, create: true, ..Default::default() }, 0o644).await);
+        escapes(jail.list("out", false, 0).await);
+        escapes(jail.remove_file("out/secret").await);
+        escapes(jail.read_link("out/secret").await);
+        escapes(jail.symlink("secret", "out/link").await);
+        escapes(jail.statvfs("out").await);
+        assert!(!setup.outside("new").exists());
+        assert!(!setup.outside("link").exists());
+        assert_eq!(fs::read(setup.outside("secret")).unwrap(), b"secret");
+        // a link to a directory inside the jail can be used as a parent
+        assert_eq!(read(jail, "sub/up/sub/file").unwrap(), b"file");
+    }
+
+    #[tokio::test]
+    async fn rename_and_mkdir_through_links() {
+        let setup = Setup::new("rename");
+        setup.link("out", "../outside");
+        let jail = &setup.jail;
+        escapes(jail.rename("sub/file", "out/file", false).await);
+        escapes(jail.rename("sub/file", "out/secret", true).await);
+        escapes(jail.rename("out/secret", "stolen", false).await);
+        escapes(jail.create_dir("out/dir", 0o755).await);
+        escapes(jail.remove_dir("out/dir").await);
+        escapes(jail.hard_link("out/secret", "stolen").await);
+        escapes(jail.hard_link("sub/file", "out/file").await);
+        escapes(jail.copy_file("sub/file", "out/file", true).await);
+        assert!(!setup.outside("file").exists());
+        assert!(!setup.outside("dir").exists());
+        assert!(!setup.base.join("jail/stolen").exists());
+        assert_eq!(fs::read(setup.outside("secret")).unwrap(), b"secret");
+        // renaming the link itself only moves the link
+        jail.rename("out", "sub/out", false).await.unwrap();
+        assert!(setup.base.join("outside/secret").exists());
+    }
+
+    #[tokio::test]
+    async fn sorted_listing_limit() {
+        let setup = Setup::new("list");
+        for i in (0..50).rev() {
+            fs::write(setup.base.join(format!("jail/sub/{:02}", i)), "").unwrap();
+        }
+        let mut dir = setup.jail.list("sub", true, 5).await.unwrap();
+        let mut names = Vec::new();
+        loop {
+            let entries = dir.next_entries(100).await.unwrap();
+            if entries.is_empty() {
+                break
+            }
+            names.extend(entries.into_iter().map(|entry| entry.name));
+        }
+        assert_eq!(names, [".", "..", "00", "01", "02", "03", "04"]);
+    }
+
+    // modes are the ones asked for, not what the process umask leaves of them
+    #[tokio::test]
+    async fn created_modes() {
+        let setup = Setup::new("modes");
+        let jail = &setup.jail;
+        let mode = |path: &str| fs::symlink_metadata(setup.base.join("jail").join(path)).map(|m| std::os::unix::fs::PermissionsExt::mode(&m.permissions()) & 0o7777).unwrap();
+        jail.open("new", OpenOptions { write: true, create: true, ..Default::default() }, 0o666).await.unwrap();
+        assert_eq!(mode("new"), 0o666);
+        jail.open("excl", OpenOptions { write: true, create: true, exclusive: true, ..Default::default() }, 0o757).await.unwrap();
+        assert_eq!(mode("excl"), 0o757);
+        // files that are already there keep theirs
+        fs::set_permissions(setup.base.join("jail/sub/file"), std::os::unix::fs::PermissionsExt::from_mode(0o640)).unwrap();
+        jail.open("sub/file", OpenOptions { write: true, create: true, truncate: true, ..Default::default() }, 0o666).await.unwrap();
+        assert_eq!(mode("sub/file"), 0o640);
+        jail.create_dir("dir", 0o777).await.unwrap();
+        assert_eq!(mode("dir"), 0o777);
+        jail.copy_file("new", "copy", false).await.unwrap();
+        assert_eq!(mode("copy"), 0o666);
+    }
+
+    // the fallback for kernels without openat2, run directly since this kernel likely has it
+    #[test]
+    fn walk_fallback() {
+        let setup = Setup::new("walk");
+        setup.link("abs", setup.outside("secret").to_str().unwrap());
+        setup.link("rel", "../outside/secret");
+        setup.link("out", "../outside");
+        setup.link("sub/up", "..");
+        setup.link("loop", "loop");
+        setup.link("inner", "sub/file");
+        let jail = &setup.jail;
+        for path in ["..", "../outside/secret", "sub/../../outside", "abs", "rel", "out", "out/secret", "out/new", "sub/up/out/secret", "sub/up/../outside"] {
+            escapes(jail.walk(path, libc::O_PATH, 0, true));
+        }
+        escapes(jail.walk("out/new", libc::O_WRONLY | libc::O_CREAT, 0o644, true));
+        assert!(!setup.outside("new").exists());
+        assert_eq!(jail.walk("loop", libc::O_PATH, 0, true).unwrap_err().raw_os_error(), Some(libc::ELOOP));
+        // the last component isn't followed without `follow`, the link itself is opened
+        let (fd, names) = jail.walk("abs", libc::O_PATH | libc::O_NOFOLLOW, 0, false).unwrap();
+        assert_eq!(fstat(&fd).unwrap().st_mode & libc::S_IFMT, libc::S_IFLNK);
+        assert_eq!(names, [b"abs".to_vec()]);
+        let (fd, names) = jail.walk("sub/up/inner", libc::O_RDONLY, 0, true).unwrap();
+        let mut data = Vec::new();
+        io::Read::read_to_end(&mut File::from(fd), &mut data).unwrap();
+        assert_eq!(data, b"file");
+        assert_eq!(names, [b"sub".to_vec(), b"file".to_vec()]);
+    }
+}
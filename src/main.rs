@@ -1,13 +1,43 @@
 mod sftp;
 mod config;
+mod jail;
+mod users;
+mod checksum;
+mod exec;
+mod dispatch;
+mod file;
+mod storage;
+mod s3;
+mod memory;
+mod encryption;
+mod compression;
+mod dedup;
+mod readonly;
+mod quota;
+mod folders;
+mod permissions;
+mod glob;
 
-use std::{io::ErrorKind, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use std::{io::ErrorKind, net::SocketAddr, num::ParseIntError, path::Path, sync::Arc, time::Duration};
 use bcrypt::{hash, DEFAULT_COST};
-use config::{Config, DriverConfig};
+use config::{AccountMode, Backend, Config, DriverConfig, GroupConfig};
+use exec::HashCommand;
+use jail::Jail;
+use s3::{Bucket, S3Backend};
+use memory::MemoryBackend;
+use encryption::{EncryptedBackend, Keys};
+use compression::{CompressedBackend, Compression, OpenFiles};
+use dedup::{BlobStore, DedupBackend};
+use folders::{FolderBackend, Folders};
+use quota::{QuotaBackend, Usages};
+use permissions::{PermissionRules, Permissions};
+use readonly::ReadOnlyBackend;
 use russh::{keys::ssh_key::PublicKey, server::{Auth, Handler as SshHandler, Msg, Server, Session}, Channel, ChannelId};
 use sftp::SftpSession;
+use storage::StorageBackend;
 use sqlx::{mysql::MySqlPoolOptions, postgres::PgPoolOptions, sqlite::SqlitePoolOptions, MySql, Pool, Postgres, Row, Sqlite};
 use tokio::fs;
+use users::{is_safe_dir_name, UsernamePolicy};
 
 macro_rules! fetch_col {
     ($col:ident, $pool:ident, $query:expr, $user:ident) => {
@@ -23,9 +53,34 @@ macro_rules! fetch_col {
     };
 }
 
+// like fetch_col but also accepts integer columns, used for values that end up as directory names
+macro_rules! fetch_text {
+    ($col:ident, $pool:ident, $query:expr, $user:ident) => {
+        {
+            let row_res = sqlx::query(&$query)
+                .bind($user)
+                .fetch_one($pool).await;
+            match row_res {
+                Ok(row) => row.try_get::<String, _>($col as &str).ok()
+                    .or_else(|| row.try_get::<i64, _>($col as &str).ok().map(|id| id.to_string()))
+                    .or_else(|| row.try_get::<i32, _>($col as &str).ok().map(|id| id.to_string())),
+                Err(_) => None
+            }
+        }
+    };
+}
+
 struct SftpServer {
     pool: Arc<DBPool>,
-    config: Arc<Config>
+    config: Arc<Config>,
+    policy: Arc<UsernamePolicy>,
+    bucket: Option<Arc<Bucket>>,
+    keys: Option<Arc<Keys>>,
+    blobs: Option<Arc<BlobStore>>,
+    compression: Option<Arc<Compression>>,
+    folders: Arc<Folders>,
+    usages: Arc<Usages>,
+    permissions: Arc<PermissionRules>
 }
 
 impl Server for SftpServer {
@@ -34,15 +89,326 @@ impl Server for SftpServer {
     fn new_client(&mut self, _peer_addr: Option<SocketAddr>) -> Self::Handler {
         let session_pool = self.pool.clone();
         let config = self.config.clone();
-        SshSession { channel: None, user: None, pool: session_pool, config }
+        let policy = self.policy.clone();
+        let bucket = self.bucket.clone();
+        let keys = self.keys.clone();
+        let blobs = self.blobs.clone();
+        let compression = self.compression.clone();
+        let folders = self.folders.clone();
+        let usages = self.usages.clone();
+        let permissions = self.permissions.clone();
+        let backend = config.storage.backend;
+        SshSession { channel: None, exec_channel: None, user: None, dir: None, umask: None, backend, mode: AccountMode::Normal, memory: None, compress: None, max_size: 0, max_files: 0, group_names: Vec::new(), folder_names: Vec::new(), pool: session_pool, config, policy, bucket, keys, blobs, compression, folders, usages, permissions }
     }
 }
 
 struct SshSession {
     channel: Option<Channel<Msg>>,
+    exec_channel: Option<ChannelId>,
     user: Option<String>,
+    dir: Option<String>,
+    umask: Option<u32>,
+    backend: Backend,
+    mode: AccountMode,
+    // scratch space of the memory backend, shared by the connection's channels and gone when it closes
+    memory: Option<Arc<MemoryBackend>>,
+    // whether the user's new files are compressed, None leaves it to the config
+    compress: Option<bool>,
+    // quota on the user's own files, 0 means no limit
+    max_size: u64,
+    max_files: u64,
+    // groups the user is in, for the permission rules that apply to them
+    group_names: Vec<String>,
+    // virtual folders mounted into the user's tree
+    folder_names: Vec<String>,
     pool: Arc<DBPool>,
-    config: Arc<Config>
+    config: Arc<Config>,
+    policy: Arc<UsernamePolicy>,
+    bucket: Option<Arc<Bucket>>,
+    keys: Option<Arc<Keys>>,
+    blobs: Option<Arc<BlobStore>>,
+    compression: Option<Arc<Compression>>,
+    folders: Arc<Folders>,
+    usages: Arc<Usages>,
+    permissions: Arc<PermissionRules>
+}
+
+impl SshSession {
+    // value of a column for the user, integer columns are returned as their decimal digits
+    async fn lookup_field(&self, field: &str, user: &str) -> Option<String> {
+        let query = format!("SELECT {} FROM {} WHERE {} = ?", field, self.config.database.common.table, self.config.database.common.username_field);
+        match &*self.pool {
+            DBPool::Sqlite(pool) => fetch_text!(field, pool, query, user),
+            DBPool::Postgres(pool) => fetch_text!(field, pool, query.replace("?", "$1"), user),
+            DBPool::Mysql(pool) => fetch_text!(field, pool, query, user)
+        }
+    }
+
+    // name of the user's directory inside jail_dir, the username itself unless dir_field is configured
+    async fn lookup_dir(&self, user: &str) -> Option<String> {
+        let Some(dir_field) = &self.config.database.common.dir_field else {
+            return Some(user.to_string())
+        };
+        self.lookup_field(dir_field, user).await.filter(|dir| is_safe_dir_name(dir))
+    }
+
+    fn open_jail(&self) -> Option<Jail> {
+        let jail_dir = format!("{}/{}", self.config.general.jail_dir, self.dir.as_ref()?);
+        match Jail::new(&jail_dir) {
+            Ok(jail) => Some(jail),
+            Err(e) => {
+                println!("error opening jail directory {} for user {}: {}", jail_dir, self.user.as_ref()?, e);
+                None
+            }
+        }
+    }
+
+    // the user's files, read-only accounts can't change anything in them, including their folders
+    fn open_storage(&mut self) -> Option<Arc<dyn StorageBackend>> {
+        let storage = self.open_tree()?;
+        match self.mode {
+            AccountMode::ReadOnly => Some(Arc::new(ReadOnlyBackend::new(storage))),
+            _ => Some(storage)
+        }
+    }
+
+    // what the user's rules permit, drop box accounts can only upload whatever the rules say
+    fn permissions(&self) -> Permissions {
+        let permissions = self.permissions.for_user(self.user.as_ref().unwrap(), &self.group_names);
+        match self.mode {
+            AccountMode::DropBox => permissions.drop_box(self.config.sftp.drop_box_overwrite),
+            _ => permissions
+        }
+    }
+
+    // where the user's files are kept, limited by the user's quota, compressed if the compression layer is
+    // configured, with the user's virtual folders mounted into it
+    fn open_tree(&mut self) -> Option<Arc<dyn StorageBackend>> {
+        let mut storage = self.open_backend()?;
+        if let Some(quota_dir) = self.quota_dir() {
+            storage = Arc::new(QuotaBackend::new(storage, self.usages.get(&quota_dir), self.max_size, self.max_files));
+        }
+        if let Some(compression) = &self.compression {
+            let files = self.tree_dir().map_or_else(|| Arc::new(OpenFiles::new()), |dir| compression.open_files(&dir));
+            storage = Arc::new(CompressedBackend::new(storage, compression.clone(), self.compress.unwrap_or(compression.enabled()), files));
+        }
+        if self.folder_names.is_empty() {
+            return Some(storage)
+        }
+        match self.folders.mount(&self.folder_names, self.user.as_ref()?, self.dir.as_ref()?) {
+            Ok(mounts) => Some(Arc::new(FolderBackend::new(storage, mounts))),
+            Err(e) => {
+                println!("{} for user: {}", e, self.user.as_ref()?);
+                None
+            }
+        }
+    }
+
+    // what the usage of the user's files is kept under, None without a quota. the memory backend has its own limits
+    fn quota_dir(&self) -> Option<String> {
+        if self.max_size == 0 && self.max_files == 0 {
+            return None
+        }
+        self.tree_dir()
+    }
+
+    // where the user's files are, so every session seeing the same files shares what is kept about them. None for
+    // the memory backend
+    fn tree_dir(&self) -> Option<String> {
+        let dir = self.dir.as_ref()?;
+        match self.backend {
+            Backend::Local => Some(format!("{}/{}", self.config.general.jail_dir, dir)),
+            Backend::Memory => None,
+            backend => Some(format!("{}:{}", String::from(backend), dir))
+        }
+    }
+
+    fn open_backend(&mut self) -> Option<Arc<dyn StorageBackend>> {
+        match self.backend {
+            Backend::Local => {
+                let jail = Arc::new(self.open_jail()?);
+                let Some(keys) = &self.keys else {
+                    return Some(jail)
+                };
+                let dir = self.dir.as_ref()?;
+                match keys.user_key(dir) {
+                    Ok(key) => Some(Arc::new(EncryptedBackend::new(jail, key, keys.file_locks(dir)))),
+                    Err(e) => {
+                        println!("error loading data key for user {}: {}", self.user.as_ref()?, e);
+                        None
+                    }
+                }
+            }
+            Backend::S3 => {
+                let Some(bucket) = &self.bucket else {
+                    println!("s3 backend is not configured, rejecting user: {}", self.user.as_ref()?);
+                    return None
+                };
+                Some(Arc::new(S3Backend::new(bucket.clone(), self.dir.as_ref()?)))
+            }
+            Backend::Memory => {
+                let memory = self.memory.get_or_insert_with(|| Arc::new(MemoryBackend::new(&self.config.storage.memory)));
+                Some(memory.clone())
+            }
+            Backend::Dedup => {
+                let Some(blobs) = &self.blobs else {
+                    println!("dedup backend is not configured, rejecting user: {}", self.user.as_ref()?);
+                    return None
+                };
+                match blobs.namespace(self.dir.as_ref()?) {
+                    Ok(namespace) => Some(Arc::new(DedupBackend::new(namespace, blobs.clone()))),
+                    Err(e) => {
+                        println!("error opening dedup directory for user {}: {}", self.user.as_ref()?, e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    // comma separated names in a column, e.g. of folders or groups
+    async fn lookup_list(&self, field: &str, user: &str) -> Vec<String> {
+        let list = self.lookup_field(field, user).await.unwrap_or_default();
+        list.split(',').map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect()
+    }
+
+    // a number in a column, None if it's empty
+    async fn lookup_limit(&self, field: &str, user: &str) -> Option<Result<u64, ParseIntError>> {
+        self.lookup_field(field, user).await.filter(|limit| !limit.trim().is_empty()).map(|limit| limit.trim().parse())
+    }
+
+    async fn accept(&mut self, user: &str) -> Auth {
+        let config = self.config.clone();
+        // groups from the database and the config file, in that order, which is the order their settings apply in.
+        // an unknown group rejects the user
+        let mut group_names = Vec::new();
+        if let Some(groups_field) = &config.database.common.groups_field {
+            for name in self.lookup_list(groups_field, user).await {
+                if !config.groups.contains_key(&name) {
+                    println!("unknown group {} for user: {}", name, user);
+                    return Auth::reject()
+                }
+                if !group_names.contains(&name) {
+                    group_names.push(name);
+                }
+            }
+        }
+        for (name, group) in &config.groups {
+            if group.users.iter().any(|u| u == user) && !group_names.contains(name) {
+                group_names.push(name.clone());
+            }
+        }
+        let groups: Vec<&GroupConfig> = group_names.iter().map(|name| &config.groups[name]).collect();
+        // settings of the first group that has them, overridden by the user's own columns
+        self.backend = groups.iter().find_map(|group| group.backend).unwrap_or(config.storage.backend);
+        self.compress = groups.iter().find_map(|group| group.compression);
+        self.max_size = groups.iter().find_map(|group| group.max_size).unwrap_or(0);
+        self.max_files = groups.iter().find_map(|group| group.max_files).unwrap_or(0);
+        self.mode = groups.iter().find_map(|group| group.mode).unwrap_or(AccountMode::Normal);
+        let group_umask = groups.iter().find_map(|group| group.umask);
+        // an empty umask column falls back to the group's or the global one, one that can't be parsed rejects the user
+        self.umask = group_umask;
+        if let Some(umask_field) = &config.database.common.umask_field {
+            self.umask = match self.lookup_field(umask_field, user).await.map(|umask| u32::from_str_radix(umask.trim(), 8)) {
+                None => group_umask,
+                Some(Ok(umask)) if umask <= 0o777 => Some(umask),
+                Some(_) => {
+                    println!("invalid umask for user: {}", user);
+                    return Auth::reject()
+                }
+            };
+        }
+        // same for the backend, an unknown one rejects the user
+        if let Some(backend_field) = &config.database.common.backend_field {
+            match self.lookup_field(backend_field, user).await.filter(|backend| !backend.trim().is_empty()).map(|backend| Backend::try_from(backend.trim().to_string())) {
+                None => {}
+                Some(Ok(backend)) => self.backend = backend,
+                Some(Err(e)) => {
+                    println!("{} for user: {}", e, user);
+                    return Auth::reject()
+                }
+            }
+        }
+        // same for the account mode
+        if let Some(mode_field) = &config.database.common.mode_field {
+            match self.lookup_field(mode_field, user).await.filter(|mode| !mode.trim().is_empty()).map(|mode| AccountMode::try_from(mode.trim().to_string())) {
+                None => {}
+                Some(Ok(mode)) => self.mode = mode,
+                Some(Err(e)) => {
+                    println!("{} for user: {}", e, user);
+                    return Auth::reject()
+                }
+            }
+        }
+        // a value that isn't a boolean rejects the user
+        if let Some(compression_field) = &config.database.common.compression_field {
+            match self.lookup_field(compression_field, user).await.map(|compress| compress.trim().to_lowercase()) {
+                None => {}
+                Some(compress) => match compress.as_str() {
+                    "" => {}
+                    "true" | "1" | "yes" => self.compress = Some(true),
+                    "false" | "0" | "no" => self.compress = Some(false),
+                    _ => {
+                        println!("invalid compression setting for user: {}", user);
+                        return Auth::reject()
+                    }
+                }
+            }
+        }
+        // quotas, a value that isn't a number rejects the user
+        if let Some(max_size_field) = &config.database.common.max_size_field {
+            match self.lookup_limit(max_size_field, user).await {
+                None => {}
+                Some(Ok(max_size)) => self.max_size = max_size,
+                Some(Err(_)) => {
+                    println!("invalid max_size for user: {}", user);
+                    return Auth::reject()
+                }
+            }
+        }
+        if let Some(max_files_field) = &config.database.common.max_files_field {
+            match self.lookup_limit(max_files_field, user).await {
+                None => {}
+                Some(Ok(max_files)) => self.max_files = max_files,
+                Some(Err(_)) => {
+                    println!("invalid max_files for user: {}", user);
+                    return Auth::reject()
+                }
+            }
+        }
+        // folders from the config file, the user's groups and a comma separated list from the database, unknown
+        // names reject the user
+        let mut folder_names = self.folders.names_for(user);
+        for name in groups.iter().flat_map(|group| &group.folders) {
+            if !folder_names.contains(name) {
+                folder_names.push(name.clone());
+            }
+        }
+        if let Some(folders_field) = &config.database.common.folders_field {
+            for name in self.lookup_list(folders_field, user).await {
+                if !self.folders.contains(&name) {
+                    println!("unknown folder {} for user: {}", name, user);
+                    return Auth::reject()
+                }
+                if !folder_names.contains(&name) {
+                    folder_names.push(name);
+                }
+            }
+        }
+        self.folder_names = folder_names;
+        self.group_names = group_names;
+        match self.lookup_dir(user).await {
+            Some(dir) => {
+                self.user = Some(user.to_string());
+                self.dir = Some(dir);
+                Auth::Accept
+            }
+            None => {
+                println!("no valid directory found for user: {}", user);
+                Auth::reject()
+            }
+        }
+    }
 }
 
 impl SshHandler for SshSession {
@@ -53,8 +419,12 @@ impl SshHandler for SshSession {
         user: &str,
         password: &str,
     ) -> Result<Auth, Self::Error> {
+        let Some(user) = self.policy.canonicalize(user) else {
+            println!("rejected invalid username: {:?}", user);
+            return Ok(Auth::reject())
+        };
+        let user = user.as_str();
         if let Some(password_field) = &self.config.database.common.password_field {
-            self.user = Some(user.to_string());
             let offered_hash = match hash(password, DEFAULT_COST) {
                 Ok(hash) => hash,
                 Err(_) => return Ok(Auth::reject())
@@ -67,7 +437,7 @@ impl SshHandler for SshSession {
                 DBPool::Mysql(pool) => fetch_col!(password_field, pool, query, user)
             }.unwrap_or_default();
 
-            if offered_hash == stored_password { Ok(Auth::Accept) } else { Ok(Auth::reject()) }
+            if offered_hash == stored_password { Ok(self.accept(user).await) } else { Ok(Auth::reject()) }
         }
         else {
             Ok(Auth::reject())
@@ -79,8 +449,12 @@ impl SshHandler for SshSession {
         user: &str,
         public_key: &PublicKey,
     ) -> Result<Auth, Self::Error> {
+        let Some(user) = self.policy.canonicalize(user) else {
+            println!("rejected invalid username: {:?}", user);
+            return Ok(Auth::reject())
+        };
+        let user = user.as_str();
         if let Some(public_key_field) = &self.config.database.common.public_key_field {
-            self.user = Some(user.to_string());
             let offered_key = public_key.to_string();
 
             let query = format!("SELECT {} FROM {} WHERE {} = ?", public_key_field, self.config.database.common.table, self.config.database.common.username_field);
@@ -90,7 +464,7 @@ impl SshHandler for SshSession {
                 DBPool::Mysql(pool) => fetch_col!(public_key_field, pool, query, user)
             }.unwrap_or_default();
             
-            if offered_key == stored_key { Ok(Auth::Accept) } else { Ok(Auth::reject()) }
+            if offered_key == stored_key { Ok(self.accept(user).await) } else { Ok(Auth::reject()) }
         }
         else {
             Ok(Auth::reject())
@@ -119,9 +493,34 @@ impl SshHandler for SshSession {
         channel_id: ChannelId,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        // a running exec command may still be reading its input, it closes the channel itself
+        if self.exec_channel == Some(channel_id) {
+            return Ok(())
+        }
         session.close(channel_id)
     }
 
+    async fn exec_request(
+        &mut self,
+        channel_id: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let command = String::from_utf8_lossy(data);
+        let Some(hash_command) = HashCommand::parse(&command, &self.config.exec.hash_commands) else {
+            println!("rejected exec request: {}", command);
+            return session.channel_failure(channel_id)
+        };
+        let Some(storage) = self.open_storage() else {
+            return session.channel_failure(channel_id)
+        };
+        session.channel_success(channel_id)?;
+        self.exec_channel = Some(channel_id);
+        let permissions = self.permissions();
+        tokio::spawn(hash_command.run(self.channel.take().ok_or(Self::Error::WrongChannel)?, storage, permissions));
+        Ok(())
+    }
+
     async fn subsystem_request(
         &mut self,
         channel_id: ChannelId,
@@ -129,10 +528,24 @@ impl SshHandler for SshSession {
         session: &mut Session,
     ) -> Result<(), Self::Error> {
         if name == "sftp" {
+            let Some(storage) = self.open_storage() else {
+                return session.channel_failure(channel_id)
+            };
+            let mut sftp_config = self.config.sftp.clone();
+            if let Some(umask) = self.umask {
+                sftp_config.umask = umask;
+            }
+            let permissions = self.permissions();
+            let sftp_handler = match SftpSession::new(storage, sftp_config, self.user.clone().unwrap(), permissions) {
+                Ok(handler) => handler,
+                Err(e) => {
+                    println!("error starting sftp session: {}", e);
+                    return session.channel_failure(channel_id)
+                }
+            };
             session.channel_success(channel_id)?;
-            let jail_dir = format!("{}/{}", self.config.general.jail_dir, self.user.as_ref().unwrap());
-            let sftp_handler = SftpSession::new(jail_dir);
-            russh_sftp::server::run(self.channel.take().ok_or(Self::Error::WrongChannel)?.into_stream(), sftp_handler).await;
+            let max_concurrent = self.config.sftp.max_concurrent_requests;
+            tokio::spawn(dispatch::run(self.channel.take().ok_or(Self::Error::WrongChannel)?.into_stream(), sftp_handler, max_concurrent));
         }
         else {
             session.channel_failure(channel_id)?;
@@ -151,7 +564,6 @@ enum DBPool {
 
 #[tokio::main]
 async fn main() -> Result<(), sqlx::Error> {
-
     const CONFIG_PATH: &str = "/etc/flux-sftp/config.toml";
     let config: Arc<Config>;
     match fs::read_to_string(CONFIG_PATH).await {
@@ -174,6 +586,31 @@ async fn main() -> Result<(), sqlx::Error> {
         }
     }
 
+    // `flux-sftp gc` cleans up the dedup backend's blob store instead of starting the server
+    if std::env::args().nth(1).as_deref() == Some("gc") {
+        let Some(dedup) = &config.storage.dedup else {
+            println!("the [storage.dedup] section is missing from the config file, there is nothing to collect");
+            return Ok(())
+        };
+        let blobs = match BlobStore::open(dedup) {
+            Ok(blobs) => Arc::new(blobs),
+            Err(e) => {
+                println!("{}", e);
+                return Ok(())
+            }
+        };
+        match blobs.collect_garbage().await {
+            Ok(collected) => {
+                println!("removed {} unreferenced blobs ({} bytes) and {} interrupted uploads, corrected {} reference counts", collected.blobs, collected.bytes, collected.uploads, collected.counts);
+                if collected.missing > 0 {
+                    println!("warning: {} blobs that files point to are missing, those files can't be read", collected.missing);
+                }
+            }
+            Err(e) => println!("error collecting garbage: {}", e)
+        }
+        return Ok(())
+    }
+
     let url = match &config.database.driver {
         DriverConfig::Sqlite { path } => format!("sqlite:{}", path),
         DriverConfig::Postgres { host, port, user, password, dbname }  => format!("postgres://{}:{}@{}:{}/{}", user, password, host, port, dbname),
@@ -186,7 +623,99 @@ async fn main() -> Result<(), sqlx::Error> {
         DriverConfig::Mysql { .. } => DBPool::Mysql(MySqlPoolOptions::new().max_connections(3).connect(&url).await?)
     };
 
-    let mut server = SftpServer { pool: Arc::new(pool), config: config.clone() };
+    let policy = match UsernamePolicy::new(&config.users) {
+        Ok(policy) => Arc::new(policy),
+        Err(e) => {
+            println!("invalid username_regex in config file: {}", e);
+            return Ok(())
+        }
+    };
+
+    let bucket = match &config.storage.s3 {
+        Some(s3) => match Bucket::new(s3) {
+            Ok(bucket) => Some(Arc::new(bucket)),
+            Err(e) => {
+                println!("invalid s3 storage config: {}", e);
+                return Ok(())
+            }
+        },
+        None if config.storage.backend == Backend::S3 => {
+            println!("s3 backend selected but the [storage.s3] section is missing from the config file");
+            return Ok(())
+        }
+        None => None
+    };
+
+    let keys = match &config.storage.encryption {
+        Some(encryption) => match Keys::load(encryption) {
+            Ok(keys) => Some(Arc::new(keys)),
+            Err(e) => {
+                println!("{}", e);
+                return Ok(())
+            }
+        },
+        None => None
+    };
+
+    let blobs = match &config.storage.dedup {
+        Some(dedup) => match BlobStore::open(dedup) {
+            Ok(blobs) => Some(Arc::new(blobs)),
+            Err(e) => {
+                println!("{}", e);
+                return Ok(())
+            }
+        },
+        None if config.storage.backend == Backend::Dedup => {
+            println!("dedup backend selected but the [storage.dedup] section is missing from the config file");
+            return Ok(())
+        }
+        None => None
+    };
+
+    let compression = match &config.storage.compression {
+        Some(compression) => match Compression::new(compression) {
+            Ok(compression) => Some(Arc::new(compression)),
+            Err(e) => {
+                println!("invalid compression config: {}", e);
+                return Ok(())
+            }
+        },
+        None => None
+    };
+
+    let usages = Arc::new(Usages::new());
+    let folders = match Folders::new(&config.folders, usages.clone()) {
+        Ok(folders) => Arc::new(folders),
+        Err(e) => {
+            println!("invalid folders config: {}", e);
+            return Ok(())
+        }
+    };
+
+    for (name, group) in &config.groups {
+        if let Some(folder) = group.folders.iter().find(|folder| !folders.contains(folder)) {
+            println!("invalid groups config: unknown folder {} in group {}", folder, name);
+            return Ok(())
+        }
+        if group.umask.is_some_and(|umask| umask > 0o777) {
+            println!("invalid groups config: invalid umask in group {}", name);
+            return Ok(())
+        }
+    }
+
+    if let Some(group) = config.permissions.iter().flat_map(|rule| &rule.groups).find(|group| !config.groups.contains_key(*group)) {
+        println!("invalid permissions config: unknown group {}", group);
+        return Ok(())
+    }
+    let permissions = match PermissionRules::new(&config.permissions) {
+        Ok(permissions) => Arc::new(permissions),
+        Err(e) => {
+            println!("invalid permissions config: {}", e);
+            return Ok(())
+        }
+    };
+
+    let mut server = SftpServer { pool: Arc::new(pool), config: config.clone(), policy, bucket, keys, blobs, compression, folders, usages, permissions };
 
     let russh_config = russh::server::Config {
         auth_rejection_time: Duration::from_secs(3),
@@ -0,0 +1,846 @@
+use std::{collections::{BTreeMap, HashMap, VecDeque}, io::{self, ErrorKind}, sync::{Arc, Mutex, MutexGuard}};
+
+use chrono::Utc;
+
+use crate::{config::MemoryConfig, jail, storage::{BoxFuture, DirEntry, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile}};
+
+const ROOT: u64 = 1;
+// same limit as linux
+const MAX_SYMLINKS: usize = 40;
+const BLOCK_SIZE: u64 = 4096;
+const NAME_MAX: u64 = 255;
+// files are kept whole in memory, holes included, so none can grow past this even without a max_size
+const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+// permission bits of the owner, the only ones checked since every file belongs to the session's user
+const READ: u32 = 0o400;
+const WRITE: u32 = 0o200;
+const EXEC: u32 = 0o100;
+
+fn os_error(code: i32) -> io::Error {
+    io::Error::from_raw_os_error(code)
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+enum Kind {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, u64>),
+    Symlink(String)
+}
+
+struct Node {
+    kind: Kind,
+    // permission bits only, the type comes from `kind`
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    atime: i64,
+    mtime: i64,
+    // names the node has, it is dropped once there are none and no handle has it open
+    nlink: u64,
+    open: u64
+}
+
+impl Node {
+    fn new(kind: Kind, mode: u32, owner: (u32, u32)) -> Self {
+        let time = now();
+        Node { kind, mode: mode & 0o7777, uid: owner.0, gid: owner.1, atime: time, mtime: time, nlink: 1, open: 0 }
+    }
+
+    fn check(&self, access: u32) -> io::Result<()> {
+        if self.mode & access != access {
+            return Err(io::Error::from(ErrorKind::PermissionDenied))
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> io::Result<&BTreeMap<String, u64>> {
+        match &self.kind {
+            Kind::Dir(entries) => Ok(entries),
+            _ => Err(os_error(libc::ENOTDIR))
+        }
+    }
+
+    fn entries_mut(&mut self) -> io::Result<&mut BTreeMap<String, u64>> {
+        match &mut self.kind {
+            Kind::Dir(entries) => Ok(entries),
+            _ => Err(os_error(libc::ENOTDIR))
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Dir(_))
+    }
+
+    fn apply(&mut self, attrs: &SetAttributes) {
+        if let Some(mode) = attrs.mode {
+            self.mode = mode & 0o7777;
+        }
+        if let Some(uid) = attrs.uid {
+            self.uid = uid;
+        }
+        if let Some(gid) = attrs.gid {
+            self.gid = gid;
+        }
+        if let Some(atime) = attrs.atime {
+            self.atime = atime;
+        }
+        if let Some(mtime) = attrs.mtime {
+            self.mtime = mtime;
+        }
+    }
+}
+
+// a resolved path, the directories leading to it and the node itself, by name and inode
+struct Resolved {
+    names: Vec<(String, u64)>
+}
+
+impl Resolved {
+    fn ino(&self) -> u64 {
+        self.names.last().map_or(ROOT, |(_, ino)| *ino)
+    }
+
+    fn parent(&self) -> u64 {
+        self.names.len().checked_sub(2).map_or(ROOT, |i| self.names[i].1)
+    }
+
+    fn path(&self) -> String {
+        format!("/{}", self.names.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join("/"))
+    }
+}
+
+struct Tree {
+    nodes: HashMap<u64, Node>,
+    next_ino: u64,
+    // bytes of file data stored
+    used: u64,
+    max_size: u64,
+    max_files: u64,
+    owner: (u32, u32)
+}
+
+impl Tree {
+    fn node(&self, ino: u64) -> io::Result<&Node> {
+        self.nodes.get(&ino).ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+    }
+
+    fn node_mut(&mut self, ino: u64) -> io::Result<&mut Node> {
+        self.nodes.get_mut(&ino).ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+    }
+
+    // walks a normalized path like the jail does, symlinks are followed but never outside the root
+    fn resolve(&self, path: &str, follow: bool) -> io::Result<Resolved> {
+        let mut pending: VecDeque<String> = path.split('/').filter(|name| !name.is_empty()).map(String::from).collect();
+        let mut resolved = Resolved { names: Vec::new() };
+        let mut links = 0;
+        while let Some(name) = pending.pop_front() {
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    if resolved.names.pop().is_none() {
+                        return Err(os_error(libc::EXDEV))
+                    }
+                    continue
+                }
+                _ => {}
+            }
+            let dir = self.node(resolved.ino())?;
+            let entries = dir.entries()?;
+            dir.check(EXEC)?;
+            let Some(&ino) = entries.get(&name) else {
+                return Err(io::Error::from(ErrorKind::NotFound))
+            };
+            match &self.node(ino)?.kind {
+                Kind::Symlink(target) if follow || !pending.is_empty() => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(os_error(libc::ELOOP))
+                    }
+                    if target.starts_with('/') {
+                        return Err(os_error(libc::EXDEV))
+                    }
+                    for part in target.split('/').rev().filter(|part| !part.is_empty()) {
+                        pending.push_front(part.to_string());
+                    }
+                }
+                _ => resolved.names.push((name, ino))
+            }
+        }
+        Ok(resolved)
+    }
+
+    // the directory a path is in and its final component, the directory has to be writable to change entries
+    fn parent(&self, path: &str, access: u32) -> io::Result<(u64, String)> {
+        let (dir, name) = jail::split(path);
+        if name.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "the root directory can't be changed"))
+        }
+        let dir = self.resolve(dir, true)?.ino();
+        let node = self.node(dir)?;
+        node.entries()?;
+        node.check(access)?;
+        Ok((dir, name.to_string()))
+    }
+
+    fn child(&self, dir: u64, name: &str) -> io::Result<Option<u64>> {
+        Ok(self.node(dir)?.entries()?.get(name).copied())
+    }
+
+    fn add(&mut self, dir: u64, name: String, node: Node) -> io::Result<u64> {
+        // the root doesn't count
+        if self.max_files != 0 && self.nodes.len() as u64 > self.max_files {
+            return Err(os_error(libc::ENOSPC))
+        }
+        if name.len() as u64 > NAME_MAX {
+            return Err(os_error(libc::ENAMETOOLONG))
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.nodes.insert(ino, node);
+        self.link(dir, name, ino)?;
+        Ok(ino)
+    }
+
+    fn link(&mut self, dir: u64, name: String, ino: u64) -> io::Result<()> {
+        let dir = self.node_mut(dir)?;
+        dir.entries_mut()?.insert(name, ino);
+        dir.mtime = now();
+        Ok(())
+    }
+
+    // removes a name, the node goes away with its last name unless it is still open
+    fn unlink(&mut self, dir: u64, name: &str) -> io::Result<()> {
+        let dir = self.node_mut(dir)?;
+        let Some(ino) = dir.entries_mut()?.remove(name) else {
+            return Err(io::Error::from(ErrorKind::NotFound))
+        };
+        dir.mtime = now();
+        let node = self.node_mut(ino)?;
+        node.nlink -= 1;
+        if node.nlink == 0 && node.open == 0 {
+            self.drop_node(ino);
+        }
+        Ok(())
+    }
+
+    fn drop_node(&mut self, ino: u64) {
+        if let Some(Node { kind: Kind::File(data), .. }) = self.nodes.remove(&ino) {
+            self.used -= data.len() as u64;
+        }
+    }
+
+    fn resize(&mut self, ino: u64, size: u64) -> io::Result<()> {
+        let (used, max_size) = (self.used, self.max_size);
+        let node = self.node_mut(ino)?;
+        let Kind::File(data) = &mut node.kind else {
+            return Err(os_error(libc::EISDIR))
+        };
+        let old = data.len() as u64;
+        if size > MAX_FILE_SIZE {
+            return Err(os_error(libc::EFBIG))
+        }
+        if size > old && max_size != 0 && used.saturating_add(size - old) > max_size {
+            return Err(os_error(libc::ENOSPC))
+        }
+        data.resize(size as usize, 0);
+        node.mtime = now();
+        self.used = used + size - old;
+        Ok(())
+    }
+
+    fn stat(&self, ino: u64) -> io::Result<FileStat> {
+        let node = self.node(ino)?;
+        let (kind, size, nlink) = match &node.kind {
+            Kind::File(data) => (libc::S_IFREG, data.len() as u64, node.nlink),
+            // like on disk, every subdirectory's `..` is a link to its parent
+            Kind::Dir(entries) => {
+                let subdirs = entries.values().filter(|ino| self.nodes.get(ino).is_some_and(Node::is_dir)).count();
+                (libc::S_IFDIR, BLOCK_SIZE, 2 + subdirs as u64)
+            }
+            Kind::Symlink(target) => (libc::S_IFLNK, target.len() as u64, node.nlink)
+        };
+        Ok(FileStat { size, mode: kind | node.mode, uid: node.uid, gid: node.gid, nlink, atime: node.atime, mtime: node.mtime })
+    }
+
+    fn statvfs(&self) -> FsStats {
+        let blocks = if self.max_size == 0 { u64::MAX / BLOCK_SIZE } else { self.max_size / BLOCK_SIZE };
+        let blocks_free = blocks.saturating_sub(self.used.div_ceil(BLOCK_SIZE));
+        let files = if self.max_files == 0 { u64::MAX } else { self.max_files };
+        let files_free = files.saturating_sub(self.nodes.len() as u64 - 1);
+        FsStats {
+            block_size: BLOCK_SIZE,
+            fragment_size: BLOCK_SIZE,
+            blocks,
+            blocks_free,
+            blocks_avail: blocks_free,
+            files,
+            files_free,
+            files_avail: files_free,
+            fs_id: 0,
+            read_only: false,
+            no_suid: true,
+            name_max: NAME_MAX
+        }
+    }
+}
+
+/// A filesystem kept entirely in memory, it lives as long as the last session or file using it
+pub(crate) struct MemoryBackend {
+    tree: Arc<Mutex<Tree>>
+}
+
+impl MemoryBackend {
+    /// An empty filesystem owned by the server's user
+    pub(crate) fn new(config: &MemoryConfig) -> Self {
+        let owner = unsafe { (libc::geteuid(), libc::getegid()) };
+        let mut nodes = HashMap::new();
+        nodes.insert(ROOT, Node::new(Kind::Dir(BTreeMap::new()), 0o755, owner));
+        let tree = Tree { nodes, next_ino: ROOT + 1, used: 0, max_size: config.max_size, max_files: config.max_files, owner };
+        MemoryBackend { tree: Arc::new(Mutex::new(tree)) }
+    }
+
+    fn tree(&self) -> MutexGuard<'_, Tree> {
+        lock(&self.tree)
+    }
+}
+
+// nothing can be left half changed by a panic while the lock is held, so a poisoned lock is still usable
+fn lock(tree: &Mutex<Tree>) -> MutexGuard<'_, Tree> {
+    tree.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl StorageBackend for MemoryBackend {
+    fn owner(&self) -> io::Result<(u32, u32)> {
+        Ok(self.tree().owner)
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(async move {
+            let path = jail::normalize(path);
+            let tree = self.tree();
+            match tree.resolve(&path, true) {
+                Ok(resolved) => Ok(resolved.path()),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    let (dir, name) = jail::split(&path);
+                    let dir = tree.resolve(dir, true)?.path();
+                    Ok(if dir == "/" { format!("/{}", name) } else { format!("{}/{}", dir, name) })
+                }
+                Err(e) => Err(e)
+            }
+        })
+    }
+
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            let path = jail::normalize(path);
+            let mut tree = self.tree();
+            let writing = options.write || options.append;
+            let ino = match tree.resolve(&path, true) {
+                Ok(_) if options.create && options.exclusive => return Err(io::Error::from(ErrorKind::AlreadyExists)),
+                Ok(resolved) => {
+                    let ino = resolved.ino();
+                    let node = tree.node(ino)?;
+                    if !matches!(node.kind, Kind::File(_)) {
+                        return Err(os_error(libc::EISDIR))
+                    }
+                    node.check(if options.read { READ } else { 0 } | if writing { WRITE } else { 0 })?;
+                    if options.truncate && writing {
+                        tree.resize(ino, 0)?;
+                    }
+                    ino
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound && options.create => {
+                    let (dir, name) = tree.parent(&path, WRITE | EXEC)?;
+                    // a dangling symlink as the final component
+                    if tree.child(dir, &name)?.is_some() {
+                        return Err(io::Error::from(ErrorKind::NotFound))
+                    }
+                    let owner = tree.owner;
+                    tree.add(dir, name, Node::new(Kind::File(Vec::new()), mode, owner))?
+                }
+                Err(e) => return Err(e)
+            };
+            tree.node_mut(ino)?.open += 1;
+            let file = MemoryFile { tree: self.tree.clone(), ino, read: options.read, write: writing, append: options.append };
+            Ok(Box::new(file) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+        Box::pin(async move {
+            let tree = self.tree();
+            tree.stat(tree.resolve(&jail::normalize(path), follow)?.ino())
+        })
+    }
+
+    fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, follow: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut tree = self.tree();
+            let ino = tree.resolve(&jail::normalize(path), follow)?.ino();
+            let node = tree.node(ino)?;
+            if matches!(node.kind, Kind::Symlink(_)) && (attrs.size.is_some() || attrs.mode.is_some()) {
+                return Err(os_error(libc::EOPNOTSUPP))
+            }
+            if let Some(size) = attrs.size {
+                node.check(WRITE)?;
+                tree.resize(ino, size)?;
+            }
+            tree.node_mut(ino)?.apply(&attrs);
+            Ok(())
+        })
+    }
+
+    // entries are kept ordered by name, so listings are always sorted
+    fn list<'a>(&'a self, path: &'a str, _sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+        Box::pin(async move {
+            let tree = self.tree();
+            let resolved = tree.resolve(&jail::normalize(path), true)?;
+            let node = tree.node(resolved.ino())?;
+            let entries = node.entries()?;
+            node.check(READ)?;
+            let mut listing = VecDeque::from([
+                DirEntry { name: String::from("."), stat: tree.stat(resolved.ino())? },
+                DirEntry { name: String::from(".."), stat: tree.stat(resolved.parent())? }
+            ]);
+            let limit = if limit == 0 { usize::MAX } else { limit };
+            for (name, &ino) in entries.iter().take(limit) {
+                listing.push_back(DirEntry { name: name.clone(), stat: tree.stat(ino)? });
+            }
+            Ok(Box::new(MemoryDir { tree: self.tree.clone(), ino: resolved.ino(), entries: listing }) as Box<dyn DirStream>)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut tree = self.tree();
+            let (dir, name) = tree.parent(&jail::normalize(path), WRITE | EXEC)?;
+            let Some(ino) = tree.child(dir, &name)? else {
+                return Err(io::Error::from(ErrorKind::NotFound))
+            };
+            if tree.node(ino)?.is_dir() {
+                return Err(os_error(libc::EISDIR))
+            }
+            tree.unlink(dir, &name)
+        })
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut tree = self.tree();
+            let (dir, name) = tree.parent(&jail::normalize(path), WRITE | EXEC)?;
+            if tree.child(dir, &name)?.is_some() {
+                return Err(io::Error::from(ErrorKind::AlreadyExists))
+            }
+            let owner = tree.owner;
+            tree.add(dir, name, Node::new(Kind::Dir(BTreeMap::new()), mode, owner)).map(|_| ())
+        })
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut tree = self.tree();
+            let (dir, name) = tree.parent(&jail::normalize(path), WRITE | EXEC)?;
+            let Some(ino) = tree.child(dir, &name)? else {
+                return Err(io::Error::from(ErrorKind::NotFound))
+            };
+            if !tree.node(ino)?.entries()?.is_empty() {
+                return Err(io::Error::from(ErrorKind::DirectoryNotEmpty))
+            }
+            tree.unlink(dir, &name)
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (from, to) = (jail::normalize(from), jail::normalize(to));
+            let mut tree = self.tree();
+            let (from_dir, from_name) = tree.parent(&from, WRITE | EXEC)?;
+            let (to_dir, to_name) = tree.parent(&to, WRITE | EXEC)?;
+            let Some(ino) = tree.child(from_dir, &from_name)? else {
+                return Err(io::Error::from(ErrorKind::NotFound))
+            };
+            let is_dir = tree.node(ino)?.is_dir();
+            if is_dir && tree.resolve(jail::split(&to).0, true)?.names.iter().any(|(_, dir)| *dir == ino) {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "can't move a directory into itself"))
+            }
+            if let Some(existing) = tree.child(to_dir, &to_name)? {
+                // two names of the same file, rename does nothing
+                if existing == ino {
+                    return Ok(())
+                }
+                if !replace {
+                    return Err(io::Error::from(ErrorKind::AlreadyExists))
+                }
+                let existing = tree.node(existing)?;
+                match (is_dir, existing.is_dir()) {
+                    (true, false) => return Err(os_error(libc::ENOTDIR)),
+                    (false, true) => return Err(os_error(libc::EISDIR)),
+                    (true, true) if !existing.entries()?.is_empty() => return Err(io::Error::from(ErrorKind::DirectoryNotEmpty)),
+                    _ => {}
+                }
+                tree.unlink(to_dir, &to_name)?;
+            }
+            let from_node = tree.node_mut(from_dir)?;
+            from_node.entries_mut()?.remove(&from_name);
+            from_node.mtime = now();
+            tree.link(to_dir, to_name, ino)
+        })
+    }
+
+    fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+        Box::pin(async move {
+            let tree = self.tree();
+            tree.resolve(&jail::normalize(path), true)?;
+            Ok(tree.statvfs())
+        })
+    }
+
+    fn read_link<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(async move {
+            let tree = self.tree();
+            let (dir, name) = tree.parent(&jail::normalize(path), EXEC)?;
+            let Some(ino) = tree.child(dir, &name)? else {
+                return Err(io::Error::from(ErrorKind::NotFound))
+            };
+            match &tree.node(ino)?.kind {
+                Kind::Symlink(target) => Ok(target.clone()),
+                _ => Err(os_error(libc::EINVAL))
+            }
+        })
+    }
+
+    fn symlink<'a>(&'a self, target: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut tree = self.tree();
+            let (dir, name) = tree.parent(&jail::normalize(path), WRITE | EXEC)?;
+            if tree.child(dir, &name)?.is_some() {
+                return Err(io::Error::from(ErrorKind::AlreadyExists))
+            }
+            let owner = tree.owner;
+            tree.add(dir, name, Node::new(Kind::Symlink(target.to_string()), 0o777, owner)).map(|_| ())
+        })
+    }
+
+    fn hard_link<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut tree = self.tree();
+            let ino = tree.resolve(&jail::normalize(from), false)?.ino();
+            if tree.node(ino)?.is_dir() {
+                return Err(io::Error::from(ErrorKind::PermissionDenied))
+            }
+            let (dir, name) = tree.parent(&jail::normalize(to), WRITE | EXEC)?;
+            if tree.child(dir, &name)?.is_some() {
+                return Err(io::Error::from(ErrorKind::AlreadyExists))
+            }
+            tree.link(dir, name, ino)?;
+            tree.node_mut(ino)?.nlink += 1;
+            Ok(())
+        })
+    }
+}
+
+/// A file of a memory backend, it keeps its data alive after being deleted until it is dropped
+struct MemoryFile {
+    tree: Arc<Mutex<Tree>>,
+    ino: u64,
+    read: bool,
+    write: bool,
+    append: bool
+}
+
+impl StorageFile for MemoryFile {
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            if !self.read {
+                return Err(os_error(libc::EBADF))
+            }
+            let tree = lock(&self.tree);
+            let Kind::File(data) = &tree.node(self.ino)?.kind else {
+                return Err(os_error(libc::EISDIR))
+            };
+            let start = (offset as usize).min(data.len());
+            let end = start.saturating_add(len).min(data.len());
+            Ok(data[start..end].to_vec())
+        })
+    }
+
+    // writes past the end leave a hole of zeros, like a sparse file
+    fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)> {
+        Box::pin(async move {
+            if !self.write {
+                return (data, Err(os_error(libc::EBADF)))
+            }
+            let mut tree = lock(&self.tree);
+            let size = match tree.stat(self.ino) {
+                Ok(st) => st.size,
+                Err(e) => return (data, Err(e))
+            };
+            let offset = if self.append { size } else { offset };
+            let Some(end) = offset.checked_add(data.len() as u64) else {
+                return (data, Err(os_error(libc::EFBIG)))
+            };
+            if let Err(e) = tree.resize(self.ino, end.max(size)) {
+                return (data, Err(e))
+            }
+            let node = match tree.node_mut(self.ino) {
+                Ok(node) => node,
+                Err(e) => return (data, Err(e))
+            };
+            if let Kind::File(contents) = &mut node.kind {
+                contents[offset as usize..end as usize].copy_from_slice(&data);
+            }
+            node.mtime = now();
+            (data, Ok(()))
+        })
+    }
+
+    fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>> {
+        Box::pin(async move { lock(&self.tree).stat(self.ino) })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let mut tree = lock(&self.tree);
+            if let Some(size) = attrs.size {
+                if !self.write {
+                    return Err(os_error(libc::EINVAL))
+                }
+                tree.resize(self.ino, size)?;
+            }
+            tree.node_mut(self.ino)?.apply(&attrs);
+            Ok(())
+        })
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        Box::pin(async move { Ok(lock(&self.tree).statvfs()) })
+    }
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        let mut tree = lock(&self.tree);
+        let Ok(node) = tree.node_mut(self.ino) else {
+            return
+        };
+        node.open -= 1;
+        if node.open == 0 && node.nlink == 0 {
+            tree.drop_node(self.ino);
+        }
+    }
+}
+
+/// Directory listing of a memory backend, taken when the directory is opened
+struct MemoryDir {
+    tree: Arc<Mutex<Tree>>,
+    ino: u64,
+    entries: VecDeque<DirEntry>
+}
+
+impl DirStream for MemoryDir {
+    fn next_entries(&mut self, count: usize) -> BoxFuture<'_, io::Result<Vec<DirEntry>>> {
+        let entries = self.entries.drain(..count.min(self.entries.len())).collect();
+        Box::pin(async move { Ok(entries) })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if attrs.size.is_some() {
+                return Err(os_error(libc::EISDIR))
+            }
+            lock(&self.tree).node_mut(self.ino)?.apply(&attrs);
+            Ok(())
+        })
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        Box::pin(async move { Ok(lock(&self.tree).statvfs()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(max_size: u64, max_files: u64) -> MemoryBackend {
+        MemoryBackend::new(&MemoryConfig { max_size, max_files })
+    }
+
+    fn error<T>(result: io::Result<T>) -> io::Error {
+        match result {
+            Err(e) => e,
+            Ok(_) => panic!("expected an error")
+        }
+    }
+
+    fn create() -> OpenOptions {
+        OpenOptions { read: true, write: true, create: true, ..Default::default() }
+    }
+
+    async fn write(backend: &MemoryBackend, path: &str, offset: u64, data: &[u8]) -> io::Result<()> {
+        let file = backend.open(path, create(), 0o644).await?;
+        file.write_at(offset, data.to_vec()).await.1
+    }
+
+    async fn read(backend: &MemoryBackend, path: &str) -> Vec<u8> {
+        let file = backend.open(path, OpenOptions { read: true, ..Default::default() }, 0).await.unwrap();
+        file.read_at(0, usize::MAX).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn sparse_writes() {
+        let backend = backend(0, 0);
+        write(&backend, "sparse", 8, b"end").await.unwrap();
+        assert_eq!(read(&backend, "sparse").await, b"\0\0\0\0\0\0\0\0end");
+        write(&backend, "sparse", 2, b"mid").await.unwrap();
+        assert_eq!(read(&backend, "sparse").await, b"\0\0mid\0\0\0end");
+        assert_eq!(backend.stat("sparse", true).await.unwrap().size, 11);
+        let file = backend.open("sparse", create(), 0).await.unwrap();
+        assert!(file.read_at(100, 10).await.unwrap().is_empty());
+        file.set_attributes(SetAttributes { size: Some(4), ..Default::default() }).await.unwrap();
+        assert_eq!(read(&backend, "sparse").await, b"\0\0mi");
+        assert_eq!(backend.statvfs("").await.unwrap().blocks_free, u64::MAX / BLOCK_SIZE - 1);
+    }
+
+    #[tokio::test]
+    async fn sizes_out_of_range() {
+        let backend = backend(0, 0);
+        let file = backend.open("big", create(), 0o644).await.unwrap();
+        assert_eq!(file.write_at(u64::MAX - 1, b"data".to_vec()).await.1.unwrap_err().raw_os_error(), Some(libc::EFBIG));
+        assert_eq!(file.write_at(MAX_FILE_SIZE, b"x".to_vec()).await.1.unwrap_err().raw_os_error(), Some(libc::EFBIG));
+        let truncate = SetAttributes { size: Some(u64::MAX), ..Default::default() };
+        assert_eq!(file.set_attributes(truncate).await.unwrap_err().raw_os_error(), Some(libc::EFBIG));
+        assert_eq!(backend.set_attributes("big", truncate, true).await.unwrap_err().raw_os_error(), Some(libc::EFBIG));
+        assert_eq!(file.stat().await.unwrap().size, 0);
+    }
+
+    #[tokio::test]
+    async fn size_limit() {
+        let backend = backend(10, 0);
+        write(&backend, "a", 0, b"12345678").await.unwrap();
+        assert_eq!(error(write(&backend, "b", 0, b"123").await).raw_os_error(), Some(libc::ENOSPC));
+        // holes count as well
+        assert_eq!(error(write(&backend, "b", 5, b"x").await).raw_os_error(), Some(libc::ENOSPC));
+        write(&backend, "b", 0, b"12").await.unwrap();
+        // deleted files keep their space while they are open
+        let file = backend.open("a", OpenOptions { read: true, ..Default::default() }, 0).await.unwrap();
+        backend.remove_file("a").await.unwrap();
+        assert_eq!(error(write(&backend, "c", 0, b"1").await).raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(file.read_at(0, 100).await.unwrap(), b"12345678");
+        drop(file);
+        write(&backend, "c", 0, b"12345678").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_limit() {
+        let backend = backend(0, 3);
+        backend.create_dir("dir", 0o755).await.unwrap();
+        write(&backend, "dir/file", 0, b"").await.unwrap();
+        backend.symlink("file", "dir/link").await.unwrap();
+        assert_eq!(error(backend.create_dir("other", 0o755).await).raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(error(write(&backend, "file", 0, b"").await).raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(error(backend.symlink("dir", "link").await).raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(backend.statvfs("").await.unwrap().files_free, 0);
+        // hard links are names of a file already counted
+        backend.hard_link("dir/file", "hard").await.unwrap();
+        backend.remove_file("dir/link").await.unwrap();
+        backend.create_dir("other", 0o755).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        let backend = backend(0, 0);
+        backend.create_dir("dir", 0o755).await.unwrap();
+        backend.create_dir("dir/sub", 0o755).await.unwrap();
+        write(&backend, "a", 0, b"a").await.unwrap();
+        write(&backend, "b", 0, b"b").await.unwrap();
+        assert_eq!(error(backend.rename("a", "b", false).await).kind(), ErrorKind::AlreadyExists);
+        backend.rename("a", "b", true).await.unwrap();
+        assert_eq!(read(&backend, "b").await, b"a");
+        assert_eq!(error(backend.stat("a", true).await).kind(), ErrorKind::NotFound);
+        backend.rename("b", "dir/sub/c", false).await.unwrap();
+        assert_eq!(read(&backend, "dir/sub/c").await, b"a");
+        assert_eq!(error(backend.rename("dir", "dir/sub/dir", false).await).kind(), ErrorKind::InvalidInput);
+        assert_eq!(error(backend.rename("dir/sub/c", "dir", true).await).raw_os_error(), Some(libc::EISDIR));
+        backend.create_dir("empty", 0o755).await.unwrap();
+        assert_eq!(error(backend.rename("empty", "dir", true).await).kind(), ErrorKind::DirectoryNotEmpty);
+        backend.rename("dir", "empty", true).await.unwrap();
+        assert_eq!(read(&backend, "empty/sub/c").await, b"a");
+        // two names of the same file stay as they are
+        backend.hard_link("empty/sub/c", "d").await.unwrap();
+        backend.rename("d", "empty/sub/c", true).await.unwrap();
+        assert_eq!(backend.stat("d", true).await.unwrap().nlink, 2);
+        assert_eq!(backend.canonicalize("empty/sub/../sub/c").await.unwrap(), "/empty/sub/c");
+    }
+
+    #[tokio::test]
+    async fn permissions() {
+        let backend = backend(0, 0);
+        write(&backend, "file", 0, b"data").await.unwrap();
+        backend.set_attributes("file", SetAttributes { mode: Some(0o200), ..Default::default() }, true).await.unwrap();
+        assert_eq!(error(backend.open("file", OpenOptions { read: true, ..Default::default() }, 0).await).kind(), ErrorKind::PermissionDenied);
+        backend.open("file", OpenOptions { write: true, ..Default::default() }, 0).await.unwrap();
+        backend.set_attributes("file", SetAttributes { mode: Some(0o400), ..Default::default() }, true).await.unwrap();
+        assert_eq!(error(backend.open("file", OpenOptions { write: true, ..Default::default() }, 0).await).kind(), ErrorKind::PermissionDenied);
+        assert_eq!(error(backend.set_attributes("file", SetAttributes { size: Some(0), ..Default::default() }, true).await).kind(), ErrorKind::PermissionDenied);
+        // handles opened for reading can't change the size either
+        let file = backend.open("file", OpenOptions { read: true, ..Default::default() }, 0).await.unwrap();
+        assert_eq!(file.write_at(0, b"x".to_vec()).await.1.unwrap_err().raw_os_error(), Some(libc::EBADF));
+        assert_eq!(error(file.set_attributes(SetAttributes { size: Some(0), ..Default::default() }).await).raw_os_error(), Some(libc::EINVAL));
+
+        backend.create_dir("dir", 0o755).await.unwrap();
+        write(&backend, "dir/inside", 0, b"").await.unwrap();
+        backend.set_attributes("dir", SetAttributes { mode: Some(0o500), ..Default::default() }, true).await.unwrap();
+        assert_eq!(error(write(&backend, "dir/new", 0, b"").await).kind(), ErrorKind::PermissionDenied);
+        assert_eq!(error(backend.remove_file("dir/inside").await).kind(), ErrorKind::PermissionDenied);
+        assert_eq!(error(backend.rename("dir/inside", "moved", false).await).kind(), ErrorKind::PermissionDenied);
+        backend.set_attributes("dir", SetAttributes { mode: Some(0o300), ..Default::default() }, true).await.unwrap();
+        assert_eq!(error(backend.list("dir", true, 0).await).kind(), ErrorKind::PermissionDenied);
+        backend.set_attributes("dir", SetAttributes { mode: Some(0o600), ..Default::default() }, true).await.unwrap();
+        assert_eq!(error(backend.stat("dir/inside", true).await).kind(), ErrorKind::PermissionDenied);
+
+        // symlinks never lead out of the root
+        backend.symlink("/etc", "abs").await.unwrap();
+        backend.symlink("../..", "up").await.unwrap();
+        assert_eq!(error(backend.stat("abs", true).await).raw_os_error(), Some(libc::EXDEV));
+        assert_eq!(error(backend.stat("up/etc", true).await).raw_os_error(), Some(libc::EXDEV));
+    }
+
+    #[tokio::test]
+    async fn timestamps() {
+        let backend = backend(0, 0);
+        backend.create_dir("dir", 0o755).await.unwrap();
+        write(&backend, "dir/file", 0, b"data").await.unwrap();
+        let old = SetAttributes { atime: Some(1000), mtime: Some(2000), ..Default::default() };
+        backend.set_attributes("dir/file", old, true).await.unwrap();
+        backend.set_attributes("dir", old, true).await.unwrap();
+        let st = backend.stat("dir/file", true).await.unwrap();
+        assert_eq!((st.atime, st.mtime), (1000, 2000));
+        // reads leave the times alone, writes and truncates change mtime
+        read(&backend, "dir/file").await;
+        assert_eq!(backend.stat("dir/file", true).await.unwrap().mtime, 2000);
+        write(&backend, "dir/file", 0, b"x").await.unwrap();
+        let st = backend.stat("dir/file", true).await.unwrap();
+        assert!(st.mtime >= now() - 5);
+        assert_eq!(st.atime, 1000);
+        backend.set_attributes("dir/file", old, true).await.unwrap();
+        backend.set_attributes("dir/file", SetAttributes { size: Some(1), ..Default::default() }, true).await.unwrap();
+        assert!(backend.stat("dir/file", true).await.unwrap().mtime >= now() - 5);
+        // changing the entries of a directory changes its mtime
+        assert_eq!(backend.stat("dir", true).await.unwrap().mtime, 2000);
+        backend.rename("dir/file", "dir/renamed", false).await.unwrap();
+        assert!(backend.stat("dir", true).await.unwrap().mtime >= now() - 5);
+        // the times of a symlink itself can be set without following it
+        backend.symlink("dir", "link").await.unwrap();
+        backend.set_attributes("link", old, false).await.unwrap();
+        assert_eq!(backend.stat("link", false).await.unwrap().mtime, 2000);
+        assert!(backend.stat("link", true).await.unwrap().mtime >= now() - 5);
+    }
+}
@@ -0,0 +1,118 @@
+use std::{io::{self, ErrorKind}, sync::Arc};
+
+use crate::{config::{Permission, PermissionConfig}, glob::Glob, jail, storage::StorageBackend};
+
+fn not_permitted(permission: Permission, path: &str) -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, format!("{} not permitted on /{}", String::from(permission), path))
+}
+
+struct Rule {
+    users: Vec<String>,
+    groups: Vec<String>,
+    paths: Vec<Glob>,
+    allow: Vec<Permission>,
+    deny: Vec<Permission>
+}
+
+impl Rule {
+    fn applies_to(&self, user: &str, groups: &[String]) -> bool {
+        (self.users.is_empty() && self.groups.is_empty()) || self.users.iter().any(|u| u == user) || self.groups.iter().any(|group| groups.contains(group))
+    }
+
+    // Some(true) if the rule allows the permission on the path, Some(false) if it denies it
+    fn decide(&self, permission: Permission, path: &str) -> Option<bool> {
+        if !self.paths.is_empty() && !self.paths.iter().any(|glob| glob.matches(path)) {
+            return None
+        }
+        if self.deny.iter().any(|p| p.covers(permission)) {
+            return Some(false)
+        }
+        if self.allow.iter().any(|p| p.covers(permission)) {
+            return Some(true)
+        }
+        None
+    }
+}
+
+/// The permission rules from the config file
+pub(crate) struct PermissionRules {
+    rules: Vec<Arc<Rule>>
+}
+
+impl PermissionRules {
+    pub(crate) fn new(rules: &[PermissionConfig]) -> Result<Self, String> {
+        let mut compiled = Vec::new();
+        for rule in rules {
+            if let Some(permission) = rule.allow.iter().find(|p| rule.deny.contains(p)) {
+                return Err(format!("{} is both allowed and denied by the same rule", String::from(*permission)))
+            }
+            let paths = rule.paths.iter().map(|path| Glob::new(path)).collect::<Result<_, _>>()?;
+            compiled.push(Arc::new(Rule { users: rule.users.clone(), groups: rule.groups.clone(), paths, allow: rule.allow.clone(), deny: rule.deny.clone() }));
+        }
+        Ok(PermissionRules { rules: compiled })
+    }
+
+    /// The rules that apply to a user in `groups`
+    pub(crate) fn for_user(&self, user: &str, groups: &[String]) -> Permissions {
+        Permissions { rules: self.rules.iter().filter(|rule| rule.applies_to(user, groups)).cloned().collect(), hide_files: false }
+    }
+}
+
+/// What a user is allowed to do where, everything not denied by a rule is allowed
+#[derive(Clone)]
+pub(crate) struct Permissions {
+    rules: Vec<Arc<Rule>>,
+    // drop box users can't see anything but directories
+    hide_files: bool
+}
+
+impl Permissions {
+    /// Turns the account into a drop box, files can be uploaded and directories created but nothing can be read,
+    /// listed or changed, whatever other rules say
+    pub(crate) fn drop_box(mut self, overwrite: bool) -> Self {
+        let mut allow = vec![Permission::Upload, Permission::Mkdir];
+        let mut deny = vec![Permission::List, Permission::Download, Permission::Delete, Permission::Rename, Permission::Symlink, Permission::Chmod, Permission::Chtimes];
+        if overwrite { allow.push(Permission::Overwrite) } else { deny.push(Permission::Overwrite) }
+        self.rules.push(Arc::new(Rule { users: Vec::new(), groups: Vec::new(), paths: Vec::new(), allow, deny }));
+        self.hide_files = true;
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether attributes of anything but directories are kept from the user
+    pub(crate) fn hides_files(&self) -> bool {
+        self.hide_files
+    }
+
+    // the last rule that matches the path and names the permission decides
+    fn allows(&self, permission: Permission, path: &str) -> bool {
+        self.rules.iter().rev().find_map(|rule| rule.decide(permission, path)).unwrap_or(true)
+    }
+
+    /// Fails with PermissionDenied unless the permission is granted on an absolute path. paths going through
+    /// symlinks need the permission where they lead as well
+    pub(crate) async fn check(&self, storage: &dyn StorageBackend, permission: Permission, path: &str) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(())
+        }
+        let path = jail::normalize(path);
+        if !self.allows(permission, &path) {
+            return Err(not_permitted(permission, &path))
+        }
+        // files that don't exist yet are resolved through their directory
+        let resolved = match storage.canonicalize(&path).await {
+            Ok(resolved) => Some(jail::normalize(&resolved)),
+            Err(_) => {
+                let (parent, name) = jail::split(&path);
+                storage.canonicalize(parent).await.ok().map(|parent| jail::normalize(&format!("{}/{}", parent, name)))
+            }
+        };
+        match resolved {
+            Some(resolved) if resolved != path && !self.allows(permission, &resolved) => Err(not_permitted(permission, &resolved)),
+            _ => Ok(())
+        }
+    }
+}
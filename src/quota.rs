@@ -0,0 +1,543 @@
+use std::{collections::HashMap, io::{self, ErrorKind}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex as StdMutex, Weak}};
+
+use tokio::sync::OnceCell;
+
+use crate::{jail, storage::{BoxFuture, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile}};
+
+// entries counted at once while adding up what a directory tree uses
+const COUNT_BATCH: usize = 256;
+
+fn quota_exceeded() -> io::Error {
+    io::Error::from_raw_os_error(libc::EDQUOT)
+}
+
+// adds up the sizes of the files and the number of entries below `dir`
+fn count<'a>(backend: &'a dyn StorageBackend, dir: String) -> BoxFuture<'a, io::Result<(u64, u64)>> {
+    Box::pin(async move {
+        let mut stream = backend.list(&dir, false, 0).await?;
+        let (mut size, mut files) = (0, 0);
+        loop {
+            let entries = stream.next_entries(COUNT_BATCH).await?;
+            if entries.is_empty() {
+                return Ok((size, files))
+            }
+            for entry in entries.into_iter().filter(|entry| entry.name != "." && entry.name != "..") {
+                files += 1;
+                if entry.stat.is_file() {
+                    size += entry.stat.size;
+                }
+                else if entry.stat.is_dir() {
+                    let (dir_size, dir_files) = count(backend, format!("{}/{}", dir, entry.name)).await?;
+                    size += dir_size;
+                    files += dir_files;
+                }
+            }
+        }
+    })
+}
+
+/// Bytes and entries used below a directory. They are counted the first time a quota needs them and kept up to
+/// date from then on by every session writing there, changes made by anything but the server aren't noticed
+pub(crate) struct Usage {
+    counted: OnceCell<()>,
+    size: AtomicU64,
+    files: AtomicU64,
+    // charges of the files open for writing, by canonical path
+    open: StdMutex<HashMap<String, Weak<Charge>>>
+}
+
+impl Usage {
+    pub(crate) fn new() -> Self {
+        Usage { counted: OnceCell::new(), size: AtomicU64::new(0), files: AtomicU64::new(0), open: StdMutex::new(HashMap::new()) }
+    }
+
+    // the charge of a file being opened, `size` is what is counted for it if no handle has it open yet
+    fn charge(self: &Arc<Self>, key: &str, size: u64) -> Arc<Charge> {
+        let mut open = self.open.lock().unwrap();
+        if let Some(charge) = open.get(key).and_then(Weak::upgrade) {
+            return charge
+        }
+        open.retain(|_, charge| charge.strong_count() > 0);
+        let charge = Arc::new(Charge { usage: self.clone(), size: StdMutex::new(size), removed: AtomicBool::new(false) });
+        open.insert(key.to_string(), Arc::downgrade(&charge));
+        charge
+    }
+
+    fn open_charge(&self, key: &str) -> Option<Arc<Charge>> {
+        self.open.lock().unwrap().get(key).and_then(Weak::upgrade)
+    }
+
+    // the file at `key` lost its name, it is counted until its last handle is closed. false if none is open
+    fn detach(&self, key: &str) -> bool {
+        let Some(charge) = self.open.lock().unwrap().remove(key).and_then(|charge| charge.upgrade()) else {
+            return false
+        };
+        charge.removed.store(true, Ordering::Relaxed);
+        true
+    }
+
+    // open files below a renamed path keep their charges under the new one
+    fn rename_charges(&self, from: &str, to: &str) {
+        let mut open = self.open.lock().unwrap();
+        let below = format!("{}/", from);
+        let moved: Vec<String> = open.keys().filter(|key| *key == from || key.starts_with(&below)).cloned().collect();
+        for key in moved {
+            if let Some(charge) = open.remove(&key) {
+                open.insert(format!("{}{}", to, &key[from.len()..]), charge);
+            }
+        }
+    }
+
+    // takes `amount` more of a counter, unless that would go over `max`. a `max` of 0 means no limit
+    fn take(counter: &AtomicU64, amount: u64, max: u64) -> io::Result<()> {
+        counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| match used.checked_add(amount) {
+            Some(total) if max == 0 || total <= max => Some(total),
+            _ => None
+        }).map(|_| ()).map_err(|_| quota_exceeded())
+    }
+
+    fn give_back(counter: &AtomicU64, amount: u64) {
+        let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| Some(used.saturating_sub(amount)));
+    }
+}
+
+/// The usage of every directory with a quota, so sessions writing to the same directory share it
+pub(crate) struct Usages {
+    usage: StdMutex<HashMap<String, Arc<Usage>>>
+}
+
+impl Usages {
+    pub(crate) fn new() -> Self {
+        Usages { usage: StdMutex::new(HashMap::new()) }
+    }
+
+    pub(crate) fn get(&self, dir: &str) -> Arc<Usage> {
+        self.usage.lock().unwrap().entry(dir.to_string()).or_insert_with(|| Arc::new(Usage::new())).clone()
+    }
+}
+
+/// What the quota counts for a file open for writing, shared by all of its handles so a handle truncating it
+/// or another one writing to it is noticed by the rest
+struct Charge {
+    usage: Arc<Usage>,
+    size: StdMutex<u64>,
+    // set once the file has no name anymore, its bytes are given back when the last handle goes away
+    removed: AtomicBool
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        if self.removed.load(Ordering::Relaxed) {
+            Usage::give_back(&self.usage.size, *self.size.get_mut().unwrap());
+        }
+    }
+}
+
+/// Limits the bytes and entries (files, directories and symlinks) stored in another backend
+pub(crate) struct QuotaBackend {
+    inner: Arc<dyn StorageBackend>,
+    usage: Arc<Usage>,
+    max_size: u64,
+    max_files: u64
+}
+
+impl QuotaBackend {
+    /// A limit of 0 means no limit
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>, usage: Arc<Usage>, max_size: u64, max_files: u64) -> Self {
+        QuotaBackend { inner, usage, max_size, max_files }
+    }
+
+    async fn usage(&self) -> io::Result<&Usage> {
+        self.usage.counted.get_or_try_init(|| async {
+            let (size, files) = count(&*self.inner, String::new()).await?;
+            self.usage.size.store(size, Ordering::Relaxed);
+            self.usage.files.store(files, Ordering::Relaxed);
+            Ok::<_, io::Error>(())
+        }).await?;
+        Ok(&self.usage)
+    }
+
+    // makes room for one more entry with `size` bytes, taken back if the operation fails
+    async fn add_entry<T>(&self, size: u64, operation: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+        let usage = self.usage().await?;
+        Usage::take(&usage.files, 1, self.max_files)?;
+        if let Err(e) = Usage::take(&usage.size, size, self.max_size) {
+            Usage::give_back(&usage.files, 1);
+            return Err(e)
+        }
+        let result = operation.await;
+        if result.is_err() {
+            Usage::give_back(&usage.files, 1);
+            Usage::give_back(&usage.size, size);
+        }
+        result
+    }
+
+    // open files are known by their canonical path, so links to them and renames find the same charge
+    // a file that doesn't exist yet by its canonical parent
+    async fn key(&self, path: &str, follow: bool) -> String {
+        let resolved = if follow { self.inner.canonicalize(path).await.ok() } else { None };
+        if let Some(resolved) = resolved {
+            return jail::normalize(&resolved)
+        }
+        let path = jail::normalize(path);
+        let (dir, name) = jail::split(&path);
+        match self.inner.canonicalize(dir).await {
+            Ok(dir) => jail::normalize(&format!("{}/{}", dir, name)),
+            Err(_) => path
+        }
+    }
+
+    // what an entry counts for, None if it doesn't exist
+    async fn entry_size(&self, path: &str) -> Option<u64> {
+        let st = self.inner.stat(path, false).await.ok()?;
+        Some(if st.is_file() { st.size } else { 0 })
+    }
+
+    async fn remove_entry(&self, path: &str, operation: impl Future<Output = io::Result<()>>) -> io::Result<()> {
+        let usage = self.usage().await?;
+        let key = self.key(path, false).await;
+        let freed = self.entry_size(path).await;
+        operation.await?;
+        if let Some(size) = freed {
+            Usage::give_back(&usage.files, 1);
+            // a file still open keeps its bytes until it is closed
+            if !self.usage.detach(&key) {
+                Usage::give_back(&usage.size, size);
+            }
+        }
+        Ok(())
+    }
+
+    fn limit_stats(&self, mut st: FsStats) -> FsStats {
+        let (size, files) = (self.usage.size.load(Ordering::Relaxed), self.usage.files.load(Ordering::Relaxed));
+        if self.max_size != 0 && st.fragment_size != 0 {
+            st.blocks = st.blocks.min(self.max_size / st.fragment_size);
+            st.blocks_free = st.blocks_free.min(self.max_size.saturating_sub(size) / st.fragment_size);
+            st.blocks_avail = st.blocks_avail.min(st.blocks_free);
+        }
+        if self.max_files != 0 {
+            st.files = st.files.min(self.max_files);
+            st.files_free = st.files_free.min(self.max_files.saturating_sub(files));
+            st.files_avail = st.files_avail.min(st.files_free);
+        }
+        st
+    }
+}
+
+impl StorageBackend for QuotaBackend {
+    fn owner(&self) -> io::Result<(u32, u32)> {
+        self.inner.owner()
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.canonicalize(path)
+    }
+
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            if !options.write && !options.append && !options.create && !options.exclusive {
+                return self.inner.open(path, options, mode).await
+            }
+            let usage = self.usage().await?;
+            let key = self.key(path, true).await;
+            let (inner, charge) = match self.inner.stat(path, true).await {
+                Ok(st) => {
+                    let charge = self.usage.charge(&key, if st.is_file() { st.size } else { 0 });
+                    let inner = self.inner.open(path, options, mode).await?;
+                    if options.truncate && st.is_file() {
+                        let mut size = charge.size.lock().unwrap();
+                        Usage::give_back(&usage.size, *size);
+                        *size = 0;
+                    }
+                    (inner, charge)
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound && (options.create || options.exclusive) => {
+                    let inner = self.add_entry(0, self.inner.open(path, options, mode)).await?;
+                    (inner, self.usage.charge(&key, 0))
+                }
+                Err(e) => return Err(e)
+            };
+            let file = QuotaFile { inner, charge, max_size: self.max_size, append: options.append };
+            Ok(Box::new(file) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+        self.inner.stat(path, follow)
+    }
+
+    fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, follow: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let Some(size) = attrs.size else {
+                return self.inner.set_attributes(path, attrs, follow).await
+            };
+            let usage = self.usage().await?;
+            // handles the file is open through have to know its new size
+            let charge = self.usage.open_charge(&self.key(path, follow).await);
+            let old = match &charge {
+                Some(charge) => *charge.size.lock().unwrap(),
+                None => self.inner.stat(path, follow).await?.size
+            };
+            if size > old {
+                Usage::take(&usage.size, size - old, self.max_size)?;
+            }
+            if let Some(charge) = &charge {
+                *charge.size.lock().unwrap() = size;
+            }
+            let result = self.inner.set_attributes(path, attrs, follow).await;
+            match &result {
+                Ok(()) if size < old => Usage::give_back(&usage.size, old - size),
+                Err(_) => {
+                    if size > old {
+                        Usage::give_back(&usage.size, size - old);
+                    }
+                    if let Some(charge) = &charge {
+                        *charge.size.lock().unwrap() = old;
+                    }
+                }
+                _ => {}
+            }
+            result
+        })
+    }
+
+    fn list<'a>(&'a self, path: &'a str, sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+        self.inner.list(path, sort, limit)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.remove_entry(path, self.inner.remove_file(path)))
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.add_entry(0, self.inner.create_dir(path, mode)))
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.remove_entry(path, self.inner.remove_dir(path)))
+    }
+
+    // a replaced file goes away, files open below the old path move along
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (from_key, to_key) = (self.key(from, false).await, self.key(to, false).await);
+            if !replace || jail::normalize(from) == jail::normalize(to) {
+                self.inner.rename(from, to, replace).await?;
+            }
+            else {
+                self.remove_entry(to, self.inner.rename(from, to, replace)).await?;
+            }
+            self.usage.rename_charges(&from_key, &to_key);
+            Ok(())
+        })
+    }
+
+    fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+        Box::pin(async move {
+            let st = self.inner.statvfs(path).await?;
+            self.usage().await?;
+            Ok(self.limit_stats(st))
+        })
+    }
+
+    fn read_link<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.read_link(path)
+    }
+
+    fn symlink<'a>(&'a self, target: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.add_entry(0, self.inner.symlink(target, path)))
+    }
+
+    fn hard_link<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let size = self.entry_size(from).await.unwrap_or(0);
+            self.add_entry(size, self.inner.hard_link(from, to)).await
+        })
+    }
+
+    // an overwritten file goes away first
+    fn copy_file<'a>(&'a self, from: &'a str, to: &'a str, overwrite: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let size = self.inner.stat(from, true).await?.size;
+            let usage = self.usage().await?;
+            let replaced = if overwrite { self.inner.stat(to, true).await.ok().filter(|st| st.is_file()).map(|st| st.size) } else { None };
+            // an open file is overwritten in place, what its handles took goes with it
+            let charge = match replaced {
+                Some(_) => self.usage.open_charge(&self.key(to, true).await),
+                None => None
+            };
+            let replaced = replaced.map(|old| charge.as_ref().map_or(old, |charge| *charge.size.lock().unwrap()));
+            if let Some(old) = replaced {
+                Usage::give_back(&usage.files, 1);
+                Usage::give_back(&usage.size, old);
+            }
+            let result = self.add_entry(size, self.inner.copy_file(from, to, overwrite)).await;
+            match (replaced, &result) {
+                (Some(old), Err(_)) => {
+                    usage.files.fetch_add(1, Ordering::Relaxed);
+                    usage.size.fetch_add(old, Ordering::Relaxed);
+                }
+                (_, Ok(())) => {
+                    if let Some(charge) = &charge {
+                        *charge.size.lock().unwrap() = size;
+                    }
+                }
+                _ => {}
+            }
+            result
+        })
+    }
+}
+
+// not handed out as a local file, a kernel copy into it would get around the limit
+struct QuotaFile {
+    inner: Box<dyn StorageFile>,
+    charge: Arc<Charge>,
+    max_size: u64,
+    append: bool
+}
+
+impl QuotaFile {
+    // takes what a file growing to `end` needs from the quota, returns the size before
+    fn grow(&self, end: u64) -> io::Result<u64> {
+        let mut size = self.charge.size.lock().unwrap();
+        let old = *size;
+        if end > old {
+            Usage::take(&self.charge.usage.size, end - old, self.max_size)?;
+            *size = end;
+        }
+        Ok(old)
+    }
+
+    // gives back what grow took once the change it was for failed, unless the file has grown further since
+    fn undo_grow(&self, old: u64, end: u64) {
+        let mut size = self.charge.size.lock().unwrap();
+        if end > old && *size == end {
+            Usage::give_back(&self.charge.usage.size, end - old);
+            *size = old;
+        }
+    }
+}
+
+impl StorageFile for QuotaFile {
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        self.inner.read_at(offset, len)
+    }
+
+    fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)> {
+        let start = if self.append { *self.charge.size.lock().unwrap() } else { offset };
+        let end = start.saturating_add(data.len() as u64);
+        let old = match self.grow(end) {
+            Ok(old) => old,
+            Err(e) => return Box::pin(async { (data, Err(e)) })
+        };
+        Box::pin(async move {
+            let (data, result) = self.inner.write_at(offset, data).await;
+            if result.is_err() {
+                self.undo_grow(old, end);
+            }
+            (data, result)
+        })
+    }
+
+    fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>> {
+        self.inner.stat()
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let Some(new) = attrs.size else {
+                return self.inner.set_attributes(attrs).await
+            };
+            let old = *self.charge.size.lock().unwrap();
+            if new > old {
+                let old = self.grow(new)?;
+                let result = self.inner.set_attributes(attrs).await;
+                if result.is_err() {
+                    self.undo_grow(old, new);
+                }
+                return result
+            }
+            self.inner.set_attributes(attrs).await?;
+            let mut size = self.charge.size.lock().unwrap();
+            if *size == old {
+                Usage::give_back(&self.charge.usage.size, old - new);
+                *size = new;
+            }
+            Ok(())
+        })
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.sync()
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        self.inner.statvfs()
+    }
+
+    fn close(&self) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.close()
+    }
+
+    fn will_need(&self, offset: u64, len: u64) {
+        self.inner.will_need(offset, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::MemoryConfig, memory::MemoryBackend};
+
+    #[tokio::test]
+    async fn failed_writes_give_back_their_space() {
+        // the inner backend runs out of space before the quota does
+        let inner = Arc::new(MemoryBackend::new(&MemoryConfig { max_size: 10, max_files: 0 }));
+        let usage = Arc::new(Usage::new());
+        let backend = QuotaBackend::new(inner, usage.clone(), 12, 0);
+        let create = OpenOptions { write: true, create: true, ..Default::default() };
+        let a = backend.open("a", create, 0o644).await.unwrap();
+        a.write_at(0, vec![1; 8]).await.1.unwrap();
+        let b = backend.open("b", create, 0o644).await.unwrap();
+        assert_eq!(b.write_at(0, vec![2; 4]).await.1.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(usage.size.load(Ordering::Relaxed), 8);
+        let resize = SetAttributes { size: Some(4), ..Default::default() };
+        assert_eq!(b.set_attributes(resize).await.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(usage.size.load(Ordering::Relaxed), 8);
+        b.write_at(0, vec![2; 2]).await.1.unwrap();
+        assert_eq!(usage.size.load(Ordering::Relaxed), 10);
+        a.set_attributes(SetAttributes { size: Some(2), ..Default::default() }).await.unwrap();
+        assert_eq!(usage.size.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn handles_share_the_file_size() {
+        let inner = Arc::new(MemoryBackend::new(&MemoryConfig::default()));
+        let usage = Arc::new(Usage::new());
+        let backend = QuotaBackend::new(inner, usage.clone(), 100, 0);
+        let create = OpenOptions { write: true, create: true, ..Default::default() };
+        let a = backend.open("a", create, 0o644).await.unwrap();
+        a.write_at(0, vec![1; 10]).await.1.unwrap();
+        // truncating through another handle gives the bytes back once, rewriting them takes them again
+        let b = backend.open("a", OpenOptions { truncate: true, ..create }, 0o644).await.unwrap();
+        assert_eq!(usage.size.load(Ordering::Relaxed), 0);
+        a.write_at(0, vec![1; 10]).await.1.unwrap();
+        assert_eq!(usage.size.load(Ordering::Relaxed), 10);
+        b.write_at(0, vec![2; 4]).await.1.unwrap();
+        assert_eq!(usage.size.load(Ordering::Relaxed), 10);
+        backend.set_attributes("a", SetAttributes { size: Some(2), ..Default::default() }, true).await.unwrap();
+        a.write_at(0, vec![1; 10]).await.1.unwrap();
+        assert_eq!(usage.size.load(Ordering::Relaxed), 10);
+        // a file removed while open is counted until it is closed
+        backend.remove_file("a").await.unwrap();
+        assert_eq!(usage.files.load(Ordering::Relaxed), 0);
+        b.write_at(10, vec![2; 5]).await.1.unwrap();
+        assert_eq!(usage.size.load(Ordering::Relaxed), 15);
+        drop(a);
+        assert_eq!(usage.size.load(Ordering::Relaxed), 15);
+        drop(b);
+        assert_eq!(usage.size.load(Ordering::Relaxed), 0);
+    }
+}
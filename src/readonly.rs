@@ -0,0 +1,154 @@
+use std::{io::{self, ErrorKind}, sync::Arc};
+
+use crate::storage::{BoxFuture, DirEntry, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile};
+
+fn read_only() -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, "read-only")
+}
+
+fn denied<'a, T: Send + 'a>() -> BoxFuture<'a, io::Result<T>> {
+    Box::pin(async { Err(read_only()) })
+}
+
+fn read_only_stats(st: io::Result<FsStats>) -> io::Result<FsStats> {
+    st.map(|st| FsStats { read_only: true, ..st })
+}
+
+/// Lets files be read and directories listed, anything that would change them is refused
+pub(crate) struct ReadOnlyBackend {
+    inner: Arc<dyn StorageBackend>
+}
+
+impl ReadOnlyBackend {
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>) -> Self {
+        ReadOnlyBackend { inner }
+    }
+}
+
+impl StorageBackend for ReadOnlyBackend {
+    fn owner(&self) -> io::Result<(u32, u32)> {
+        self.inner.owner()
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.canonicalize(path)
+    }
+
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        if options.write || options.append || options.create || options.truncate || options.exclusive {
+            return denied()
+        }
+        Box::pin(async move {
+            let inner = self.inner.open(path, options, mode).await?;
+            Ok(Box::new(ReadOnlyFile { inner }) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+        self.inner.stat(path, follow)
+    }
+
+    fn set_attributes<'a>(&'a self, _path: &'a str, _attrs: SetAttributes, _follow: bool) -> BoxFuture<'a, io::Result<()>> {
+        denied()
+    }
+
+    fn list<'a>(&'a self, path: &'a str, sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+        Box::pin(async move {
+            let inner = self.inner.list(path, sort, limit).await?;
+            Ok(Box::new(ReadOnlyDir { inner }) as Box<dyn DirStream>)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, _path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        denied()
+    }
+
+    fn create_dir<'a>(&'a self, _path: &'a str, _mode: u32) -> BoxFuture<'a, io::Result<()>> {
+        denied()
+    }
+
+    fn remove_dir<'a>(&'a self, _path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        denied()
+    }
+
+    fn rename<'a>(&'a self, _from: &'a str, _to: &'a str, _replace: bool) -> BoxFuture<'a, io::Result<()>> {
+        denied()
+    }
+
+    fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+        Box::pin(async move { read_only_stats(self.inner.statvfs(path).await) })
+    }
+
+    fn read_link<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.read_link(path)
+    }
+
+    fn symlink<'a>(&'a self, _target: &'a str, _path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        denied()
+    }
+
+    fn hard_link<'a>(&'a self, _from: &'a str, _to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        denied()
+    }
+
+    fn copy_file<'a>(&'a self, _from: &'a str, _to: &'a str, _overwrite: bool) -> BoxFuture<'a, io::Result<()>> {
+        denied()
+    }
+}
+
+// not handed out as a local file, a kernel copy into it would get around the checks
+struct ReadOnlyFile {
+    inner: Box<dyn StorageFile>
+}
+
+impl StorageFile for ReadOnlyFile {
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        self.inner.read_at(offset, len)
+    }
+
+    fn write_at(&self, _offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)> {
+        Box::pin(async { (data, Err(read_only())) })
+    }
+
+    fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>> {
+        self.inner.stat()
+    }
+
+    fn set_attributes(&self, _attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        denied()
+    }
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.sync()
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        Box::pin(async move { read_only_stats(self.inner.statvfs().await) })
+    }
+
+    fn close(&self) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.close()
+    }
+
+    fn will_need(&self, offset: u64, len: u64) {
+        self.inner.will_need(offset, len)
+    }
+}
+
+struct ReadOnlyDir {
+    inner: Box<dyn DirStream>
+}
+
+impl DirStream for ReadOnlyDir {
+    fn next_entries(&mut self, count: usize) -> BoxFuture<'_, io::Result<Vec<DirEntry>>> {
+        self.inner.next_entries(count)
+    }
+
+    fn set_attributes(&self, _attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        denied()
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        Box::pin(async move { read_only_stats(self.inner.statvfs().await) })
+    }
+}
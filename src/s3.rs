@@ -0,0 +1,799 @@
+use std::{collections::{BTreeMap, VecDeque}, io::{self, ErrorKind}, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{header::HeaderMap, Client, Method, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::{checksum::to_hex, config::S3Config, jail, storage::{BoxFuture, DirEntry, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend, StorageFile}};
+
+// S3 refuses multipart parts smaller than this, except for the last one
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+// writes that arrive ahead of the ones before them are held back if they end within this amount of what was written
+const MAX_OUT_OF_ORDER: usize = 64 * 1024 * 1024;
+// objects have no permissions of their own, everything is reported with these
+const DIR_MODE: u32 = libc::S_IFDIR | 0o755;
+const FILE_MODE: u32 = libc::S_IFREG | 0o644;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    contents: Vec<Object>,
+    #[serde(default)]
+    common_prefixes: Vec<CommonPrefix>,
+    next_continuation_token: Option<String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Object {
+    key: String,
+    size: u64,
+    last_modified: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CommonPrefix {
+    prefix: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InitiateMultipartUploadResult {
+    upload_id: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ErrorResponse {
+    code: String
+}
+
+// percent encoding as signature version 4 wants it, everything but unreserved characters is encoded
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            b'/' if keep_slash => encoded.push('/'),
+            b => encoded.push_str(&format!("%{:02X}", b))
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    // hmac takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn parse_error(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("invalid response from s3: {}", e))
+}
+
+fn http_time(headers: &HeaderMap) -> i64 {
+    headers.get("last-modified").and_then(|time| time.to_str().ok())
+        .and_then(|time| DateTime::parse_from_rfc2822(time).ok())
+        .map_or(0, |time| time.timestamp())
+}
+
+fn list_time(time: &str) -> i64 {
+    DateTime::parse_from_rfc3339(time).map_or(0, |time| time.timestamp())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// An S3 compatible bucket, requests are signed with signature version 4
+pub(crate) struct Bucket {
+    http: Client,
+    scheme: String,
+    // host and port as sent in the Host header
+    host: String,
+    // `/bucket` with path style requests, the bucket is part of the host otherwise
+    base_path: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    prefix: String,
+    part_size: usize
+}
+
+impl Bucket {
+    pub(crate) fn new(config: &S3Config) -> Result<Self, String> {
+        if config.bucket.is_empty() {
+            return Err(String::from("no bucket configured"))
+        }
+        let endpoint = if config.endpoint.is_empty() { format!("https://s3.{}.amazonaws.com", config.region) } else { config.endpoint.clone() };
+        let url = Url::parse(&endpoint).map_err(|e| format!("invalid endpoint {}: {}", endpoint, e))?;
+        let Some(host) = url.host_str() else {
+            return Err(format!("invalid endpoint {}: no host", endpoint))
+        };
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string()
+        };
+        let (host, base_path) = if config.path_style { (host, format!("/{}", config.bucket)) } else { (format!("{}.{}", config.bucket, host), String::new()) };
+        let prefix = jail::normalize(&config.prefix);
+        Ok(Bucket {
+            http: Client::new(),
+            scheme: url.scheme().to_string(),
+            host,
+            base_path,
+            bucket: config.bucket.clone(),
+            region: config.region.clone(),
+            access_key: config.access_key.clone(),
+            secret_key: config.secret_key.clone(),
+            prefix: if prefix.is_empty() { prefix } else { format!("{}/", prefix) },
+            part_size: config.part_size.max(MIN_PART_SIZE)
+        })
+    }
+
+    async fn request(&self, method: Method, key: &str, query: &[(&str, &str)], headers: &[(&str, String)], body: Vec<u8>) -> io::Result<reqwest::Response> {
+        let path = format!("{}/{}", self.base_path, uri_encode(key, true));
+        let mut query: Vec<String> = query.iter().map(|(name, value)| format!("{}={}", uri_encode(name, false), uri_encode(value, false))).collect();
+        query.sort();
+        let query = query.join("&");
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = to_hex(&Sha256::digest(&body));
+        let mut signed: Vec<(String, String)> = headers.iter().map(|(name, value)| (name.to_string(), value.trim().to_string())).collect();
+        signed.push((String::from("host"), self.host.clone()));
+        signed.push((String::from("x-amz-content-sha256"), payload_hash.clone()));
+        signed.push((String::from("x-amz-date"), amz_date.clone()));
+        signed.sort();
+
+        let canonical_headers: String = signed.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed_headers = signed.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";");
+        let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, signed_headers, payload_hash);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, to_hex(&Sha256::digest(canonical_request.as_bytes())));
+        let signing_key = hmac(&hmac(&hmac(&hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date), &self.region), "s3"), "aws4_request");
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, to_hex(&hmac(&signing_key, &string_to_sign))
+        );
+
+        let url = if query.is_empty() { format!("{}://{}{}", self.scheme, self.host, path) } else { format!("{}://{}{}?{}", self.scheme, self.host, path, query) };
+        let mut request = self.http.request(method.clone(), url).header("authorization", authorization);
+        for (name, value) in signed.iter().filter(|(name, _)| name != "host") {
+            request = request.header(name, value);
+        }
+        let response = request.body(body).send().await.map_err(io::Error::other)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response)
+        }
+
+        let code = if method == Method::HEAD { None } else {
+            response.text().await.ok().and_then(|text| quick_xml::de::from_str::<ErrorResponse>(&text).ok()).map(|e| e.code)
+        };
+        let message = format!("s3 request failed with {}{}", status, code.map(|code| format!(": {}", code)).unwrap_or_default());
+        Err(match status.as_u16() {
+            404 => io::Error::new(ErrorKind::NotFound, message),
+            403 => io::Error::new(ErrorKind::PermissionDenied, message),
+            412 => io::Error::new(ErrorKind::AlreadyExists, message),
+            _ => io::Error::other(message)
+        })
+    }
+
+    // size and modification time of an object
+    async fn head(&self, key: &str) -> io::Result<(u64, i64)> {
+        let response = self.request(Method::HEAD, key, &[], &[], Vec::new()).await?;
+        let size = response.headers().get("content-length").and_then(|len| len.to_str().ok()).and_then(|len| len.parse().ok()).unwrap_or(0);
+        Ok((size, http_time(response.headers())))
+    }
+
+    async fn get_range(&self, key: &str, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+        let response = self.request(Method::GET, key, &[], &[("range", range)], Vec::new()).await?;
+        Ok(response.bytes().await.map_err(io::Error::other)?.to_vec())
+    }
+
+    async fn put(&self, key: &str, body: Vec<u8>) -> io::Result<()> {
+        self.request(Method::PUT, key, &[], &[], body).await.map(|_| ())
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.request(Method::DELETE, key, &[], &[], Vec::new()).await.map(|_| ())
+    }
+
+    // a copy made by the server, objects over 5 GiB can't be copied this way
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let source = format!("/{}/{}", self.bucket, uri_encode(from, true));
+        self.request(Method::PUT, to, &[], &[("x-amz-copy-source", source)], Vec::new()).await.map(|_| ())
+    }
+
+    // one page of the keys below `prefix`, with `delimiter` keys further down are grouped into common prefixes
+    async fn list(&self, prefix: &str, delimiter: bool, token: Option<&str>, max_keys: usize) -> io::Result<ListBucketResult> {
+        let max_keys = max_keys.to_string();
+        let mut query = vec![("list-type", "2"), ("prefix", prefix), ("max-keys", max_keys.as_str())];
+        if delimiter {
+            query.push(("delimiter", "/"));
+        }
+        if let Some(token) = token {
+            query.push(("continuation-token", token));
+        }
+        let response = self.request(Method::GET, "", &query, &[], Vec::new()).await?;
+        let text = response.text().await.map_err(io::Error::other)?;
+        quick_xml::de::from_str(&text).map_err(parse_error)
+    }
+
+    // every key below `prefix`, however deep
+    async fn list_all(&self, prefix: &str) -> io::Result<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut token = None;
+        loop {
+            let page = self.list(prefix, false, token.as_deref(), 1000).await?;
+            objects.extend(page.contents);
+            token = page.next_continuation_token;
+            if token.is_none() {
+                return Ok(objects)
+            }
+        }
+    }
+
+    async fn create_multipart(&self, key: &str) -> io::Result<String> {
+        let response = self.request(Method::POST, key, &[("uploads", "")], &[], Vec::new()).await?;
+        let text = response.text().await.map_err(io::Error::other)?;
+        quick_xml::de::from_str::<InitiateMultipartUploadResult>(&text).map(|result| result.upload_id).map_err(parse_error)
+    }
+
+    async fn upload_part(&self, key: &str, upload_id: &str, number: u32, body: Vec<u8>) -> io::Result<String> {
+        let number = number.to_string();
+        let response = self.request(Method::PUT, key, &[("partNumber", &number), ("uploadId", upload_id)], &[], body).await?;
+        match response.headers().get("etag").and_then(|etag| etag.to_str().ok()) {
+            Some(etag) => Ok(etag.to_string()),
+            None => Err(parse_error("no etag for uploaded part"))
+        }
+    }
+
+    async fn complete_multipart(&self, key: &str, upload_id: &str, parts: &[(u32, String)]) -> io::Result<()> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (number, etag) in parts {
+            body.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", number, escape(etag)));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let response = self.request(Method::POST, key, &[("uploadId", upload_id)], &[], body.into_bytes()).await?;
+        // errors that happen after the request was accepted come back with a 200 status
+        let text = response.text().await.map_err(io::Error::other)?;
+        match quick_xml::de::from_str::<ErrorResponse>(&text) {
+            Ok(e) if text.contains("<Error>") => Err(io::Error::other(format!("s3 multipart upload failed: {}", e.code))),
+            _ => Ok(())
+        }
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> io::Result<()> {
+        self.request(Method::DELETE, key, &[("uploadId", upload_id)], &[], Vec::new()).await.map(|_| ())
+    }
+}
+
+fn dir_stat(owner: (u32, u32), mtime: i64) -> FileStat {
+    FileStat { size: 0, mode: DIR_MODE, uid: owner.0, gid: owner.1, nlink: 1, atime: mtime, mtime }
+}
+
+fn file_stat(owner: (u32, u32), size: u64, mtime: i64) -> FileStat {
+    FileStat { size, mode: FILE_MODE, uid: owner.0, gid: owner.1, nlink: 1, atime: mtime, mtime }
+}
+
+/// A user's files stored below a prefix of a bucket. Directories are keys ending in `/` and
+/// any prefix other keys are stored under, so objects uploaded by other tools show up as well
+pub(crate) struct S3Backend {
+    bucket: Arc<Bucket>,
+    // prefix of every key of the user, empty or ending in `/`
+    root: String,
+    owner: (u32, u32)
+}
+
+impl S3Backend {
+    /// `dir` is the user's directory below the configured prefix
+    pub(crate) fn new(bucket: Arc<Bucket>, dir: &str) -> Self {
+        let root = format!("{}{}/", bucket.prefix, jail::normalize(dir));
+        let root = if root == "/" { String::new() } else { root };
+        let owner = unsafe { (libc::geteuid(), libc::getegid()) };
+        S3Backend { bucket, root, owner }
+    }
+
+    // object key of a path, the root itself is an empty path
+    fn key(&self, path: &str) -> (String, bool) {
+        let path = jail::normalize(path);
+        let is_root = path.is_empty();
+        (format!("{}{}", self.root, path), is_root)
+    }
+
+    // prefix of everything inside the directory at `key`
+    fn dir_prefix(&self, key: &str, is_root: bool) -> String {
+        if is_root { self.root.clone() } else { format!("{}/", key) }
+    }
+
+    async fn stat_key(&self, key: &str, is_root: bool) -> io::Result<FileStat> {
+        if is_root {
+            return Ok(dir_stat(self.owner, 0))
+        }
+        match self.bucket.head(key).await {
+            Ok((size, mtime)) => return Ok(file_stat(self.owner, size, mtime)),
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+        let prefix = self.dir_prefix(key, false);
+        let page = self.bucket.list(&prefix, true, None, 1).await?;
+        match page.contents.first() {
+            // the marker object of an empty directory
+            Some(object) if object.key == prefix => Ok(dir_stat(self.owner, list_time(&object.last_modified))),
+            _ if !page.contents.is_empty() || !page.common_prefixes.is_empty() => Ok(dir_stat(self.owner, 0)),
+            _ => Err(io::Error::from(ErrorKind::NotFound))
+        }
+    }
+
+    async fn check_parent(&self, path: &str) -> io::Result<()> {
+        let path = jail::normalize(path);
+        let (parent, _) = jail::split(&path);
+        let (key, is_root) = self.key(parent);
+        if self.stat_key(&key, is_root).await?.is_file() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR))
+        }
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str, is_root: bool) -> io::Result<bool> {
+        match self.stat_key(key, is_root).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn owner(&self) -> io::Result<(u32, u32)> {
+        Ok(self.owner)
+    }
+
+    // there are no symlinks, so this is only a matter of collapsing `.` and `..`
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(async move { Ok(format!("/{}", jail::normalize(path))) })
+    }
+
+    // objects can't be changed in place, files opened for writing are uploaded as new objects from the first byte on
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, _mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            let (key, is_root) = self.key(path);
+            let existing = match self.stat_key(&key, is_root).await {
+                Ok(st) if !st.is_file() => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+                Ok(st) => Some(st),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e)
+            };
+            let writing = options.write || options.append;
+            let file = match existing {
+                Some(_) if writing && options.exclusive => return Err(io::Error::from(ErrorKind::AlreadyExists)),
+                Some(_) if writing && !options.truncate => {
+                    return Err(io::Error::new(ErrorKind::Unsupported, "existing objects can only be replaced as a whole"))
+                }
+                Some(st) if !writing => S3File { bucket: self.bucket.clone(), key, stat: st, writer: None },
+                None if !writing || !options.create => return Err(io::Error::from(ErrorKind::NotFound)),
+                _ => {
+                    self.check_parent(path).await?;
+                    let now = Utc::now().timestamp();
+                    let writer = Writer { upload_id: None, parts: Vec::new(), buf: Vec::new(), end: 0, ahead: BTreeMap::new(), ahead_len: 0, closed: false };
+                    S3File { bucket: self.bucket.clone(), key, stat: file_stat(self.owner, 0, now), writer: Some(Mutex::new(writer)) }
+                }
+            };
+            Ok(Box::new(file) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a str, _follow: bool) -> BoxFuture<'a, io::Result<FileStat>> {
+        Box::pin(async move {
+            let (key, is_root) = self.key(path);
+            self.stat_key(&key, is_root).await
+        })
+    }
+
+    // objects have no owner, permissions or times that could be changed, those changes are ignored
+    fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, _follow: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (key, is_root) = self.key(path);
+            let st = self.stat_key(&key, is_root).await?;
+            match attrs.size {
+                Some(size) if size != st.size => Err(io::Error::new(ErrorKind::Unsupported, "objects can't be truncated")),
+                _ => Ok(())
+            }
+        })
+    }
+
+    // keys come back ordered by the bucket, so listings are always sorted
+    fn list<'a>(&'a self, path: &'a str, _sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>> {
+        Box::pin(async move {
+            let (key, is_root) = self.key(path);
+            let st = self.stat_key(&key, is_root).await?;
+            if st.is_file() {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR))
+            }
+            let entries = VecDeque::from([
+                DirEntry { name: String::from("."), stat: st },
+                DirEntry { name: String::from(".."), stat: dir_stat(self.owner, 0) }
+            ]);
+            let prefix = self.dir_prefix(&key, is_root);
+            Ok(Box::new(S3Dir { bucket: self.bucket.clone(), prefix, owner: self.owner, token: None, done: false, entries, remaining: (limit != 0).then_some(limit) }) as Box<dyn DirStream>)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (key, is_root) = self.key(path);
+            // deleting a key that doesn't exist succeeds in S3
+            if !self.stat_key(&key, is_root).await?.is_file() {
+                return Err(io::Error::from_raw_os_error(libc::EISDIR))
+            }
+            self.bucket.delete(&key).await
+        })
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a str, _mode: u32) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (key, is_root) = self.key(path);
+            if self.exists(&key, is_root).await? {
+                return Err(io::Error::from(ErrorKind::AlreadyExists))
+            }
+            self.check_parent(path).await?;
+            self.bucket.put(&self.dir_prefix(&key, false), Vec::new()).await
+        })
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (key, is_root) = self.key(path);
+            if is_root {
+                return Err(io::Error::from(ErrorKind::PermissionDenied))
+            }
+            if self.stat_key(&key, false).await?.is_file() {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR))
+            }
+            let prefix = self.dir_prefix(&key, false);
+            let page = self.bucket.list(&prefix, true, None, 2).await?;
+            if !page.common_prefixes.is_empty() || page.contents.iter().any(|object| object.key != prefix) {
+                return Err(io::Error::from(ErrorKind::DirectoryNotEmpty))
+            }
+            self.bucket.delete(&prefix).await
+        })
+    }
+
+    // S3 has no rename, objects are copied and the originals deleted. directories are moved key by key
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let ((from_key, from_root), (to_key, to_root)) = (self.key(from), self.key(to));
+            if from_root || to_root {
+                return Err(io::Error::from(ErrorKind::PermissionDenied))
+            }
+            let st = self.stat_key(&from_key, false).await?;
+            let to_st = match self.stat_key(&to_key, false).await {
+                Ok(st) => Some(st),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e)
+            };
+            if from_key == to_key {
+                return Ok(())
+            }
+            self.check_parent(to).await?;
+
+            if st.is_file() {
+                match to_st {
+                    Some(to_st) if !to_st.is_file() => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+                    Some(_) if !replace => return Err(io::Error::from(ErrorKind::AlreadyExists)),
+                    _ => {}
+                }
+                self.bucket.copy(&from_key, &to_key).await?;
+                return self.bucket.delete(&from_key).await
+            }
+
+            if to_st.is_some() {
+                return Err(io::Error::from(ErrorKind::AlreadyExists))
+            }
+            let (from_prefix, to_prefix) = (self.dir_prefix(&from_key, false), self.dir_prefix(&to_key, false));
+            if to_prefix.starts_with(&from_prefix) {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "can't move a directory into itself"))
+            }
+            let objects = self.bucket.list_all(&from_prefix).await?;
+            for object in &objects {
+                self.bucket.copy(&object.key, &format!("{}{}", to_prefix, &object.key[from_prefix.len()..])).await?;
+            }
+            for object in &objects {
+                self.bucket.delete(&object.key).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn statvfs<'a>(&'a self, _path: &'a str) -> BoxFuture<'a, io::Result<FsStats>> {
+        Box::pin(async { Err(io::Error::from(ErrorKind::Unsupported)) })
+    }
+
+    fn copy_file<'a>(&'a self, from: &'a str, to: &'a str, overwrite: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let ((from_key, from_root), (to_key, to_root)) = (self.key(from), self.key(to));
+            if !self.stat_key(&from_key, from_root).await?.is_file() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "not a regular file"))
+            }
+            if from_key == to_key {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "source and destination are the same file"))
+            }
+            if !overwrite && self.exists(&to_key, to_root).await? {
+                return Err(io::Error::from(ErrorKind::AlreadyExists))
+            }
+            self.check_parent(to).await?;
+            self.bucket.copy(&from_key, &to_key).await
+        })
+    }
+}
+
+// state of an object being uploaded
+struct Writer {
+    upload_id: Option<String>,
+    // part numbers and etags of the parts uploaded so far
+    parts: Vec<(u32, String)>,
+    // data received but not uploaded yet, it ends at `end`
+    buf: Vec<u8>,
+    end: u64,
+    // writes that arrived before the data in front of them, by offset
+    ahead: BTreeMap<u64, Vec<u8>>,
+    ahead_len: usize,
+    closed: bool
+}
+
+impl Writer {
+    // takes data starting at or before `end`, data that overlaps what is still buffered replaces it
+    fn accept(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let buf_start = self.end - self.buf.len() as u64;
+        if offset < buf_start {
+            return Err(io::Error::new(ErrorKind::Unsupported, "data that was already uploaded can't be changed"))
+        }
+        let Some(end) = offset.checked_add(data.len() as u64) else {
+            return Err(io::Error::from_raw_os_error(libc::EFBIG))
+        };
+        let start = (offset - buf_start) as usize;
+        let overlap = (self.buf.len() - start).min(data.len());
+        self.buf[start..start + overlap].copy_from_slice(&data[..overlap]);
+        self.buf.extend_from_slice(&data[overlap..]);
+        self.end = self.end.max(end);
+        Ok(())
+    }
+
+    // moves writes that were held back into the buffer once the data in front of them is there
+    fn catch_up(&mut self) -> io::Result<()> {
+        while let Some(entry) = self.ahead.first_entry() {
+            if *entry.key() > self.end {
+                break
+            }
+            let (offset, data) = entry.remove_entry();
+            self.ahead_len -= data.len();
+            self.accept(offset, &data)?;
+        }
+        Ok(())
+    }
+
+    async fn upload_part(&mut self, bucket: &Bucket, key: &str, len: usize) -> io::Result<()> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let upload_id = bucket.create_multipart(key).await?;
+                self.upload_id = Some(upload_id.clone());
+                upload_id
+            }
+        };
+        let rest = self.buf.split_off(len);
+        let part = std::mem::replace(&mut self.buf, rest);
+        let number = self.parts.len() as u32 + 1;
+        let etag = bucket.upload_part(key, &upload_id, number, part).await?;
+        self.parts.push((number, etag));
+        Ok(())
+    }
+
+    async fn finish(&mut self, bucket: &Bucket, key: &str) -> io::Result<()> {
+        // whatever is still missing in front of held back writes reads as zeros, like in a sparse file. the zeros
+        // are added a part at a time so they are uploaded as they go
+        while let Some((&offset, _)) = self.ahead.first_key_value() {
+            let zeros = vec![0u8; (offset - self.end).min(bucket.part_size as u64) as usize];
+            let end = self.end;
+            self.accept(end, &zeros)?;
+            self.catch_up()?;
+            while self.buf.len() > bucket.part_size {
+                self.upload_part(bucket, key, bucket.part_size).await?;
+            }
+        }
+        while self.buf.len() > bucket.part_size {
+            self.upload_part(bucket, key, bucket.part_size).await?;
+        }
+        match self.upload_id.clone() {
+            Some(upload_id) => {
+                self.upload_part(bucket, key, self.buf.len()).await?;
+                bucket.complete_multipart(key, &upload_id, &self.parts).await
+            }
+            None => bucket.put(key, std::mem::take(&mut self.buf)).await
+        }
+    }
+
+    async fn abort(&mut self, bucket: &Bucket, key: &str) {
+        let Some(upload_id) = self.upload_id.take() else {
+            return
+        };
+        if let Err(e) = bucket.abort_multipart(key, &upload_id).await {
+            println!("error aborting multipart upload: {}", e);
+        }
+    }
+}
+
+/// An object opened for reading, or a new one being uploaded. Uploads become visible once the file is closed
+struct S3File {
+    bucket: Arc<Bucket>,
+    key: String,
+    // the object's attributes when it was opened
+    stat: FileStat,
+    writer: Option<Mutex<Writer>>
+}
+
+impl StorageFile for S3File {
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            if self.writer.is_some() {
+                return Err(io::Error::new(ErrorKind::Unsupported, "objects being uploaded can't be read"))
+            }
+            let len = (len as u64).min(self.stat.size.saturating_sub(offset));
+            if len == 0 {
+                return Ok(Vec::new())
+            }
+            self.bucket.get_range(&self.key, offset, len).await
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)> {
+        Box::pin(async move {
+            let Some(writer) = &self.writer else {
+                return (data, Err(io::Error::from_raw_os_error(libc::EBADF)))
+            };
+            let mut writer = writer.lock().await;
+            if writer.closed {
+                return (data, Err(io::Error::from_raw_os_error(libc::EBADF)))
+            }
+            if offset.checked_add(data.len() as u64).is_none() {
+                return (data, Err(io::Error::from_raw_os_error(libc::EFBIG)))
+            }
+            if offset > writer.end {
+                // the gap counts as well, it is filled with zeros if it's never written
+                if offset + data.len() as u64 - writer.end > MAX_OUT_OF_ORDER as u64 || writer.ahead_len + data.len() > MAX_OUT_OF_ORDER {
+                    return (data, Err(io::Error::new(ErrorKind::Unsupported, "writes too far out of order")))
+                }
+                writer.ahead_len += data.len();
+                if let Some(replaced) = writer.ahead.insert(offset, data) {
+                    writer.ahead_len -= replaced.len();
+                }
+                return (Vec::new(), Ok(()))
+            }
+            if let Err(e) = writer.accept(offset, &data).and_then(|_| writer.catch_up()) {
+                return (data, Err(e))
+            }
+            // parts are only uploaded once there is more, the last one may be smaller but no other can
+            while writer.buf.len() > self.bucket.part_size {
+                if let Err(e) = writer.upload_part(&self.bucket, &self.key, self.bucket.part_size).await {
+                    return (data, Err(e))
+                }
+            }
+            (data, Ok(()))
+        })
+    }
+
+    fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>> {
+        Box::pin(async move {
+            let mut stat = self.stat;
+            if let Some(writer) = &self.writer {
+                let writer = writer.lock().await;
+                stat.size = writer.ahead.last_key_value().map_or(writer.end, |(offset, data)| writer.end.max(offset.saturating_add(data.len() as u64)));
+            }
+            Ok(stat)
+        })
+    }
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let size = self.stat().await?.size;
+            match attrs.size {
+                Some(new_size) if new_size != size => Err(io::Error::new(ErrorKind::Unsupported, "objects can't be truncated")),
+                _ => Ok(())
+            }
+        })
+    }
+
+    // nothing is stored before the file is closed
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            match self.writer {
+                Some(_) => Err(io::Error::new(ErrorKind::Unsupported, "uploads are only stored once the file is closed")),
+                None => Ok(())
+            }
+        })
+    }
+
+    fn close(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let Some(writer) = &self.writer else {
+                return Ok(())
+            };
+            let mut writer = writer.lock().await;
+            if writer.closed {
+                return Ok(())
+            }
+            writer.closed = true;
+            let result = writer.finish(&self.bucket, &self.key).await;
+            if result.is_err() {
+                writer.abort(&self.bucket, &self.key).await;
+            }
+            result
+        })
+    }
+}
+
+// a directory listing fetched from the bucket one page at a time
+struct S3Dir {
+    bucket: Arc<Bucket>,
+    prefix: String,
+    owner: (u32, u32),
+    token: Option<String>,
+    done: bool,
+    entries: VecDeque<DirEntry>,
+    // entries still allowed by the listing limit, if there is one
+    remaining: Option<usize>
+}
+
+impl S3Dir {
+    async fn fetch(&mut self, count: usize) -> io::Result<()> {
+        let page = self.bucket.list(&self.prefix, true, self.token.as_deref(), count.clamp(1, 1000)).await?;
+        let prefix_len = self.prefix.len();
+        let dirs = page.common_prefixes.into_iter()
+            .map(|dir| DirEntry { name: dir.prefix[prefix_len..].trim_end_matches('/').to_string(), stat: dir_stat(self.owner, 0) });
+        let files = page.contents.into_iter()
+            .filter(|object| object.key.len() > prefix_len)
+            .map(|object| DirEntry { name: object.key[prefix_len..].to_string(), stat: file_stat(self.owner, object.size, list_time(&object.last_modified)) });
+        let mut entries: Vec<DirEntry> = dirs.chain(files).collect();
+        entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        self.entries.extend(entries);
+        self.token = page.next_continuation_token;
+        self.done = self.token.is_none();
+        Ok(())
+    }
+}
+
+impl DirStream for S3Dir {
+    fn next_entries(&mut self, count: usize) -> BoxFuture<'_, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            while self.entries.len() < count && !self.done {
+                self.fetch(count - self.entries.len()).await?;
+            }
+            let mut count = count.min(self.entries.len());
+            let Some(remaining) = self.remaining else {
+                return Ok(self.entries.drain(..count).collect())
+            };
+            // `.` and `..` don't count against the limit
+            let special = self.entries.iter().take(count).take_while(|entry| entry.name == "." || entry.name == "..").count();
+            count = count.min(special + remaining);
+            let remaining = remaining - (count - special);
+            self.remaining = Some(remaining);
+            let entries = self.entries.drain(..count).collect();
+            if remaining == 0 {
+                // entries fetched past the limit are dropped and no more are fetched
+                self.entries.clear();
+                self.done = true;
+            }
+            Ok(entries)
+        })
+    }
+}
@@ -1,10 +1,60 @@
-use std::{collections::HashMap, io::{ErrorKind, SeekFrom}, os::unix::fs::MetadataExt};
+use std::{collections::{HashMap, HashSet}, io::{self, ErrorKind}, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 
-use chrono::{Local, TimeZone};
-use regex::Regex;
-use russh_sftp::{protocol::{Attrs, Data, File, FileAttributes, Handle as SftpHandle, Name, OpenFlags, Status, StatusCode}, server::Handler as SftpHandler};
+use chrono::{Local, TimeZone, Utc};
+use russh_sftp::{de, extensions::{self, LimitsExtension, Statvfs}, protocol::{Attrs, Data, ExtendedReply, File, FileAttributes, Handle as SftpHandle, Name, OpenFlags, Packet, Status, StatusCode, Version}, ser, server::Handler as SftpHandler};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use tokio::{fs::{self, OpenOptions, ReadDir}, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}};
+use crate::{checksum::{self, Algorithm}, file::{BufferPool, OpenFile}, config::{ChownPolicy, OwnerDisplay, PartialUploads, Permission, SftpConfig, SymlinkPolicy, Timezone}, jail, permissions::Permissions, storage::{self, DirEntry, DirStream, FileStat, FsStats, OpenOptions, SetAttributes, StorageBackend}, users};
+
+const EXPAND_PATH: &str = "expand-path@openssh.com";
+const POSIX_RENAME: &str = "posix-rename@openssh.com";
+const FSTATVFS: &str = "fstatvfs@openssh.com";
+const LSETSTAT: &str = "lsetstat@openssh.com";
+const CHECK_FILE_NAME: &str = "check-file-name";
+const CHECK_FILE_HANDLE: &str = "check-file-handle";
+const MD5_HASH: &str = "md5-hash";
+const MD5_HASH_HANDLE: &str = "md5-hash-handle";
+const COPY_DATA: &str = "copy-data";
+const COPY_FILE: &str = "copy-file";
+const USERS_GROUPS_BY_ID: &str = "users-groups-by-id@openssh.com";
+
+// algorithms offered through check-file, in order of preference
+const CHECK_FILE_ALGORITHMS: &str = "md5,sha1,sha224,sha256,sha384,sha512";
+// the draft requires at least this block size when hashing in blocks
+const MIN_CHECK_BLOCK_SIZE: u32 = 256;
+// md5-hash compares this many leading bytes against the quick check hash
+const QUICK_CHECK_LEN: u64 = 2048;
+
+// extensions advertised in the version reply
+const EXTENSIONS: &[(&str, &str)] = &[
+    (POSIX_RENAME, "1"),
+    (extensions::STATVFS, "2"),
+    (FSTATVFS, "2"),
+    (extensions::HARDLINK, "1"),
+    (extensions::FSYNC, "1"),
+    (LSETSTAT, "1"),
+    (extensions::LIMITS, "1"),
+    (EXPAND_PATH, "1"),
+    (CHECK_FILE_NAME, CHECK_FILE_ALGORITHMS),
+    (CHECK_FILE_HANDLE, CHECK_FILE_ALGORITHMS),
+    (MD5_HASH, "1"),
+    (MD5_HASH_HANDLE, "1"),
+    (COPY_DATA, "1"),
+    (COPY_FILE, "1"),
+    (USERS_GROUPS_BY_ID, "1")
+];
+
+// entries stat'ed at once while listing a directory, replies are filled up to the packet limit
+const READDIR_BATCH: usize = 256;
+// length prefixes of the name and longname plus the largest attributes we send
+const NAME_ENTRY_OVERHEAD: usize = 4 + 4 + 32;
+
+// listings show the time instead of the year for files modified within this many seconds, like ls does
+const RECENT_SECS: i64 = 182 * 24 * 60 * 60;
+
+// same limits OpenSSH uses, reported through limits@openssh.com
+pub(crate) const MAX_PACKET_LEN: u64 = 256 * 1024;
+const MAX_READ_WRITE_LEN: u64 = MAX_PACKET_LEN - 1024;
 
 macro_rules! match_expr {
     ($match:expr, $err_msg:literal, $id:ident) => {
@@ -12,32 +62,565 @@ macro_rules! match_expr {
             Ok(()) => Ok(Status { $id, status_code: StatusCode::Ok, error_message: "Ok".to_string(), language_tag: "en-US".to_string() }),
             Err(e) => {
                 println!($err_msg, e);
-                match e.kind() {
-                    ErrorKind::NotFound => Ok(Status { $id, status_code: StatusCode::NoSuchFile, error_message: e.to_string(), language_tag: "en-US".to_string() }),
-                    ErrorKind::PermissionDenied => Ok(Status { $id, status_code: StatusCode::PermissionDenied, error_message: e.to_string(), language_tag: "en-US".to_string() }),
-                    ErrorKind::ConnectionReset => Ok(Status { $id, status_code: StatusCode::ConnectionLost, error_message: e.to_string(), language_tag: "en-US".to_string() }),
-                    ErrorKind::NotConnected => Ok(Status { $id, status_code: StatusCode::NoConnection, error_message: e.to_string(), language_tag: "en-US".to_string() }),
-                    _ => Ok(Status { $id, status_code: StatusCode::Failure, error_message: e.to_string(), language_tag: "en-US".to_string() })
-                }
+                Ok(Status { $id, status_code: status_code(&e), error_message: e.to_string(), language_tag: "en-US".to_string() })
             }
         }
     };
 }
 
+#[derive(Deserialize)]
+struct PathRequest {
+    path: String
+}
+
+#[derive(Deserialize)]
+struct TwoPathRequest {
+    oldpath: String,
+    newpath: String
+}
+
+#[derive(Deserialize)]
+struct HandleRequest {
+    handle: String
+}
+
+#[derive(Deserialize)]
+struct LsetstatRequest {
+    path: String,
+    attrs: FileAttributes
+}
+
+// used for both check-file-name and check-file-handle, target is a path or a handle
+#[derive(Deserialize)]
+struct CheckFileRequest {
+    target: String,
+    algorithms: String,
+    start: u64,
+    length: u64,
+    block_size: u32
+}
+
+#[derive(Serialize)]
+struct CheckFileReply {
+    name: &'static str,
+    algorithm: &'static str
+}
+
+// used for both md5-hash and md5-hash-handle
+#[derive(Deserialize)]
+struct Md5HashRequest {
+    target: String,
+    start: u64,
+    length: u64,
+    quick_check_hash: Vec<u8>
+}
+
+#[derive(Serialize)]
+struct Md5HashReply {
+    name: &'static str,
+    hash: Vec<u8>
+}
+
+#[derive(Deserialize)]
+struct CopyDataRequest {
+    read_from_handle: String,
+    read_from_offset: u64,
+    read_data_length: u64,
+    write_to_handle: String,
+    write_to_offset: u64
+}
+
+#[derive(Deserialize)]
+struct CopyFileRequest {
+    source: String,
+    destination: String,
+    // a boolean on the wire, which the deserializer has no support for
+    overwrite: u8
+}
+
+// both lists are packed back to back, uids and gids as 4 byte integers and names as strings
+#[derive(Deserialize)]
+struct UsersGroupsRequest {
+    uids: Vec<u8>,
+    gids: Vec<u8>
+}
+
+#[derive(Serialize)]
+struct UsersGroupsReply {
+    users: Vec<u8>,
+    groups: Vec<u8>
+}
+
+fn parse<T: DeserializeOwned>(data: Vec<u8>) -> Result<T, StatusCode> {
+    de::from_bytes(&mut data.into()).map_err(|_| StatusCode::BadMessage)
+}
+
+async fn hash(file: &OpenFile, algorithm: Algorithm, start: u64, length: u64, block_size: u32) -> Result<Vec<u8>, StatusCode> {
+    match checksum::hash_file(file.storage(), algorithm, start, length, block_size).await {
+        Ok(hash) => Ok(hash),
+        Err(e) => {
+            println!("error hashing file: {}", e);
+            Err(status_code(&e))
+        }
+    }
+}
+
+fn extended_reply<T: Serialize>(id: u32, reply: &T) -> Result<Packet, StatusCode> {
+    match ser::to_bytes(reply) {
+        Ok(data) => Ok(Packet::ExtendedReply(ExtendedReply { id, data: data.to_vec() })),
+        Err(_) => Err(StatusCode::Failure)
+    }
+}
+
+// the flag values are the ones OpenSSH defines for statvfs@openssh.com
+fn statvfs_reply(id: u32, st: FsStats) -> Result<Packet, StatusCode> {
+    let mut flags = 0;
+    if st.read_only {
+        flags |= 0x1;
+    }
+    if st.no_suid {
+        flags |= 0x2;
+    }
+    extended_reply(id, &Statvfs {
+        block_size: st.block_size,
+        fragment_size: st.fragment_size,
+        blocks: st.blocks,
+        blocks_free: st.blocks_free,
+        blocks_avail: st.blocks_avail,
+        inodes: st.files,
+        inodes_free: st.files_free,
+        inodes_avail: st.files_avail,
+        fs_id: st.fs_id,
+        flags,
+        name_max: st.name_max
+    })
+}
+
+fn status_code(e: &io::Error) -> StatusCode {
+    match e.kind() {
+        ErrorKind::NotFound => StatusCode::NoSuchFile,
+        ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        ErrorKind::ConnectionReset => StatusCode::ConnectionLost,
+        ErrorKind::NotConnected => StatusCode::NoConnection,
+        ErrorKind::Unsupported => StatusCode::OpUnsupported,
+        _ => StatusCode::Failure
+    }
+}
+
+// protocol version 3 only has 32 bit unsigned times, anything outside that range is clamped instead of wrapping
+fn wire_time(secs: i64) -> u32 {
+    secs.clamp(0, u32::MAX as i64) as u32
+}
+
+// permissions the way ls -l shows them, e.g. drwxr-xr-x
+fn mode_string(mode: u32) -> String {
+    let mut s = String::from(match mode & libc::S_IFMT {
+        libc::S_IFDIR => 'd',
+        libc::S_IFLNK => 'l',
+        libc::S_IFCHR => 'c',
+        libc::S_IFBLK => 'b',
+        libc::S_IFIFO => 'p',
+        libc::S_IFSOCK => 's',
+        _ => '-'
+    });
+    for (shift, special, special_char) in [(6, libc::S_ISUID, 's'), (3, libc::S_ISGID, 's'), (0, libc::S_ISVTX, 't')] {
+        let bits = mode >> shift;
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-'
+        });
+    }
+    s
+}
+
+fn pack_names(names: impl Iterator<Item = String>) -> Vec<u8> {
+    let mut packed = Vec::new();
+    for name in names {
+        packed.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        packed.extend_from_slice(name.as_bytes());
+    }
+    packed
+}
+
+// a listing and the entries that didn't fit in the previous reply
+struct DirHandle {
+    stream: Box<dyn DirStream>,
+    unread: Vec<DirEntry>
+}
+
 enum Handle {
-    Dir(ReadDir),
-    File(fs::File)
+    Dir(DirHandle),
+    // shared so reads and writes can run outside of the session while the handle stays open
+    File(Arc<OpenFile>)
+}
+
+// an upload written to a hidden file next to `target`, which replaces the target once the client closes it
+struct Upload {
+    temp: String,
+    target: String,
+    // with EXCLUDE the upload must not replace a file that appeared in the meantime
+    replace: bool
+}
+
+/// Reads at `offset` without using the file position, so any number of reads and writes on one handle can be in flight
+pub(crate) async fn read_file(file: &Arc<OpenFile>, id: u32, offset: u64, len: u32) -> Result<Data, StatusCode> {
+    let len = (len as u64).min(MAX_READ_WRITE_LEN) as usize;
+    match file.read(offset, len).await {
+        Ok(data) if data.is_empty() => Err(StatusCode::Eof),
+        Ok(data) => Ok(Data { id, data }),
+        Err(e) => {
+            println!("Error in reading from offset in file: {}", e);
+            Err(status_code(&e))
+        }
+    }
+}
+
+/// Writes at `offset` without using the file position, see read_file
+pub(crate) async fn write_file(file: &Arc<OpenFile>, id: u32, offset: u64, data: Vec<u8>) -> Result<Status, StatusCode> {
+    match_expr!(file.write(offset, data).await, "Error in writing at offset in file: {}", id)
+}
+
+fn open_options(pflags: OpenFlags) -> OpenOptions {
+    OpenOptions {
+        read: pflags.contains(OpenFlags::READ),
+        write: pflags.contains(OpenFlags::WRITE),
+        append: pflags.contains(OpenFlags::APPEND),
+        create: pflags.contains(OpenFlags::CREATE),
+        truncate: pflags.contains(OpenFlags::TRUNCATE),
+        exclusive: pflags.contains(OpenFlags::EXCLUDE)
+    }
 }
 
 pub struct SftpSession {
-    jail_dir: String,
-    cwd: String,
-    handles: HashMap<String, Handle>
+    storage: Arc<dyn StorageBackend>,
+    config: SftpConfig,
+    user: String,
+    // uid and gid of the user's root directory, reported for every file when owners are shown as the session user
+    owner: (u32, u32),
+    home: String,
+    handles: HashMap<String, Handle>,
+    next_handle: u64,
+    buffers: Arc<BufferPool>,
+    // uploads by handle when atomic uploads are enabled
+    uploads: HashMap<String, Upload>,
+    // what the user may do where, and the path every handle was opened with to check requests by handle
+    permissions: Permissions,
+    paths: HashMap<String, String>,
+    // files opened without read access, which the user's rules don't let them read through the handle either
+    write_only: HashSet<String>,
+    user_names: HashMap<u32, Option<String>>,
+    group_names: HashMap<u32, Option<String>>
 }
 
 impl SftpSession {
-    pub fn new(jail_dir: String) -> Self {
-        SftpSession { jail_dir, cwd: String::from("/"), handles: HashMap::new() }
+    pub(crate) fn new(storage: Arc<dyn StorageBackend>, config: SftpConfig, user: String, permissions: Permissions) -> io::Result<Self> {
+        let owner = storage.owner()?;
+        let buffers = Arc::new(BufferPool::new(config.write_buffer_size));
+        Ok(SftpSession {
+            storage,
+            config,
+            user,
+            owner,
+            home: String::from("/"),
+            handles: HashMap::new(),
+            next_handle: 0,
+            buffers,
+            uploads: HashMap::new(),
+            permissions,
+            paths: HashMap::new(),
+            write_only: HashSet::new(),
+            user_names: HashMap::new(),
+            group_names: HashMap::new()
+        })
+    }
+
+    fn user_name(&mut self, uid: u32) -> Option<String> {
+        match self.config.owner {
+            OwnerDisplay::Session if uid == self.owner.0 => Some(self.user.clone()),
+            OwnerDisplay::Session => None,
+            OwnerDisplay::Real => self.user_names.entry(uid).or_insert_with(|| users::system_user_name(uid)).clone()
+        }
+    }
+
+    fn group_name(&mut self, gid: u32) -> Option<String> {
+        match self.config.owner {
+            OwnerDisplay::Session if gid == self.owner.1 => Some(self.user.clone()),
+            OwnerDisplay::Session => None,
+            OwnerDisplay::Real => self.group_names.entry(gid).or_insert_with(|| users::system_group_name(gid)).clone()
+        }
+    }
+
+    // every attribute reply is built here so stat, readdir and realpath always agree
+    fn file_attributes(&mut self, stat: &FileStat) -> FileAttributes {
+        let (uid, gid) = match self.config.owner {
+            OwnerDisplay::Real => (stat.uid, stat.gid),
+            OwnerDisplay::Session => self.owner
+        };
+        FileAttributes {
+            size: Some(stat.size),
+            uid: Some(uid),
+            user: self.user_name(uid),
+            gid: Some(gid),
+            group: self.group_name(gid),
+            permissions: Some(stat.mode),
+            atime: Some(wire_time(stat.atime)),
+            mtime: Some(wire_time(stat.mtime))
+        }
+    }
+
+    fn format_time(&self, secs: i64) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+        let format = if secs <= now && now - secs < RECENT_SECS { "%b %e %H:%M" } else { "%b %e  %Y" };
+        let time = match self.config.timezone {
+            Timezone::Local => Local.timestamp_opt(secs, 0).single().map(|t| t.format(format).to_string()),
+            Timezone::Utc => Utc.timestamp_opt(secs, 0).single().map(|t| t.format(format).to_string()),
+            Timezone::Fixed(offset) => offset.timestamp_opt(secs, 0).single().map(|t| t.format(format).to_string())
+        };
+        time.unwrap_or_default()
+    }
+
+    // a name entry with an ls -l style longname, which some clients parse instead of the attributes
+    fn file_entry(&mut self, name: String, stat: &FileStat) -> File {
+        let attrs = self.file_attributes(stat);
+        let longname = format!(
+            "{} {:>3} {:<8} {:<8} {:>8} {} {}",
+            mode_string(stat.mode),
+            stat.nlink,
+            attrs.user.clone().unwrap_or_else(|| attrs.uid.unwrap_or(0).to_string()),
+            attrs.group.clone().unwrap_or_else(|| attrs.gid.unwrap_or(0).to_string()),
+            stat.size,
+            self.format_time(stat.mtime),
+            name
+        );
+        File { filename: name, longname, attrs }
+    }
+
+    fn check_handle_limit(&self) -> Result<(), StatusCode> {
+        if self.handles.len() >= self.config.max_handles {
+            println!("max open handles reached: {}", self.config.max_handles);
+            return Err(StatusCode::Failure)
+        }
+        Ok(())
+    }
+
+    // handles are opaque to the client and unique per session, so the same file can be opened more than once
+    fn insert_handle(&mut self, handle: Handle, path: &str) -> String {
+        self.next_handle += 1;
+        let key = format!("{:016x}", self.next_handle);
+        self.handles.insert(key.clone(), handle);
+        self.paths.insert(key.clone(), self.absolute(path));
+        key
+    }
+
+    /// Whether the file behind a handle may be read
+    pub(crate) fn readable(&self, handle: &str) -> bool {
+        !self.write_only.contains(handle)
+    }
+
+    // refuses what the user's permission rules don't allow on a path
+    async fn check(&self, permission: Permission, path: &str) -> Result<(), StatusCode> {
+        match self.permissions.check(&*self.storage, permission, &self.absolute(path)).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                println!("refused request: {}", e);
+                Err(status_code(&e))
+            }
+        }
+    }
+
+    // same for the path a handle was opened with
+    async fn check_handle(&self, permission: Permission, handle: &str) -> Result<(), StatusCode> {
+        let Some(path) = self.paths.get(handle) else {
+            println!("invalid handle: {}", handle);
+            return Err(StatusCode::Failure)
+        };
+        self.check(permission, path).await
+    }
+
+    // drop box users only get to see directories, files are refused whether they exist or not
+    fn visible(&self, stat: io::Result<FileStat>) -> io::Result<FileStat> {
+        match stat {
+            Ok(stat) if self.permissions.hides_files() && !stat.is_dir() => Err(io::Error::new(ErrorKind::PermissionDenied, "files are hidden")),
+            Err(_) if self.permissions.hides_files() => Err(io::Error::new(ErrorKind::PermissionDenied, "files are hidden")),
+            stat => stat
+        }
+    }
+
+    // writing to a file is an upload if the file is new and overwriting it otherwise
+    async fn check_write(&self, path: &str) -> Result<(), StatusCode> {
+        let exists = self.storage.stat(path, false).await.is_ok();
+        self.check(if exists { Permission::Overwrite } else { Permission::Upload }, path).await
+    }
+
+    // changing the size of a file overwrites it, owners count as permissions
+    async fn check_attributes(&self, path: &str, changes: &SetAttributes) -> Result<(), StatusCode> {
+        if changes.mode.is_some() || changes.uid.is_some() || changes.gid.is_some() {
+            self.check(Permission::Chmod, path).await?;
+        }
+        if changes.atime.is_some() || changes.mtime.is_some() {
+            self.check(Permission::Chtimes, path).await?;
+        }
+        if changes.size.is_some() {
+            self.check(Permission::Overwrite, path).await?;
+        }
+        Ok(())
+    }
+
+    /// The open file behind a handle, None if the handle is unknown or a directory
+    pub(crate) fn file(&self, handle: &str) -> Option<Arc<OpenFile>> {
+        match self.handles.get(handle) {
+            Some(Handle::File(file)) => Some(file.clone()),
+            _ => None
+        }
+    }
+
+    // a hidden name in the same directory, so the rename into place never crosses filesystems
+    fn temp_upload_path(path: &str) -> String {
+        let path = jail::normalize(path);
+        let (dir, name) = jail::split(&path);
+        let temp = format!(".{}.{:08x}.part", name, rand::random::<u32>());
+        if dir.is_empty() { temp } else { format!("{}/{}", dir, temp) }
+    }
+
+    async fn discard_upload(&self, upload: &Upload) {
+        if self.config.partial_uploads == PartialUploads::Keep {
+            return
+        }
+        if let Err(e) = self.storage.remove_file(&upload.temp).await {
+            println!("error removing partial upload: {}", e);
+        }
+    }
+
+    // moves a finished upload into place, or gets rid of it if its data couldn't be written
+    async fn finish_upload(&self, upload: Upload, flushed: io::Result<()>) -> io::Result<()> {
+        let result = match flushed {
+            Ok(()) => self.storage.rename(&upload.temp, &upload.target, upload.replace).await,
+            Err(e) => Err(e)
+        };
+        if result.is_err() {
+            self.discard_upload(&upload).await;
+        }
+        result
+    }
+
+    /// Called when the client goes away. Uploads it never closed are incomplete, other files
+    /// are closed like the client had done it
+    pub(crate) async fn close_all(&mut self) {
+        for (handle, upload) in std::mem::take(&mut self.uploads) {
+            if let Some(Handle::File(file)) = self.handles.remove(&handle) {
+                let _ = file.close().await;
+            }
+            println!("upload aborted: {}", upload.target);
+            self.discard_upload(&upload).await;
+        }
+        self.paths.clear();
+        self.write_only.clear();
+        for (_, handle) in self.handles.drain() {
+            let Handle::File(file) = handle else {
+                continue
+            };
+            if let Err(e) = file.close().await {
+                println!("error writing file: {}", e);
+            }
+        }
+    }
+
+    /// Writes out data held back from earlier writes on every open file, so requests by path see it.
+    /// Errors are left to be reported when the file is closed
+    pub(crate) async fn flush_writes(&self) {
+        for handle in self.handles.values() {
+            if let Handle::File(file) = handle {
+                let _ = file.flush().await;
+            }
+        }
+    }
+
+    // relative paths are relative to the user's home, `.` and `..` are collapsed without going above `/`
+    fn absolute(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            format!("/{}", jail::normalize(path))
+        }
+        else {
+            format!("/{}", jail::normalize(&format!("{}/{}", self.home, path)))
+        }
+    }
+
+    // turns client supplied attributes into the changes we are willing to make according to the config
+    fn attribute_changes(&self, attrs: &FileAttributes) -> Result<SetAttributes, StatusCode> {
+        let (uid, gid) = match self.config.chown {
+            ChownPolicy::Ignore => (None, None),
+            ChownPolicy::Reject if attrs.uid.is_some() || attrs.gid.is_some() => {
+                println!("rejected ownership change");
+                return Err(StatusCode::PermissionDenied)
+            }
+            ChownPolicy::Reject => (None, None),
+            ChownPolicy::Map => {
+                let uid = attrs.uid.map(|uid| self.config.uid_map.get(&uid.to_string()).copied().ok_or(StatusCode::PermissionDenied)).transpose()?;
+                let gid = attrs.gid.map(|gid| self.config.gid_map.get(&gid.to_string()).copied().ok_or(StatusCode::PermissionDenied)).transpose()?;
+                (uid, gid)
+            }
+        };
+        Ok(SetAttributes {
+            size: attrs.size,
+            uid,
+            gid,
+            mode: attrs.permissions.map(|mode| mode & 0o7777 & self.config.chmod_mask),
+            atime: attrs.atime.map(i64::from),
+            mtime: attrs.mtime.map(i64::from)
+        })
+    }
+
+    // mode for a new file or directory, the client's permissions go through the same mask as setstat
+    fn create_mode(&self, attrs: &FileAttributes, default: u32) -> u32 {
+        let mode = match attrs.permissions {
+            Some(permissions) if self.config.honor_client_permissions => permissions & 0o7777 & self.config.chmod_mask,
+            _ => default
+        };
+        mode & !self.config.umask
+    }
+
+    // opens the file a hash extension refers to, by path or by handle depending on the request
+    async fn hash_source(&self, request: &str, target: &str) -> Result<Arc<OpenFile>, StatusCode> {
+        if request == CHECK_FILE_HANDLE || request == MD5_HASH_HANDLE {
+            if !self.readable(target) {
+                return Err(StatusCode::PermissionDenied)
+            }
+            self.check_handle(Permission::Download, target).await?;
+            return self.file(target).ok_or_else(|| {
+                println!("invalid file handle: {}", target);
+                StatusCode::Failure
+            })
+        }
+        self.check(Permission::Download, target).await?;
+        match self.storage.open(target, OpenOptions { read: true, ..Default::default() }, 0).await {
+            Ok(file) => Ok(Arc::new(OpenFile::new(file, self.buffers.clone(), 0))),
+            Err(e) => {
+                println!("error opening file for hashing: {}", e);
+                Err(status_code(&e))
+            }
+        }
+    }
+
+    async fn canonical_name(&mut self, id: u32, path: &str) -> Result<Name, StatusCode> {
+        let mut path = self.absolute(path);
+        if self.config.realpath_resolve_symlinks {
+            path = match self.storage.canonicalize(&path).await {
+                Ok(path) => path,
+                Err(e) => {
+                    println!("error resolving path: {}", e);
+                    return Err(status_code(&e))
+                }
+            };
+        }
+        let file = match self.visible(self.storage.stat(&path, true).await) {
+            Ok(stat) => self.file_entry(path, &stat),
+            Err(_) => File::dummy(path)
+        };
+        Ok(Name { id, files: vec![file] })
     }
 }
 
@@ -48,26 +631,24 @@ impl SftpHandler for SftpSession {
         StatusCode::OpUnsupported
     }
     
+    async fn init(
+        &mut self,
+        _version: u32,
+        _extensions: HashMap<String, String>,
+    ) -> Result<Version, Self::Error> {
+        let mut version = Version::new();
+        for (name, data) in EXTENSIONS {
+            version.extensions.insert(name.to_string(), data.to_string());
+        }
+        Ok(version)
+    }
+
     async fn realpath(
         &mut self,
         id: u32,
         path: String,
     ) -> Result<Name, Self::Error> {
-
-        let re_1 = Regex::new(r"/[^/]+/\.\.").unwrap();
-        self.cwd = re_1.replace_all(&path, "").to_string();
-        while re_1.is_match(&self.cwd) {
-            self.cwd = re_1.replace_all(&self.cwd, "").to_string();
-        }
-        
-        let re_2 = Regex::new(r"/\.").unwrap();
-        self.cwd = re_2.replace_all(&self.cwd, "").to_string();
-
-        if self.cwd == "." || self.cwd == "" {
-            self.cwd = String::from("/");
-        }
-
-        Ok(Name { id, files: vec![File::dummy(&self.cwd)] })
+        self.canonical_name(id, &path).await
     }
 
     async fn open(
@@ -75,33 +656,46 @@ impl SftpHandler for SftpSession {
         id: u32,
         filename: String,
         pflags: OpenFlags,
-        _attrs: FileAttributes,
+        attrs: FileAttributes,
     ) -> Result<SftpHandle, Self::Error> {
-        let path = format!("{}{}", self.jail_dir, filename);
-        if pflags.contains(OpenFlags::EXCLUDE) && fs::metadata(&path).await.is_ok() {
-            return Err(StatusCode::Failure)
+        self.check_handle_limit()?;
+        if pflags.contains(OpenFlags::READ) {
+            self.check(Permission::Download, &filename).await?;
+        }
+        if pflags.intersects(OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE) {
+            self.check_write(&filename).await?;
         }
-        let mut options = OpenOptions::new();
-            options
-            .read(pflags.contains(OpenFlags::READ))
-            .write(pflags.contains(OpenFlags::WRITE))
-            .append(pflags.contains(OpenFlags::APPEND))
-            .create(pflags.contains(OpenFlags::CREATE))
-            .truncate(pflags.contains(OpenFlags::TRUNCATE));
-        match options.open(&path).await {
+        let mode = self.create_mode(&attrs, self.config.file_mode);
+        let atomic = self.config.atomic_uploads && pflags.contains(OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE);
+        let upload = if atomic {
+            let replace = !pflags.contains(OpenFlags::EXCLUDE);
+            if !replace && self.storage.stat(&filename, false).await.is_ok() {
+                return Err(StatusCode::Failure)
+            }
+            Some(Upload { temp: Self::temp_upload_path(&filename), target: filename.clone(), replace })
+        }
+        else {
+            None
+        };
+
+        let (path, options) = match &upload {
+            Some(upload) => (upload.temp.as_str(), OpenOptions { exclusive: true, ..open_options(pflags) }),
+            None => (filename.as_str(), open_options(pflags))
+        };
+        match self.storage.open(path, options, mode).await {
             Ok(file) =>  {
-                self.handles.insert(filename.clone(), Handle::File(file));
-                Ok(SftpHandle { id, handle: filename })
+                let handle = self.insert_handle(Handle::File(Arc::new(OpenFile::new(file, self.buffers.clone(), self.config.read_ahead_size))), &filename);
+                if !pflags.contains(OpenFlags::READ) && !self.permissions.is_empty() {
+                    self.write_only.insert(handle.clone());
+                }
+                if let Some(upload) = upload {
+                    self.uploads.insert(handle.clone(), upload);
+                }
+                Ok(SftpHandle { id, handle })
             }
             Err(e) => {
                 println!("error opeing file: {}", e);
-                match e.kind() {
-                    ErrorKind::NotFound => Err(StatusCode::NoSuchFile),
-                    ErrorKind::PermissionDenied => Err(StatusCode::PermissionDenied),
-                    ErrorKind::ConnectionReset => Err(StatusCode::ConnectionLost),
-                    ErrorKind::NotConnected => Err(StatusCode::NoConnection),
-                    _ => Err(StatusCode::Failure)
-                }
+                Err(status_code(&e))
             }
         }
     }
@@ -113,36 +707,14 @@ impl SftpHandler for SftpSession {
         offset: u64,
         len: u32,
     ) -> Result<Data, Self::Error> {
-        if let Handle::File(file) = self.handles.get_mut(&handle).unwrap() {
-            let mut buf = vec![0u8; len as usize];
-            match file.seek(SeekFrom::Start(offset)).await {
-                Ok(_) => {
-                    match file.read(&mut buf).await {
-                        Ok(bytes) => {
-                            if bytes != 0 {
-                                buf.truncate(bytes);
-                                Ok(Data { id, data: buf })
-                            }
-                            else {
-                                Err(StatusCode::Eof)
-                            }
-                        }
-                        Err(e) => {
-                            println!("Error in reading from offset in file: {}", e);
-                            Err(StatusCode::Failure)
-                        }
-                    }
-                }
-                Err(e) => {
-                    println!("Error in seeking offset in file: {}", e);
-                    Err(StatusCode::Failure)
-                }
-            }
-        }
-        else {
-            println!("handle is not a filehandle");
-            Err(StatusCode::Failure)
+        let Some(file) = self.file(&handle) else {
+            println!("invalid file handle: {}", handle);
+            return Err(StatusCode::Failure)
+        };
+        if !self.readable(&handle) {
+            return Err(StatusCode::PermissionDenied)
         }
+        read_file(&file, id, offset, len).await
     }
 
     async fn write(
@@ -152,34 +724,43 @@ impl SftpHandler for SftpSession {
         offset: u64,
         data: Vec<u8>,
     ) -> Result<Status, Self::Error> {
-        if let Handle::File(file) = self.handles.get_mut(&handle).unwrap() {
-            match file.seek(SeekFrom::Start(offset)).await {
-                Ok(_) => {
-                    match file.write_all(&data).await {
-                        Ok(()) => {
-                            Ok(Status {
-                                id,
-                                status_code: StatusCode::Ok,
-                                error_message: "Ok".to_string(),
-                                language_tag: "en-US".to_string(),
-                            })
-                        }
-                        Err(e) => {
-                            println!("Error in writing at offset in file: {}", e);
-                            Ok(Status { id, status_code: StatusCode::Failure, error_message: e.to_string(), language_tag: "en-US".to_string() })
-                        }
-                    }   
-                }
-                Err(e) => {
-                    println!("Error in seeking offset in file: {}", e);
-                    Ok(Status { id, status_code: StatusCode::Failure, error_message: e.to_string(), language_tag: "en-US".to_string() })
-                }
-            }
-        }
-        else {
-            println!("handle is not a filehandle");
-            Err(StatusCode::Failure)
+        let Some(file) = self.file(&handle) else {
+            println!("invalid file handle: {}", handle);
+            return Err(StatusCode::Failure)
+        };
+        write_file(&file, id, offset, data).await
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let changes = self.attribute_changes(&attrs)?;
+        self.check_attributes(&path, &changes).await?;
+        match_expr!(self.storage.set_attributes(&path, changes, true).await, "error setting attributes: {}", id)
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let changes = self.attribute_changes(&attrs)?;
+        if let Some(path) = self.paths.get(&handle) {
+            self.check_attributes(path, &changes).await?;
         }
+        let result = match self.handles.get(&handle) {
+            Some(Handle::File(file)) => file.storage().set_attributes(changes).await,
+            Some(Handle::Dir(dir)) => dir.stream.set_attributes(changes).await,
+            None => {
+                println!("invalid handle: {}", handle);
+                return Err(StatusCode::Failure)
+            }
+        };
+        match_expr!(result, "error setting attributes: {}", id)
     }
 
     async fn opendir(
@@ -187,16 +768,17 @@ impl SftpHandler for SftpSession {
         id: u32,
         path: String,
     ) -> Result<SftpHandle, Self::Error> {
-        let path = format!("{}{}", self.jail_dir, path);
-        match fs::read_dir(&path).await {
-            Ok(entries) => {
-                self.handles.insert(path.clone(), Handle::Dir(entries));
-                Ok(SftpHandle { id, handle: path })
+        self.check_handle_limit()?;
+        self.check(Permission::List, &path).await?;
+        match self.storage.list(&path, self.config.sort_directory_listings, self.config.max_directory_entries).await {
+            Ok(stream) => {
+                let handle = self.insert_handle(Handle::Dir(DirHandle { stream, unread: Vec::new() }), &path);
+                Ok(SftpHandle { id, handle })
             }
             Err(e) => {
                 println!("Error in reading dir: {}", e);
-                Err(StatusCode::NoSuchFile)
-            } 
+                Err(status_code(&e))
+            }
         }
     }
 
@@ -205,38 +787,40 @@ impl SftpHandler for SftpSession {
         id: u32,
         handle: String,
     ) -> Result<Name, Self::Error> {
-        println!("readdir called");
-        if let Handle::Dir(handle) = self.handles.get_mut(&handle).unwrap() {
-            match handle.next_entry().await {
-                Ok(Some(entry)) => {
-                    let metadata = entry.metadata().await.unwrap();
-                    let dt = Local.timestamp_opt(metadata.mtime(), 0).unwrap();
-                    let longname = format!("{} {} {}", metadata.size(), dt.format("%b %e %Y"), entry.file_name().to_string_lossy());
-                    Ok(Name { id, files: vec![
-                        File {
-                            filename: entry.file_name().to_string_lossy().into(),
-                            longname: longname,
-                            attrs: FileAttributes {
-                                size: Some(metadata.size()),
-                                permissions: Some(metadata.mode()),
-                                atime: Some(metadata.atime() as u32),
-                                mtime: Some(metadata.mtime() as u32),
-                                ..Default::default()
-                            }
-                        }
-                    ] })
-                }
-                Ok(None) => Err(StatusCode::Eof),
+        let mut files = Vec::new();
+        let mut size = 0;
+        loop {
+            let Some(Handle::Dir(dir)) = self.handles.get_mut(&handle) else {
+                println!("invalid dir handle: {}", handle);
+                return Err(StatusCode::Failure)
+            };
+            let unread = std::mem::take(&mut dir.unread);
+            let next = if unread.is_empty() { dir.stream.next_entries(READDIR_BATCH).await } else { Ok(unread) };
+            let mut entries = match next {
+                Ok(entries) if entries.is_empty() => break,
+                Ok(entries) => entries.into_iter(),
                 Err(e) => {
-                    println!("Error listing file: {}", e);
-                    Err(StatusCode::Failure)
+                    println!("error listing directory: {}", e);
+                    return Err(status_code(&e))
+                }
+            };
+            while let Some(entry) = entries.next() {
+                let file = self.file_entry(entry.name.clone(), &entry.stat);
+                size += file.filename.len() + file.longname.len() + NAME_ENTRY_OVERHEAD;
+                if size > MAX_READ_WRITE_LEN as usize && !files.is_empty() {
+                    // whatever doesn't fit in this reply is sent with the next one
+                    if let Some(Handle::Dir(dir)) = self.handles.get_mut(&handle) {
+                        dir.unread = std::iter::once(entry).chain(entries).collect();
+                    }
+                    return Ok(Name { id, files })
                 }
+                files.push(file);
             }
         }
-        else {
-            println!("handle is not a dirhandle");
-            Err(StatusCode::Failure)
+        if files.is_empty() {
+            return Err(StatusCode::Eof)
         }
+        Ok(Name { id, files })
     }
 
     async fn close(
@@ -244,13 +828,25 @@ impl SftpHandler for SftpSession {
         id: u32,
         handle: String,
     ) -> Result<Status, Self::Error> {
-        self.handles.remove(&handle);
-        Ok(Status {
-            id,
-            status_code: StatusCode::Ok,
-            error_message: "Ok".to_string(),
-            language_tag: "en-US".to_string(),
-        })
+        self.paths.remove(&handle);
+        self.write_only.remove(&handle);
+        match self.handles.remove(&handle) {
+            // buffered writes that fail now are the client's last chance to hear about it
+            Some(Handle::File(file)) => match self.uploads.remove(&handle) {
+                Some(upload) => match_expr!(self.finish_upload(upload, file.close().await).await, "error finishing upload: {}", id),
+                None => match_expr!(file.close().await, "error writing file: {}", id)
+            },
+            Some(Handle::Dir(_)) => Ok(Status {
+                id,
+                status_code: StatusCode::Ok,
+                error_message: "Ok".to_string(),
+                language_tag: "en-US".to_string(),
+            }),
+            None => {
+                println!("invalid handle: {}", handle);
+                Err(StatusCode::Failure)
+            }
+        }
     }
 
     async fn stat(
@@ -258,16 +854,10 @@ impl SftpHandler for SftpSession {
         id: u32,
         path: String,
     ) -> Result<Attrs, Self::Error> {
-        let path = format!("{}{}", self.jail_dir, path);
-        match fs::metadata(path).await {
-            Ok(metadata) => Ok(Attrs { id, attrs: FileAttributes {
-                size: Some(metadata.size()),
-                permissions: Some(metadata.mode()),
-                atime: Some(metadata.atime() as u32),
-                mtime: Some(metadata.mtime() as u32),
-                ..Default::default()
-            }}),
-            Err(_) => Err(StatusCode::NoSuchFile)
+        match self.visible(self.storage.stat(&path, true).await) {
+            Ok(stat) => Ok(Attrs { id, attrs: self.file_attributes(&stat) }),
+            // dangling links are NoSuchFile, links leading out of the jail PermissionDenied
+            Err(e) => Err(status_code(&e))
         }
     }
 
@@ -276,18 +866,10 @@ impl SftpHandler for SftpSession {
         id: u32,
         path: String,
     ) -> Result<Attrs, Self::Error> {
-        let path = format!("{}{}", self.jail_dir, path);
-        match fs::symlink_metadata(path).await {
-            Ok(metadata) => Ok(Attrs { id, attrs: FileAttributes {
-                size: Some(metadata.size()),
-                permissions: Some(metadata.mode()),
-                atime: Some(metadata.atime() as u32),
-                mtime: Some(metadata.mtime() as u32),
-                ..Default::default()
-            }}),
-            Err(_) => Err(StatusCode::OpUnsupported)
+        match self.visible(self.storage.stat(&path, false).await) {
+            Ok(stat) => Ok(Attrs { id, attrs: self.file_attributes(&stat) }),
+            Err(e) => Err(status_code(&e))
         }
-
     }
 
     async fn fstat(
@@ -295,18 +877,17 @@ impl SftpHandler for SftpSession {
         id: u32,
         handle: String,
     ) -> Result<Attrs, Self::Error> {
-        if let Handle::File(file) = self.handles.get(&handle).unwrap() {
-            let metadata = file.metadata().await.unwrap();
-            Ok(Attrs { id, attrs: FileAttributes {
-                size: Some(metadata.size()),
-                permissions: Some(metadata.mode()),
-                atime: Some(metadata.atime() as u32),
-                mtime: Some(metadata.mtime() as u32),
-                ..Default::default()
-            }})
+        if let Some(file) = self.file(&handle) {
+            match self.visible(file.storage().stat().await) {
+                Ok(stat) => Ok(Attrs { id, attrs: self.file_attributes(&stat) }),
+                Err(e) => {
+                    println!("error getting file metadata: {}", e);
+                    Err(status_code(&e))
+                }
+            }
         }
         else {
-            println!("handle is not a filehandle");
+            println!("invalid file handle: {}", handle);
             Err(StatusCode::Failure)
         }
         
@@ -317,18 +898,19 @@ impl SftpHandler for SftpSession {
         id: u32,
         filename: String,
     ) -> Result<Status, Self::Error> {
-        let path = format!("{}{}", self.jail_dir, filename);
-        match_expr!(fs::remove_file(path).await, "error removing file: {}", id)
+        self.check(Permission::Delete, &filename).await?;
+        match_expr!(self.storage.remove_file(&filename).await, "error removing file: {}", id)
     }
 
     async fn mkdir(
         &mut self,
         id: u32,
         path: String,
-        _attrs: FileAttributes,
+        attrs: FileAttributes,
     ) -> Result<Status, Self::Error> {
-        let path = format!("{}{}", self.jail_dir, path);
-        match_expr!(fs::create_dir(path).await, "error creating dir: {}", id)
+        self.check(Permission::Mkdir, &path).await?;
+        let mode = self.create_mode(&attrs, self.config.dir_mode);
+        match_expr!(self.storage.create_dir(&path, mode).await, "error creating dir: {}", id)
     }
 
     async fn rmdir(
@@ -336,8 +918,8 @@ impl SftpHandler for SftpSession {
         id: u32,
         path: String,
     ) -> Result<Status, Self::Error> {
-        let path = format!("{}{}", self.jail_dir, path);
-        match_expr!(fs::remove_dir(path).await, "error removing file: {}", id)
+        self.check(Permission::Delete, &path).await?;
+        match_expr!(self.storage.remove_dir(&path).await, "error removing file: {}", id)
     }
 
     async fn rename(
@@ -346,10 +928,225 @@ impl SftpHandler for SftpSession {
         oldpath: String,
         newpath: String,
     ) -> Result<Status, Self::Error> {
-        let oldpath = format!("{}{}", self.jail_dir, oldpath);
-        let newpath = format!("{}{}", self.jail_dir, newpath);
-        match_expr!(fs::rename(oldpath, newpath).await, "error renaming file: {}", id)
+        self.check(Permission::Rename, &oldpath).await?;
+        self.check(Permission::Rename, &newpath).await?;
+        // plain SFTP rename must not overwrite, clients that want that use posix-rename@openssh.com
+        match_expr!(self.storage.rename(&oldpath, &newpath, false).await, "error renaming file: {}", id)
     }
 
-}
+    async fn readlink(
+        &mut self,
+        id: u32,
+        path: String,
+    ) -> Result<Name, Self::Error> {
+        self.check(Permission::List, &path).await?;
+        // a link is a file to drop box users, where it leads is hidden like anything else about it
+        if let Err(e) = self.visible(self.storage.stat(&path, false).await) {
+            println!("error reading symlink: {}", e);
+            return Err(status_code(&e))
+        }
+        let path = jail::normalize(&self.absolute(&path));
+        match self.storage.read_link(&path).await {
+            Ok(target) => {
+                // don't reveal where links leading out of the jail point to
+                if self.config.symlinks != SymlinkPolicy::Allow && jail::link_target(jail::split(&path).0, &target).is_none() {
+                    println!("refusing to read symlink leading outside the jail: {}", path);
+                    return Err(StatusCode::PermissionDenied)
+                }
+                Ok(Name { id, files: vec![File::dummy(target)] })
+            }
+            Err(e) => {
+                println!("error reading symlink: {}", e);
+                Err(status_code(&e))
+            }
+        }
+    }
 
+    async fn symlink(
+        &mut self,
+        id: u32,
+        linkpath: String,
+        targetpath: String,
+    ) -> Result<Status, Self::Error> {
+        // OpenSSH sends the target first and the link second, the reverse of the draft,
+        // every client follows OpenSSH here so the fields are swapped
+        let (target, link) = (linkpath, targetpath);
+        let link = jail::normalize(&self.absolute(&link));
+        self.check(Permission::Symlink, &link).await?;
+        let target = match self.config.symlinks {
+            SymlinkPolicy::Deny => {
+                println!("refusing to create symlink: {}", link);
+                return Err(StatusCode::PermissionDenied)
+            }
+            SymlinkPolicy::Allow => target,
+            SymlinkPolicy::WithinJail => {
+                // stored relative to the link so it resolves the same way inside the jail and on the host
+                let dir = jail::split(&link).0;
+                let target = if target.starts_with('/') { jail::normalize(&target) } else { jail::normalize(&format!("{}/{}", dir, target)) };
+                jail::relative_path(dir, &target)
+            }
+        };
+        match_expr!(self.storage.symlink(&target, &link).await, "error creating symlink: {}", id)
+    }
+
+    async fn extended(
+        &mut self,
+        id: u32,
+        request: String,
+        data: Vec<u8>,
+    ) -> Result<Packet, Self::Error> {
+        match request.as_str() {
+            EXPAND_PATH => {
+                let PathRequest { path } = parse(data)?;
+                let path = if path == "~" {
+                    self.home.clone()
+                }
+                else if let Some(rest) = path.strip_prefix("~/") {
+                    format!("{}/{}", self.home, rest)
+                }
+                else if path.starts_with('~') {
+                    // other users' homes are never visible from inside a jail
+                    return Err(StatusCode::NoSuchFile)
+                }
+                else {
+                    path
+                };
+                Ok(Packet::Name(self.canonical_name(id, &path).await?))
+            }
+            POSIX_RENAME => {
+                let TwoPathRequest { oldpath, newpath } = parse(data)?;
+                self.check(Permission::Rename, &oldpath).await?;
+                self.check(Permission::Rename, &newpath).await?;
+                // replacing a file overwrites it
+                if self.storage.stat(&newpath, false).await.is_ok() {
+                    self.check(Permission::Overwrite, &newpath).await?;
+                }
+                match_expr!(self.storage.rename(&oldpath, &newpath, true).await, "error renaming file: {}", id).map(Packet::Status)
+            }
+            extensions::HARDLINK => {
+                let TwoPathRequest { oldpath, newpath } = parse(data)?;
+                // a hard link is a new file with the contents of the old one, readable under the new name
+                self.check(Permission::Download, &oldpath).await?;
+                self.check(Permission::Upload, &newpath).await?;
+                match_expr!(self.storage.hard_link(&oldpath, &newpath).await, "error creating hardlink: {}", id).map(Packet::Status)
+            }
+            extensions::FSYNC => {
+                let HandleRequest { handle } = parse(data)?;
+                let Some(file) = self.file(&handle) else {
+                    println!("invalid file handle: {}", handle);
+                    return Err(StatusCode::Failure)
+                };
+                match_expr!(file.storage().sync().await, "error syncing file: {}", id).map(Packet::Status)
+            }
+            extensions::STATVFS => {
+                let PathRequest { path } = parse(data)?;
+                match self.storage.statvfs(&path).await {
+                    Ok(st) => statvfs_reply(id, st),
+                    Err(e) => {
+                        println!("error getting filesystem stats: {}", e);
+                        Err(status_code(&e))
+                    }
+                }
+            }
+            FSTATVFS => {
+                let HandleRequest { handle } = parse(data)?;
+                let stats = match self.handles.get(&handle) {
+                    Some(Handle::File(file)) => file.storage().statvfs().await,
+                    Some(Handle::Dir(dir)) => dir.stream.statvfs().await,
+                    None => {
+                        println!("invalid handle: {}", handle);
+                        return Err(StatusCode::Failure)
+                    }
+                };
+                match stats {
+                    Ok(st) => statvfs_reply(id, st),
+                    Err(e) => {
+                        println!("error getting filesystem stats: {}", e);
+                        Err(status_code(&e))
+                    }
+                }
+            }
+            LSETSTAT => {
+                let LsetstatRequest { path, attrs } = parse(data)?;
+                let changes = self.attribute_changes(&attrs)?;
+                self.check_attributes(&path, &changes).await?;
+                match_expr!(self.storage.set_attributes(&path, changes, false).await, "error setting attributes: {}", id).map(Packet::Status)
+            }
+            extensions::LIMITS => {
+                extended_reply(id, &LimitsExtension {
+                    max_packet_len: MAX_PACKET_LEN,
+                    max_read_len: MAX_READ_WRITE_LEN,
+                    max_write_len: MAX_READ_WRITE_LEN,
+                    max_open_handles: self.config.max_handles as u64
+                })
+            }
+            CHECK_FILE_NAME | CHECK_FILE_HANDLE => {
+                let CheckFileRequest { target, algorithms, start, length, block_size } = parse(data)?;
+                let Some(algorithm) = algorithms.split(',').find_map(Algorithm::from_name) else {
+                    return Err(StatusCode::OpUnsupported)
+                };
+                if block_size != 0 && block_size < MIN_CHECK_BLOCK_SIZE {
+                    return Err(StatusCode::Failure)
+                }
+                let file = self.hash_source(&request, &target).await?;
+                let hash = hash(&file, algorithm, start, length, block_size).await?;
+
+                let mut reply = ser::to_bytes(&CheckFileReply { name: "check-file", algorithm: algorithm.name() })
+                    .map_err(|_| StatusCode::Failure)?.to_vec();
+                // the hashes follow without a length prefix
+                reply.extend_from_slice(&hash);
+                Ok(Packet::ExtendedReply(ExtendedReply { id, data: reply }))
+            }
+            MD5_HASH | MD5_HASH_HANDLE => {
+                let Md5HashRequest { target, start, length, quick_check_hash } = parse(data)?;
+                let file = self.hash_source(&request, &target).await?;
+                if !quick_check_hash.is_empty() {
+                    let quick_len = if length == 0 { QUICK_CHECK_LEN } else { length.min(QUICK_CHECK_LEN) };
+                    // an empty hash tells the client the quick check did not match
+                    if hash(&file, Algorithm::Md5, start, quick_len, 0).await? != quick_check_hash {
+                        return extended_reply(id, &Md5HashReply { name: MD5_HASH, hash: Vec::new() })
+                    }
+                }
+                let hash = hash(&file, Algorithm::Md5, start, length, 0).await?;
+                extended_reply(id, &Md5HashReply { name: MD5_HASH, hash })
+            }
+            COPY_DATA => {
+                let CopyDataRequest { read_from_handle, read_from_offset, read_data_length, write_to_handle, write_to_offset } = parse(data)?;
+                if read_from_handle == write_to_handle {
+                    let read_end = if read_data_length == 0 { u64::MAX } else { read_from_offset.saturating_add(read_data_length) };
+                    let write_end = write_to_offset.saturating_add(read_end - read_from_offset);
+                    if read_from_offset < write_end && write_to_offset < read_end {
+                        return Err(StatusCode::Failure)
+                    }
+                }
+                let (Some(from), Some(to)) = (self.file(&read_from_handle), self.file(&write_to_handle)) else {
+                    println!("invalid file handle: {} or {}", read_from_handle, write_to_handle);
+                    return Err(StatusCode::Failure)
+                };
+                if !self.readable(&read_from_handle) {
+                    return Err(StatusCode::PermissionDenied)
+                }
+                match_expr!(
+                    storage::copy_data(from.storage(), read_from_offset, read_data_length, to.storage(), write_to_offset).await,
+                    "error copying data: {}",
+                    id
+                ).map(Packet::Status)
+            }
+            COPY_FILE => {
+                let CopyFileRequest { source, destination, overwrite } = parse(data)?;
+                self.check(Permission::Download, &source).await?;
+                self.check_write(&destination).await?;
+                match_expr!(self.storage.copy_file(&source, &destination, overwrite != 0).await, "error copying file: {}", id).map(Packet::Status)
+            }
+            USERS_GROUPS_BY_ID => {
+                let UsersGroupsRequest { uids, gids } = parse(data)?;
+                // ids without a name get an empty string, like OpenSSH does
+                let users = pack_names(uids.chunks_exact(4).map(|uid| self.user_name(u32::from_be_bytes(uid.try_into().unwrap())).unwrap_or_default()));
+                let groups = pack_names(gids.chunks_exact(4).map(|gid| self.group_name(u32::from_be_bytes(gid.try_into().unwrap())).unwrap_or_default()));
+                extended_reply(id, &UsersGroupsReply { users, groups })
+            }
+            _ => Err(StatusCode::OpUnsupported)
+        }
+    }
+
+}
@@ -0,0 +1,229 @@
+use std::{fs::Metadata, future::Future, io::{self, ErrorKind}, os::{fd::AsFd, unix::fs::MetadataExt}, pin::Pin};
+
+use crate::jail;
+
+// data copied at once when a backend can't copy between files itself
+const COPY_BUF_SIZE: usize = 64 * 1024;
+
+/// Future returned by backends, boxed so the traits can be used as trait objects
+pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+fn unsupported<'a, T: Send + 'a>() -> BoxFuture<'a, io::Result<T>> {
+    Box::pin(async { Err(io::Error::from(ErrorKind::Unsupported)) })
+}
+
+/// Attributes of a file or directory as a backend reports them, times are seconds since the epoch
+#[derive(Clone, Copy, Default)]
+pub(crate) struct FileStat {
+    pub(crate) size: u64,
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) nlink: u64,
+    pub(crate) atime: i64,
+    pub(crate) mtime: i64
+}
+
+impl FileStat {
+    pub(crate) fn is_file(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFREG
+    }
+
+    pub(crate) fn is_dir(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFDIR
+    }
+}
+
+impl From<&Metadata> for FileStat {
+    fn from(metadata: &Metadata) -> Self {
+        FileStat {
+            size: metadata.size(),
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            nlink: metadata.nlink(),
+            atime: metadata.atime(),
+            mtime: metadata.mtime()
+        }
+    }
+}
+
+/// Attribute changes requested by a client, None leaves the attribute as it is
+#[derive(Clone, Copy, Default)]
+pub(crate) struct SetAttributes {
+    pub(crate) size: Option<u64>,
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    pub(crate) mode: Option<u32>,
+    pub(crate) atime: Option<i64>,
+    pub(crate) mtime: Option<i64>
+}
+
+/// Statistics of the filesystem a file lives on, as reported by statvfs@openssh.com
+#[derive(Clone, Copy, Default)]
+pub(crate) struct FsStats {
+    pub(crate) block_size: u64,
+    pub(crate) fragment_size: u64,
+    pub(crate) blocks: u64,
+    pub(crate) blocks_free: u64,
+    pub(crate) blocks_avail: u64,
+    pub(crate) files: u64,
+    pub(crate) files_free: u64,
+    pub(crate) files_avail: u64,
+    pub(crate) fs_id: u64,
+    pub(crate) read_only: bool,
+    pub(crate) no_suid: bool,
+    pub(crate) name_max: u64
+}
+
+/// How a file is opened, `exclusive` fails if the file already exists
+#[derive(Clone, Copy, Default)]
+pub(crate) struct OpenOptions {
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    pub(crate) append: bool,
+    pub(crate) create: bool,
+    pub(crate) truncate: bool,
+    pub(crate) exclusive: bool
+}
+
+pub(crate) struct DirEntry {
+    pub(crate) name: String,
+    pub(crate) stat: FileStat
+}
+
+/// A file opened through a backend. Reads and writes take an offset so any number of them can run at once
+pub(crate) trait StorageFile: Send + Sync {
+    /// Reads up to `len` bytes at `offset`, less only at the end of the file
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>>;
+
+    /// Writes all of `data` at `offset`. The buffer is handed back, also when the write failed, so it can be reused
+    fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<'_, (Vec<u8>, io::Result<()>)>;
+
+    fn stat(&self) -> BoxFuture<'_, io::Result<FileStat>>;
+
+    fn set_attributes(&self, attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>>;
+
+    fn sync(&self) -> BoxFuture<'_, io::Result<()>>;
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        unsupported()
+    }
+
+    /// Called once when the client is done with the file, backends that store data only at the end do it here
+    fn close(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Hint that `len` bytes at `offset` are about to be read
+    fn will_need(&self, _offset: u64, _len: u64) {}
+
+    /// The file on the local disk, so copies between two such files can be done by the kernel
+    fn as_local(&self) -> Option<&std::fs::File> {
+        None
+    }
+}
+
+/// An open directory listing, `.` and `..` included
+pub(crate) trait DirStream: Send + Sync {
+    /// Up to `count` of the remaining entries, none once the listing is exhausted
+    fn next_entries(&mut self, count: usize) -> BoxFuture<'_, io::Result<Vec<DirEntry>>>;
+
+    fn set_attributes(&self, _attrs: SetAttributes) -> BoxFuture<'_, io::Result<()>> {
+        unsupported()
+    }
+
+    fn statvfs(&self) -> BoxFuture<'_, io::Result<FsStats>> {
+        unsupported()
+    }
+}
+
+/// Where a user's files are stored. Paths are absolute or relative to the user's root and never lead outside of it,
+/// `follow` decides whether a symlink as the final component is followed
+pub(crate) trait StorageBackend: Send + Sync {
+    /// Owner and group of the user's root directory
+    fn owner(&self) -> io::Result<(u32, u32)>;
+
+    /// Resolves symlinks in a path, the result is an absolute path. The final component is allowed to not exist
+    fn canonicalize<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<String>>;
+
+    fn open<'a>(&'a self, path: &'a str, options: OpenOptions, mode: u32) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>>;
+
+    fn stat<'a>(&'a self, path: &'a str, follow: bool) -> BoxFuture<'a, io::Result<FileStat>>;
+
+    fn set_attributes<'a>(&'a self, path: &'a str, attrs: SetAttributes, follow: bool) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Opens a directory listing. At most `limit` entries besides `.` and `..` are listed when it is not 0,
+    /// with `sort` they are ordered by name
+    fn list<'a>(&'a self, path: &'a str, sort: bool, limit: usize) -> BoxFuture<'a, io::Result<Box<dyn DirStream>>>;
+
+    fn remove_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+    fn create_dir<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, io::Result<()>>;
+
+    fn remove_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Renames `from` to `to`, an existing `to` is only replaced if `replace` is set
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str, replace: bool) -> BoxFuture<'a, io::Result<()>>;
+
+    fn statvfs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<FsStats>>;
+
+    fn read_link<'a>(&'a self, _path: &'a str) -> BoxFuture<'a, io::Result<String>> {
+        unsupported()
+    }
+
+    /// Creates a symlink at `path`, the target is stored as given
+    fn symlink<'a>(&'a self, _target: &'a str, _path: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        unsupported()
+    }
+
+    fn hard_link<'a>(&'a self, _from: &'a str, _to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        unsupported()
+    }
+
+    /// Copies a regular file, by default by reading it and writing the copy
+    fn copy_file<'a>(&'a self, from: &'a str, to: &'a str, overwrite: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            // truncating the destination would otherwise destroy the source
+            if matches!((self.canonicalize(from).await, self.canonicalize(to).await), (Ok(from), Ok(to)) if from == to) {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "source and destination are the same file"))
+            }
+            let source = self.open(from, OpenOptions { read: true, ..Default::default() }, 0).await?;
+            let st = source.stat().await?;
+            if !st.is_file() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "not a regular file"))
+            }
+            let options = OpenOptions { write: true, create: true, truncate: true, exclusive: !overwrite, ..Default::default() };
+            let dest = self.open(to, options, st.mode & 0o777).await?;
+            // the destination is closed even if the copy failed, so no upload is left behind for it
+            let result = copy_between(&*source, 0, 0, &*dest, 0).await;
+            result.and(dest.close().await)
+        })
+    }
+}
+
+// reads and writes one buffer at a time, a length of 0 copies up to the end of `from`
+async fn copy_between(from: &dyn StorageFile, mut from_offset: u64, length: u64, to: &dyn StorageFile, mut to_offset: u64) -> io::Result<()> {
+    let mut remaining = if length == 0 { u64::MAX } else { length };
+    while remaining > 0 {
+        let data = from.read_at(from_offset, remaining.min(COPY_BUF_SIZE as u64) as usize).await?;
+        if data.is_empty() {
+            break
+        }
+        let copied = data.len() as u64;
+        to.write_at(to_offset, data).await.1?;
+        from_offset += copied;
+        to_offset += copied;
+        remaining -= copied;
+    }
+    Ok(())
+}
+
+/// Copies `length` bytes (0 means up to the end of the file) between two open files,
+/// inside the kernel when both are on the local disk
+pub(crate) async fn copy_data(from: &dyn StorageFile, from_offset: u64, length: u64, to: &dyn StorageFile, to_offset: u64) -> io::Result<()> {
+    if let (Some(from), Some(to)) = (from.as_local(), to.as_local()) {
+        return jail::copy_data(from.as_fd(), from_offset, length, to.as_fd(), to_offset).await
+    }
+    copy_between(from, from_offset, length, to, to_offset).await
+}
@@ -0,0 +1,72 @@
+use std::{ffi::CStr, mem, ptr};
+
+use libc::{c_char, c_int};
+use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::{Normalization, UsersConfig};
+
+/// Returns true if `name` can be used as a single directory name inside the jail
+pub(crate) fn is_safe_dir_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+// runs one of the reentrant passwd/group lookups, growing the buffer until the entry fits
+fn lookup_name<T>(lookup: impl Fn(*mut T, *mut c_char, usize, *mut *mut T) -> c_int, name: impl Fn(&T) -> *const c_char) -> Option<String> {
+    let mut buf: Vec<c_char> = vec![0; 1024];
+    loop {
+        let mut entry: T = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        match lookup(&mut entry, buf.as_mut_ptr(), buf.len(), &mut result) {
+            libc::ERANGE if buf.len() < 1024 * 1024 => buf.resize(buf.len() * 2, 0),
+            0 if !result.is_null() => return Some(unsafe { CStr::from_ptr(name(&entry)) }.to_string_lossy().into_owned()),
+            _ => return None
+        }
+    }
+}
+
+/// Name of the system user with this uid, None if there is none
+pub(crate) fn system_user_name(uid: u32) -> Option<String> {
+    lookup_name(|pwd, buf, len, result| unsafe { libc::getpwuid_r(uid, pwd, buf, len, result) }, |pwd: &libc::passwd| pwd.pw_name)
+}
+
+/// Name of the system group with this gid, None if there is none
+pub(crate) fn system_group_name(gid: u32) -> Option<String> {
+    lookup_name(|grp, buf, len, result| unsafe { libc::getgrgid_r(gid, grp, buf, len, result) }, |grp: &libc::group| grp.gr_name)
+}
+
+/// Rules every username has to pass before it is used in a query or mapped to a directory
+pub(crate) struct UsernamePolicy {
+    regex: Regex,
+    max_length: usize,
+    lowercase: bool,
+    normalization: Normalization
+}
+
+impl UsernamePolicy {
+    pub(crate) fn new(config: &UsersConfig) -> Result<Self, regex::Error> {
+        Ok(UsernamePolicy {
+            regex: Regex::new(&config.username_regex)?,
+            max_length: config.max_username_length,
+            lowercase: config.lowercase_usernames,
+            normalization: config.unicode_normalization
+        })
+    }
+
+    /// Returns the canonical form of the username or None if it is not allowed
+    pub(crate) fn canonicalize(&self, user: &str) -> Option<String> {
+        let mut user: String = match self.normalization {
+            Normalization::None => user.to_string(),
+            Normalization::Nfc => user.nfc().collect(),
+            Normalization::Nfkc => user.nfkc().collect()
+        };
+        if self.lowercase {
+            user = user.to_lowercase();
+        }
+
+        if user.chars().count() > self.max_length || !self.regex.is_match(&user) || !is_safe_dir_name(&user) {
+            return None
+        }
+        Some(user)
+    }
+}